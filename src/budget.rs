@@ -0,0 +1,302 @@
+use crate::git::FileDiff;
+
+pub const DEFAULT_CONTEXT_TOKENS: usize = 8192;
+
+/// Context loaded by local servers like LM Studio and Ollama unless told
+/// otherwise, whatever the model could take.
+pub const LOCAL_CONTEXT_TOKENS: usize = 4096;
+
+/// Tokens kept free for the answer and for the error of the estimation.
+pub const RESERVED_TOKENS: usize = 256;
+
+const LOCKFILES: [&str; 12] = [
+    "Cargo.lock",
+    "package-lock.json",
+    "npm-shrinkwrap.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "bun.lockb",
+    "composer.lock",
+    "Gemfile.lock",
+    "poetry.lock",
+    "Pipfile.lock",
+    "go.sum",
+    "flake.lock",
+];
+
+/// Context windows of well known model families, matched against the model
+/// name. More specific names have to come first.
+const CONTEXT_WINDOWS: [(&str, usize); 18] = [
+    ("gpt-3.5", 16385),
+    ("gpt-4o", 128000),
+    ("gpt-4.1", 1047576),
+    ("gpt-4-turbo", 128000),
+    ("gpt-4", 8192),
+    ("o1", 128000),
+    ("o3", 200000),
+    ("claude", 200000),
+    ("deepseek-coder-v2", 128000),
+    ("deepseek", 65536),
+    ("llama-3.1", 131072),
+    ("llama3.1", 131072),
+    ("llama3", 8192),
+    ("qwen2.5", 32768),
+    ("codellama", 16384),
+    ("mistral", 32768),
+    ("mixtral", 32768),
+    ("gemma", 8192),
+];
+
+/// Rough token count of a text. Diffs are dense in symbols, so this errs on
+/// the high side compared to the usual four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(3)
+}
+
+pub fn context_window(model: &str) -> usize {
+    let model = model.to_lowercase();
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(name, _)| model.contains(name))
+        .map(|(_, tokens)| *tokens)
+        .unwrap_or(DEFAULT_CONTEXT_TOKENS)
+}
+
+pub fn is_lockfile(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    LOCKFILES.contains(&file_name) || file_name.ends_with(".lock")
+}
+
+pub struct PackedDiff {
+    pub diff: String,
+    pub elided: Vec<String>,
+}
+
+fn file_header(file: &FileDiff) -> String {
+    format!("diff --git a/{} b/{}\n", file.path, file.path)
+}
+
+fn render_file(file: &FileDiff) -> String {
+    if file.binary {
+        format!("{}Binary file changed\n", file_header(file))
+    } else {
+        format!("{}{}", file_header(file), file.hunks.concat())
+    }
+}
+
+pub fn diffstat(diffs: &[FileDiff]) -> String {
+    diffs
+        .iter()
+        .map(|file| {
+            if file.binary {
+                format!("{} {} | binary", file.status, file.path)
+            } else {
+                format!(
+                    "{} {} | +{} -{}",
+                    file.status, file.path, file.additions, file.deletions
+                )
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Estimated size of the complete, untrimmed diff.
+pub fn diff_tokens(diffs: &[FileDiff]) -> usize {
+    diffs
+        .iter()
+        .map(|file| estimate_tokens(&render_file(file)))
+        .sum()
+}
+
+/// Keeps as many whole hunks of a file as fit into `budget` and cuts the first
+/// one that doesn't at a line boundary. Returns the text and the elided hunk count.
+fn trim_file(file: &FileDiff, budget: usize) -> (String, usize) {
+    let mut content = file_header(file);
+    let mut used = estimate_tokens(&content);
+
+    for (index, hunk) in file.hunks.iter().enumerate() {
+        let tokens = estimate_tokens(hunk);
+        if used + tokens <= budget {
+            content.push_str(hunk);
+            used += tokens;
+            continue;
+        }
+
+        let mut truncated = false;
+        for line in hunk.split_inclusive('\n') {
+            let tokens = estimate_tokens(line);
+            if used + tokens > budget {
+                break;
+            }
+            content.push_str(line);
+            used += tokens;
+            truncated = true;
+        }
+        if truncated {
+            content.push_str("[... hunk truncated]\n");
+        }
+
+        return (content, file.hunks.len() - index - usize::from(truncated));
+    }
+
+    (content, 0)
+}
+
+/// Fits the per-file diffs into `budget` tokens. Lockfiles are dropped first,
+/// after that every file gets an equal share of what is left, where small files
+/// hand their unused share on to the larger ones.
+pub fn pack_diffs(diffs: &[FileDiff], budget: usize) -> PackedDiff {
+    let rendered = diffs.iter().map(render_file).collect::<Vec<String>>();
+
+    let total = rendered
+        .iter()
+        .map(|file| estimate_tokens(file))
+        .sum::<usize>();
+    if total <= budget {
+        return PackedDiff {
+            diff: rendered.concat(),
+            elided: vec![],
+        };
+    }
+
+    let mut parts: Vec<Option<String>> = vec![None; diffs.len()];
+    let mut elided = Vec::new();
+    let mut remaining = budget;
+
+    let lockfiles = diffs
+        .iter()
+        .map(|file| is_lockfile(&file.path))
+        .collect::<Vec<bool>>();
+    let drop_lockfiles = lockfiles.contains(&true);
+
+    for (index, file) in diffs.iter().enumerate() {
+        if drop_lockfiles && lockfiles[index] {
+            elided.push(format!("{}: whole diff (lockfile)", file.path));
+        }
+    }
+
+    let mut order = (0..diffs.len())
+        .filter(|index| !(drop_lockfiles && lockfiles[*index]))
+        .collect::<Vec<usize>>();
+    order.sort_by_key(|index| estimate_tokens(&rendered[*index]));
+
+    let count = order.len();
+    for (position, index) in order.into_iter().enumerate() {
+        let share = remaining / (count - position);
+        let file = &diffs[index];
+        let tokens = estimate_tokens(&rendered[index]);
+
+        if tokens <= share {
+            parts[index] = Some(rendered[index].to_owned());
+            remaining -= tokens;
+            continue;
+        }
+
+        let (content, elided_hunks) = trim_file(file, share);
+        if content == file_header(file) {
+            elided.push(format!("{}: whole diff", file.path));
+            continue;
+        }
+        if elided_hunks > 0 {
+            elided.push(format!(
+                "{}: {} of {} hunks",
+                file.path,
+                elided_hunks,
+                file.hunks.len()
+            ));
+        } else {
+            elided.push(format!("{}: end of the diff", file.path));
+        }
+        remaining -= estimate_tokens(&content).min(remaining);
+        parts[index] = Some(content);
+    }
+
+    PackedDiff {
+        diff: parts.into_iter().flatten().collect::<String>(),
+        elided,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(path: &str, hunks: &[&str]) -> FileDiff {
+        FileDiff {
+            path: path.to_owned(),
+            status: 'M',
+            additions: hunks.len(),
+            deletions: 0,
+            binary: false,
+            hunks: hunks.iter().map(|hunk| hunk.to_string()).collect(),
+        }
+    }
+
+    fn hunk(lines: usize) -> String {
+        let mut hunk = "@@ -1 +1 @@\n".to_owned();
+        for line in 0..lines {
+            hunk.push_str(&format!("+let value_{} = {};\n", line, line));
+        }
+        hunk
+    }
+
+    #[test]
+    fn keeps_a_diff_that_fits() {
+        let diffs = vec![diff("a.rs", &[&hunk(2)]), diff("b.rs", &[&hunk(3)])];
+        let packed = pack_diffs(&diffs, diff_tokens(&diffs));
+        assert!(packed.elided.is_empty());
+        assert_eq!(
+            packed.diff,
+            diffs.iter().map(render_file).collect::<String>()
+        );
+    }
+
+    #[test]
+    fn truncates_a_hunk_larger_than_the_budget() {
+        let large = hunk(200);
+        let diffs = vec![diff("a.rs", &[&large])];
+        let budget = estimate_tokens(&large) / 2;
+        let packed = pack_diffs(&diffs, budget);
+
+        assert!(packed
+            .diff
+            .starts_with("diff --git a/a.rs b/a.rs\n@@ -1 +1 @@\n"));
+        assert!(packed.diff.ends_with("[... hunk truncated]\n"));
+        assert!(
+            estimate_tokens(&packed.diff) <= budget + estimate_tokens("[... hunk truncated]\n")
+        );
+        assert_eq!(packed.elided, vec!["a.rs: end of the diff"]);
+    }
+
+    #[test]
+    fn elides_whole_hunks_after_the_budget() {
+        let small = hunk(2);
+        let large = hunk(200);
+        let diffs = vec![diff("a.rs", &[&small, &large, &large])];
+        let budget = estimate_tokens(&file_header(&diffs[0])) + estimate_tokens(&small) + 1;
+        let packed = pack_diffs(&diffs, budget);
+
+        assert_eq!(packed.diff, format!("{}{}", file_header(&diffs[0]), small));
+        assert_eq!(packed.elided, vec!["a.rs: 2 of 3 hunks"]);
+    }
+
+    #[test]
+    fn zero_budget_elides_everything() {
+        let diffs = vec![diff("a.rs", &[&hunk(2)]), diff("b.rs", &[&hunk(3)])];
+        let packed = pack_diffs(&diffs, 0);
+        assert!(packed.diff.is_empty());
+        assert_eq!(packed.elided, vec!["a.rs: whole diff", "b.rs: whole diff"]);
+    }
+
+    #[test]
+    fn drops_lockfiles_first() {
+        let diffs = vec![
+            diff("Cargo.lock", &[&hunk(100)]),
+            diff("src/a.rs", &[&hunk(2)]),
+        ];
+        let packed = pack_diffs(&diffs, diff_tokens(&diffs[1..]));
+        assert_eq!(packed.diff, render_file(&diffs[1]));
+        assert_eq!(packed.elided, vec!["Cargo.lock: whole diff (lockfile)"]);
+    }
+}
@@ -0,0 +1,400 @@
+use std::path::PathBuf;
+
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    ArgAction, Args, Parser, Subcommand,
+};
+
+use crate::convention::{Convention, CONVENTIONS};
+use crate::utils::{PROFILE_KEYS, SETTING_KEYS};
+
+pub const MAX_CANDIDATES: usize = 10;
+
+/// Writes commit messages for your changes.
+///
+/// Without a command the changes are committed like with `commit`. Put file
+/// paths after `--` when they could be mistaken for a command or an option.
+#[derive(Parser)]
+#[command(
+    version,
+    disable_version_flag = true,
+    override_usage = "gpt-commit-rust [OPTIONS] [FILES]...\n       gpt-commit-rust [OPTIONS] <COMMAND>"
+)]
+pub struct Cli {
+    /// Uses the given profile instead of the default one
+    #[arg(long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Never asks, every question gets its default answer, like running the commands
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
+    /// Never asks and stops with exit code 7 where an answer is needed, unless --yes is given
+    #[arg(long, global = true)]
+    pub non_interactive: bool,
+
+    /// Shows the current version of the program
+    #[arg(short = 'v', long, action = ArgAction::Version)]
+    version: Option<bool>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub commit: CommitArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Generates a message for the changes and commits them
+    Commit(CommitArgs),
+    /// Writes a README.md based on the content of the given files and commits it
+    Readme(ReadmeArgs),
+    /// Writes new messages for the commits of a range and rewrites them
+    Reword(RewordArgs),
+    /// Writes a title and description for a pull request of the current branch
+    Pr(PrArgs),
+    /// Shows and changes the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Updates the program to the latest version of the update channel
+    Update {
+        /// Downloads the version even when it isn't newer than the installed one
+        #[arg(short, long)]
+        force: bool,
+        /// Installs the given version instead, also to go back to an older one
+        #[arg(long, value_name = "VERSION", conflicts_with_all = ["rollback", "list"])]
+        to: Option<String>,
+        /// Lists the published versions
+        #[arg(long, conflicts_with_all = ["force", "rollback"])]
+        list: bool,
+        /// Restores the version that was installed before the last update
+        #[arg(long, conflicts_with = "force")]
+        rollback: bool,
+    },
+    /// Manages the prepare-commit-msg hook of the repository
+    #[command(subcommand)]
+    Hook(HookCommand),
+}
+
+#[derive(Args)]
+pub struct CommitArgs {
+    /// Commits the changes with a template instead of a generated message
+    #[arg(short, long, conflicts_with_all = ["split", "candidates", "summarize"])]
+    pub no_ai: bool,
+
+    /// Pushes the changes to the remote repository after committing
+    #[arg(short, long)]
+    pub push: bool,
+
+    /// Prints the whole response at once instead of streaming it while it is generated
+    #[arg(long)]
+    pub no_stream: bool,
+
+    /// Describes and commits only the staged changes, without adding anything.
+    /// Default when the index already differs from HEAD and no files are given
+    #[arg(long, conflicts_with_all = ["all", "files", "split"])]
+    pub staged: bool,
+
+    /// Describes and commits all changes even when some are staged already
+    #[arg(long)]
+    pub all: bool,
+
+    /// Rewrites the message of the last commit, together with the changes
+    /// staged since
+    #[arg(long, conflicts_with_all = ["no_ai", "push", "staged", "all", "split", "files"])]
+    pub amend: bool,
+
+    /// Lets the model split the changes into several commits and shows the
+    /// plan before committing
+    #[arg(long, conflicts_with = "candidates")]
+    pub split: bool,
+
+    /// Generates several messages to pick one from
+    #[arg(long, value_name = "N", value_parser = parse_candidates)]
+    pub candidates: Option<usize>,
+
+    /// Summarizes every file first and writes the message from the summaries.
+    /// Happens automatically when the diff doesn't fit into the context window
+    #[arg(long)]
+    pub summarize: bool,
+
+    /// Uses the given commit convention instead of the default one
+    #[arg(long, value_name = "NAME", value_parser = convention_parser())]
+    pub convention: Option<Convention>,
+
+    /// Only prints the generated message, without committing anything
+    #[arg(long, conflicts_with_all = ["no_ai", "split", "candidates", "json"])]
+    pub print: bool,
+
+    /// Prints the generated message, the model, the token usage, the files and
+    /// the commands as JSON, without committing anything
+    #[arg(long, conflicts_with_all = ["no_ai", "split", "candidates"])]
+    pub json: bool,
+
+    /// Only commits these files
+    pub files: Vec<String>,
+}
+
+impl CommitArgs {
+    /// Whether any option or file was given.
+    pub fn is_used(&self) -> bool {
+        self.no_ai
+            || self.push
+            || self.no_stream
+            || self.staged
+            || self.all
+            || self.amend
+            || self.split
+            || self.candidates.is_some()
+            || self.summarize
+            || self.convention.is_some()
+            || self.print
+            || self.json
+            || !self.files.is_empty()
+    }
+
+    /// Whether only the message is printed, for scripts and editor plugins.
+    pub fn is_scripted(&self) -> bool {
+        self.print || self.json
+    }
+}
+
+#[derive(Args)]
+pub struct ReadmeArgs {
+    /// Pushes the changes to the remote repository after committing
+    #[arg(short, long)]
+    pub push: bool,
+
+    /// Prints the whole response at once instead of streaming it while it is generated
+    #[arg(long)]
+    pub no_stream: bool,
+
+    /// Files the README.md is based on
+    #[arg(required = true)]
+    pub files: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct RewordArgs {
+    /// The commits to reword, like main..HEAD, or a commit to reword everything after it
+    pub range: String,
+
+    /// Also rewrites merge commits and commits that are pushed already
+    #[arg(short, long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct PrArgs {
+    /// The branch the pull request goes into, defaults to the pr.base setting
+    /// or the default branch of origin
+    #[arg(short, long, value_name = "BRANCH")]
+    pub base: Option<String>,
+
+    /// Writes the description to this file instead of printing it
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Lists the configured profiles
+    Profiles,
+    /// Lists every setting in effect
+    List {
+        /// Shows the file or variable every setting comes from
+        #[arg(long)]
+        show_origin: bool,
+    },
+    /// Shows a value of the selected profile or an update or pr setting
+    Get {
+        #[arg(value_parser = parse_key)]
+        key: String,
+        /// Shows the file or variable the value comes from
+        #[arg(long)]
+        show_origin: bool,
+    },
+    /// Makes the given profile the default one
+    DefaultProfile {
+        #[arg(value_name = "NAME")]
+        name: String,
+        #[command(flatten)]
+        target: Target,
+    },
+    /// Sets a value of the selected profile or an update or pr setting. The API key
+    /// can also be set in the .env file
+    Set {
+        /// base_url, path, model, context_tokens, provider, api_key,
+        /// api_key_command, api_key_env, auth, response_format, header.<name>,
+        /// update.check, update.interval_hours, update.channel,
+        /// update.releases_url or pr.base
+        #[arg(value_parser = parse_key)]
+        key: String,
+        value: String,
+        #[command(flatten)]
+        target: Target,
+    },
+    /// Clears a value of the selected profile or an update or pr setting
+    Unset {
+        /// Any key that can be set
+        #[arg(value_parser = parse_key)]
+        key: String,
+        #[command(flatten)]
+        target: Target,
+    },
+    /// Removes the given profile from the config file
+    RemoveProfile {
+        #[arg(value_name = "NAME")]
+        name: String,
+        #[command(flatten)]
+        target: Target,
+    },
+    /// Makes the given commit convention the default one
+    DefaultConvention {
+        #[arg(value_name = "NAME", value_parser = convention_parser())]
+        convention: Convention,
+        #[command(flatten)]
+        target: Target,
+    },
+}
+
+#[derive(Args)]
+pub struct Target {
+    /// Changes the .gpt-commit.toml of the repository instead of the user config
+    #[arg(long)]
+    pub repo: bool,
+}
+
+#[derive(Subcommand)]
+pub enum HookCommand {
+    /// Installs a prepare-commit-msg hook, so git commit comes up with a generated message
+    Install,
+    /// Removes the prepare-commit-msg hook again
+    Uninstall,
+    /// Runs the hook, called by git
+    #[command(hide = true)]
+    Run {
+        file: String,
+        source: Option<String>,
+        commit: Option<String>,
+    },
+}
+
+fn convention_parser() -> impl TypedValueParser<Value = Convention> {
+    PossibleValuesParser::new(CONVENTIONS).map(|name| Convention::from_name(&name).unwrap())
+}
+
+fn parse_candidates(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(candidates) if (1..=MAX_CANDIDATES).contains(&candidates) => Ok(candidates),
+        _ => Err(format!("has to be a number from 1 to {}", MAX_CANDIDATES)),
+    }
+}
+
+fn parse_key(value: &str) -> Result<String, String> {
+    if PROFILE_KEYS.contains(&value)
+        || SETTING_KEYS.contains(&value)
+        || value
+            .strip_prefix("header.")
+            .map(|name| !name.is_empty())
+            .unwrap_or(false)
+    {
+        Ok(value.to_owned())
+    } else {
+        Err(format!(
+            "use one of {}, {}",
+            PROFILE_KEYS.join(", "),
+            SETTING_KEYS.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{error::ErrorKind, CommandFactory};
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(["gpt-commit-rust"].iter().chain(args))
+    }
+
+    fn error(args: &[&str]) -> ErrorKind {
+        parse(args).err().unwrap().kind()
+    }
+
+    #[test]
+    fn the_cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn files_without_a_command_are_committed() {
+        let cli = parse(&["--push", "src/a.rs", "src/b.rs"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.commit.push);
+        assert_eq!(cli.commit.files, ["src/a.rs", "src/b.rs"]);
+
+        // a file named like a command goes after --
+        let cli = parse(&["--", "config"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.commit.files, ["config"]);
+    }
+
+    #[test]
+    fn parses_commands_and_global_options() {
+        let cli = parse(&["commit", "--staged", "--profile", "work", "-y"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("work"));
+        assert!(cli.yes);
+        match cli.command {
+            Some(Command::Commit(args)) => assert!(args.staged && args.files.is_empty()),
+            _ => panic!("expected the commit command"),
+        }
+
+        let cli = parse(&["config", "set", "header.X-Trace", "1", "--repo"]).unwrap();
+        match cli.command {
+            Some(Command::Config(ConfigCommand::Set { key, value, target })) => {
+                assert_eq!((key.as_str(), value.as_str()), ("header.X-Trace", "1"));
+                assert!(target.repo);
+            }
+            _ => panic!("expected config set"),
+        }
+
+        let cli = parse(&["--convention", "conventional", "--candidates", "3"]).unwrap();
+        assert_eq!(cli.commit.convention, Some(Convention::Conventional));
+        assert_eq!(cli.commit.candidates, Some(3));
+    }
+
+    #[test]
+    fn rejects_conflicting_and_invalid_arguments() {
+        assert_eq!(error(&["--staged", "--all"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error(&["--amend", "--push"]), ErrorKind::ArgumentConflict);
+        assert_eq!(
+            error(&["--split", "--candidates", "2"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(error(&["--print", "--json"]), ErrorKind::ArgumentConflict);
+        assert_eq!(
+            error(&["update", "--to", "1.2.0", "--rollback"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(error(&["--convention", "angular"]), ErrorKind::InvalidValue);
+        assert_eq!(
+            error(&["config", "get", "colour"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(error(&["readme"]), ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn candidates_are_limited() {
+        assert_eq!(parse_candidates("1"), Ok(1));
+        assert_eq!(
+            parse_candidates(&MAX_CANDIDATES.to_string()),
+            Ok(MAX_CANDIDATES)
+        );
+        assert!(parse_candidates("0").is_err());
+        assert!(parse_candidates(&(MAX_CANDIDATES + 1).to_string()).is_err());
+        assert!(parse_candidates("three").is_err());
+    }
+}
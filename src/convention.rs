@@ -0,0 +1,340 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+pub(crate) const CONVENTIONS: [&str; 4] =
+    ["gitmoji", "conventional", "conventional+gitmoji", "plain"];
+
+const COMMIT_TYPES: &str =
+    "feat, fix, docs, style, refactor, perf, test, build, ci, chore or revert";
+
+const LABELS: [&str; 5] = ["Title:", "Subject:", "Body:", "Summary:", "Gitmoji:"];
+
+lazy_static! {
+    static ref HEADER_REGEX: Regex = Regex::new(
+        r"^(?P<type>[A-Za-z]+)(?:\((?P<scope>[^()]*)\))?(?P<breaking>!)?:\s*(?P<description>.*)$"
+    )
+    .unwrap();
+    // a `:code:` or an emoji, which may be a ZWJ sequence with variation
+    // selectors and skin tones, or a flag
+    static ref GITMOJI_REGEX: Regex = Regex::new(
+        r"^(?::[a-z0-9_]+:|\p{Extended_Pictographic}\x{FE0F}?\p{Emoji_Modifier}?(?:\x{200D}\p{Extended_Pictographic}\x{FE0F}?\p{Emoji_Modifier}?)*|\p{Regional_Indicator}{2})+\s*"
+    )
+    .unwrap();
+    static ref FOOTER_REGEX: Regex = Regex::new(r"(?i)^breaking[ -]change:\s*").unwrap();
+}
+
+/// The format of the commit subject.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Convention {
+    /// `:sparkles: Add something`
+    Gitmoji,
+    /// `feat(scope)!: add something`
+    Conventional,
+    /// `feat(scope)!: :sparkles: add something`
+    ConventionalGitmoji,
+    /// `Add something`
+    Plain,
+}
+
+/// A parsed Conventional Commits header, `type(scope)!: description`.
+pub struct Header<'a> {
+    pub kind: &'a str,
+    pub scope: Option<&'a str>,
+    pub breaking: bool,
+    pub description: &'a str,
+}
+
+pub fn parse_header(subject: &str) -> Option<Header<'_>> {
+    let captures = HEADER_REGEX.captures(subject)?;
+    Some(Header {
+        kind: captures.name("type")?.as_str(),
+        scope: captures
+            .name("scope")
+            .map(|scope| scope.as_str())
+            .filter(|scope| !scope.trim().is_empty()),
+        breaking: captures.name("breaking").is_some(),
+        description: captures.name("description")?.as_str(),
+    })
+}
+
+/// Whether a line is a `BREAKING CHANGE:` footer.
+pub fn is_breaking_footer(line: &str) -> bool {
+    FOOTER_REGEX.is_match(line)
+}
+
+/// Splits a leading gitmoji, written as code or as emoji, from the text.
+fn split_gitmoji(text: &str) -> (&str, &str) {
+    match GITMOJI_REGEX.find(text) {
+        Some(found) => (found.as_str().trim(), &text[found.end()..]),
+        None => ("", text),
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl Convention {
+    pub fn from_name(name: &str) -> Option<Convention> {
+        match name {
+            "gitmoji" => Some(Convention::Gitmoji),
+            "conventional" => Some(Convention::Conventional),
+            "conventional+gitmoji" => Some(Convention::ConventionalGitmoji),
+            "plain" => Some(Convention::Plain),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Convention::Gitmoji => "gitmoji",
+            Convention::Conventional => "conventional",
+            Convention::ConventionalGitmoji => "conventional+gitmoji",
+            Convention::Plain => "plain",
+        }
+    }
+
+    pub fn is_conventional(self) -> bool {
+        matches!(
+            self,
+            Convention::Conventional | Convention::ConventionalGitmoji
+        )
+    }
+
+    /// Instructions for the subject line.
+    pub fn rules(self) -> Vec<String> {
+        let subject = match self {
+            Convention::Gitmoji => "Use one Gitmoji at the start of the subject line, followed by a short summary starting with upper case".to_owned(),
+            Convention::Conventional => format!("Start the subject line with a Conventional Commits header, type(scope): summary, where type is one of {}, the scope is optional and the summary starts with lower case", COMMIT_TYPES),
+            Convention::ConventionalGitmoji => format!("Start the subject line with a Conventional Commits header followed by one Gitmoji, type(scope): :gitmoji: summary, where type is one of {}, the scope is optional and the summary starts with lower case", COMMIT_TYPES),
+            Convention::Plain => "Start the subject line with a short summary starting with upper case, without any prefix or emoji".to_owned(),
+        };
+
+        let mut rules = vec![
+            "1. Limit the subject line to 50 characters".to_owned(),
+            format!("2. {}", subject),
+            "3. Use imperative in the subject line".to_owned(),
+            "4. Wrap the body at 72 characters".to_owned(),
+            "5. Use the body to explain what and why vs. how".to_owned(),
+            "6. Do not use markdown headings or code blocks".to_owned(),
+        ];
+        if self.is_conventional() {
+            rules.push("7. For breaking changes add ! after the type or scope and end the body with a paragraph starting with BREAKING CHANGE: followed by what breaks".to_owned());
+        }
+        rules
+    }
+
+    /// Brings the subject into the shape of the convention, as models don't
+    /// always follow the instructions exactly.
+    pub fn format_subject(self, subject: &str, breaking: bool) -> String {
+        let (gitmoji, rest) = split_gitmoji(subject.trim());
+
+        let header = parse_header(rest);
+        if self == Convention::Gitmoji {
+            return match header {
+                Some(header)
+                    if !split_gitmoji(header.description).0.is_empty() || !gitmoji.is_empty() =>
+                {
+                    let (inner_gitmoji, description) = split_gitmoji(header.description);
+                    let gitmoji = if gitmoji.is_empty() {
+                        inner_gitmoji
+                    } else {
+                        gitmoji
+                    };
+                    format!("{} {}", gitmoji, capitalize(description.trim()))
+                }
+                _ if gitmoji.is_empty() => subject.trim().to_owned(),
+                _ => format!("{} {}", gitmoji, capitalize(rest.trim())),
+            };
+        }
+
+        if self == Convention::Plain {
+            let description = match &header {
+                Some(header) => split_gitmoji(header.description).1,
+                None => rest,
+            };
+            return capitalize(description.trim());
+        }
+
+        let header = match header {
+            Some(header) => header,
+            None => return subject.trim().to_owned(),
+        };
+        let (inner_gitmoji, description) = split_gitmoji(header.description);
+        let gitmoji = if gitmoji.is_empty() {
+            inner_gitmoji
+        } else {
+            gitmoji
+        };
+
+        let mut formatted = header.kind.to_lowercase();
+        if let Some(scope) = header.scope {
+            formatted.push_str(&format!("({})", scope.trim()));
+        }
+        if header.breaking || breaking {
+            formatted.push('!');
+        }
+        formatted.push_str(": ");
+        if self == Convention::ConventionalGitmoji && !gitmoji.is_empty() {
+            formatted.push_str(&format!("{} ", gitmoji));
+        }
+        formatted.push_str(description.trim());
+        formatted
+    }
+
+    /// Cleans up a reply of the model: drops labels like `Title:` at the start
+    /// of lines, formats the subject and moves `BREAKING CHANGE:` footers into
+    /// their own paragraph at the end.
+    pub fn format_message(self, message: &str) -> String {
+        let lines = message
+            .trim()
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                LABELS
+                    .iter()
+                    .find_map(|label| trimmed.strip_prefix(label))
+                    .map(|rest| rest.trim())
+                    .unwrap_or(line)
+            })
+            .collect::<Vec<&str>>();
+        self.format_lines(&lines)
+    }
+
+    /// Like `format_message`, for messages put together from the fields of a
+    /// JSON reply, whose lines are meant as they are.
+    pub fn format_rendered(self, message: &str) -> String {
+        self.format_lines(&message.trim().lines().collect::<Vec<&str>>())
+    }
+
+    fn format_lines(self, lines: &[&str]) -> String {
+        let position = lines.iter().position(|line| !line.trim().is_empty());
+        let (subject, body) = match position {
+            Some(position) => (lines[position], &lines[position + 1..]),
+            None => return String::new(),
+        };
+
+        let mut footers = Vec::new();
+        let mut body_lines = Vec::new();
+        for line in body {
+            if self.is_conventional() && is_breaking_footer(line.trim()) {
+                footers.push(
+                    FOOTER_REGEX
+                        .replace(line.trim(), "BREAKING CHANGE: ")
+                        .into_owned(),
+                );
+            } else if !footers.is_empty() && !line.trim().is_empty() {
+                let last = footers.len() - 1;
+                footers[last] = format!("{} {}", footers[last], line.trim());
+            } else {
+                body_lines.push(line.to_string());
+            }
+        }
+
+        let mut message = self.format_subject(subject, !footers.is_empty());
+        let body = body_lines.join("\n").trim().to_owned();
+        if !body.is_empty() {
+            message.push_str(&format!("\n\n{}", body));
+        }
+        if !footers.is_empty() {
+            message.push_str(&format!("\n\n{}", footers.join("\n")));
+        }
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_gitmoji_takes_codes_and_emoji() {
+        assert_eq!(split_gitmoji(":sparkles: Add x"), (":sparkles:", "Add x"));
+        assert_eq!(split_gitmoji("✨ Add x"), ("✨", "Add x"));
+        assert_eq!(split_gitmoji("⚡️ Speed up x"), ("⚡️", "Speed up x"));
+        assert_eq!(split_gitmoji("👨‍💻 Add dev tools"), ("👨‍💻", "Add dev tools"));
+        assert_eq!(split_gitmoji("🇩🇪 Translate x"), ("🇩🇪", "Translate x"));
+    }
+
+    #[test]
+    fn split_gitmoji_keeps_other_letters() {
+        assert_eq!(split_gitmoji("Ändere den Port"), ("", "Ändere den Port"));
+        assert_eq!(split_gitmoji("修复端口"), ("", "修复端口"));
+        assert_eq!(
+            split_gitmoji("Élargir la fenêtre"),
+            ("", "Élargir la fenêtre")
+        );
+    }
+
+    #[test]
+    fn format_subject_keeps_accented_subjects() {
+        let subject = "Ändere den Port";
+        assert_eq!(Convention::Gitmoji.format_subject(subject, false), subject);
+        assert_eq!(Convention::Plain.format_subject(subject, false), subject);
+        assert_eq!(
+            Convention::Conventional.format_subject("fix(api): Ändere den Port", false),
+            "fix(api): Ändere den Port"
+        );
+        assert_eq!(
+            Convention::Plain.format_subject("fix(api): ändere den Port", false),
+            "Ändere den Port"
+        );
+    }
+
+    #[test]
+    fn format_subject_keeps_cjk_subjects() {
+        assert_eq!(
+            Convention::Gitmoji.format_subject("修复端口", false),
+            "修复端口"
+        );
+        assert_eq!(
+            Convention::Plain.format_subject("修复端口", false),
+            "修复端口"
+        );
+        assert_eq!(
+            Convention::Conventional.format_subject("fix: 修复端口", false),
+            "fix: 修复端口"
+        );
+    }
+
+    #[test]
+    fn format_subject_handles_emoji_led_subjects() {
+        assert_eq!(
+            Convention::Gitmoji.format_subject("✨ add x", false),
+            "✨ Add x"
+        );
+        assert_eq!(Convention::Plain.format_subject("✨ add x", false), "Add x");
+        assert_eq!(
+            Convention::Gitmoji.format_subject("feat: 👨‍💻 add dev tools", false),
+            "👨‍💻 Add dev tools"
+        );
+        assert_eq!(
+            Convention::Conventional.format_subject("✨ feat(ui): add x", true),
+            "feat(ui)!: add x"
+        );
+        assert_eq!(
+            Convention::ConventionalGitmoji.format_subject("feat: ⚡️ speed up x", false),
+            "feat: ⚡️ speed up x"
+        );
+    }
+
+    #[test]
+    fn format_message_drops_labels_of_text_replies() {
+        assert_eq!(
+            Convention::Plain.format_message("Subject: Fix the port\n\nBody: It was off by one."),
+            "Fix the port\n\nIt was off by one."
+        );
+    }
+
+    #[test]
+    fn format_rendered_keeps_lines_as_they_are() {
+        assert_eq!(
+            Convention::Plain.format_rendered("Fix the summary\n\nSummary: now counts merges."),
+            "Fix the summary\n\nSummary: now counts merges."
+        );
+    }
+}
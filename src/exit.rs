@@ -0,0 +1,55 @@
+use std::fmt;
+
+/// Exit codes scripts can branch on. Invalid arguments exit with 2, like with
+/// every clap program, and Ctrl-C outside of a prompt with 130.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Anything else, like a broken config.
+    Failure = 1,
+    NothingToCommit = 3,
+    /// The model couldn't be reached or refused the request.
+    ModelUnreachable = 4,
+    /// The model answered with nothing that could be used.
+    UnusableOutput = 5,
+    GitFailed = 6,
+    /// Aborted by the user, or a question came up in non-interactive mode.
+    Aborted = 7,
+}
+
+/// An error together with the exit code it ends the program with.
+#[derive(Debug)]
+pub struct Failure {
+    pub code: ExitCode,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(code: ExitCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Self::new(ExitCode::Failure, message)
+    }
+}
+
+impl From<Failure> for String {
+    fn from(failure: Failure) -> Self {
+        failure.message
+    }
+}
+
+pub fn exit(code: ExitCode) -> ! {
+    std::process::exit(code as i32)
+}
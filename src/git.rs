@@ -1,271 +1,1540 @@
-use colored::Colorize;
-use git2::{DiffFormat, DiffOptions, Oid, Repository, StatusOptions};
-use normpath::{BasePathBuf, PathExt};
-use std::{path::Path, str, vec};
-
-use crate::command_utils::{replace_gitmoji_with_emoji, run_commands};
-
-pub fn build_commands(
-    commit_message: &String,
-    include_push: bool,
-    files: &Vec<String>,
-) -> Vec<Vec<String>> {
-    let mut commit_message = commit_message.clone();
-
-    if commit_message.starts_with("\"") && commit_message.ends_with("\"") {
-        commit_message = commit_message[1..commit_message.len() - 1].to_owned();
-    }
-
-    let mut commands: Vec<Vec<String>> = Vec::new();
-    let mut add_command: Vec<String> = vec!["git".to_owned(), "add".to_owned()];
-    add_command.extend(paths_to_git_paths(&files));
-    commands.push(add_command);
-    let mut commit_command: Vec<String> = vec!["git".to_owned(), "commit".to_owned()];
-
-    for msg in commit_message.split('\n') {
-        if msg.is_empty() {
-            continue;
-        }
-        commit_command.push("-m".to_owned());
-        commit_command.push(msg.to_owned());
-    }
-
-    commands.push(commit_command);
-    if include_push {
-        commands.push("git push".split(' ').map(|s| s.to_owned()).collect());
-    }
-
-    commands
-}
-
-pub struct Git {
-    pub repo: Repository,
-    _path: String,
-}
-
-impl Git {
-    pub fn new(path: String) -> Result<Self, git2::Error> {
-        let repo = Repository::open(path.to_owned());
-        if repo.is_err() {
-            return Err(repo.err().unwrap());
-        }
-        Ok(Self {
-            repo: repo.unwrap(),
-            _path: path.to_owned(),
-        })
-    }
-
-    pub fn clone(self: &Self) -> Git {
-        Git::new(self._path.clone()).unwrap()
-    }
-
-    pub fn get_diff(self: &Self, files: Option<Vec<String>>) -> Result<String, git2::Error> {
-        let repo = &self.repo;
-        let mut index = repo.index()?;
-        let oid = index.write_tree()?;
-        let tree = repo.find_tree(oid)?;
-        let mut patch = String::new();
-        let options = &mut DiffOptions::new();
-        options.include_untracked(true);
-        options.recurse_untracked_dirs(true);
-        options.include_unmodified(true);
-        options.show_untracked_content(true);
-        options.include_typechange(true);
-        let diff = repo.diff_tree_to_workdir_with_index(Some(&tree), Some(options))?;
-
-        let paths: Vec<String> = files.clone().unwrap_or(vec![]);
-        let paths: Vec<BasePathBuf> = paths
-            .iter()
-            .map(|entry| Path::new(entry).normalize())
-            .filter(|entry| entry.is_ok())
-            .map(|entry| entry.unwrap())
-            .collect();
-
-        let mut patch_file = String::new();
-        diff.print(DiffFormat::Patch, |delta, _hunk, line| {
-            let file_path = delta.new_file().path().unwrap();
-
-            if paths.is_empty() || paths.contains(&file_path.normalize().unwrap()) {
-                let content = str::from_utf8(line.content()).unwrap();
-                match line.origin() {
-                    '+' => {
-                        patch_file.push_str(format!("{}{}", "+".green(), content.green()).as_str())
-                    }
-                    '-' => patch_file.push_str(format!("{}{}", "-".red(), content.red()).as_str()),
-                    'H' => patch_file.push_str(format!("{}", content.bright_blue()).as_str()),
-                    'F' => patch_file.push_str(format!("\n{}", content.bold()).as_str()),
-                    _ => patch_file.push_str(content),
-                }
-            }
-            true
-        })?;
-        patch.push_str(&patch_file);
-        Ok(patch)
-    }
-
-    pub fn get_status(self: &Self) -> Result<String, git2::Error> {
-        let repo = &self.repo;
-        let options = &mut StatusOptions::new();
-        options.include_untracked(true);
-        options.include_ignored(false);
-        options.recurse_untracked_dirs(true);
-        let statuses = repo.statuses(Some(options))?;
-        let mut status_value = String::new();
-
-        for entry in statuses.iter() {
-            let path = entry.path().unwrap();
-            let status = entry.status();
-            let status = match status {
-                git2::Status::CURRENT => break,
-                git2::Status::INDEX_NEW => "A",
-                git2::Status::INDEX_MODIFIED => "M",
-                git2::Status::INDEX_DELETED => "D",
-                git2::Status::INDEX_RENAMED => "R",
-                git2::Status::INDEX_TYPECHANGE => "Typechange",
-                git2::Status::WT_NEW => "A",
-                git2::Status::WT_MODIFIED => "M",
-                git2::Status::WT_DELETED => "D",
-                git2::Status::WT_TYPECHANGE => "Typechange",
-                git2::Status::WT_RENAMED => "R",
-                git2::Status::IGNORED => "!",
-                git2::Status::CONFLICTED => "U",
-                _ => {
-                    if status.is_wt_new() || status.is_index_new() {
-                        "A"
-                    } else if status.is_wt_modified() || status.is_index_modified() {
-                        "M"
-                    } else if status.is_wt_deleted() || status.is_index_deleted() {
-                        "D"
-                    } else if status.is_wt_renamed() || status.is_index_renamed() {
-                        "R"
-                    } else if status.is_conflicted() {
-                        "U"
-                    } else {
-                        "Unknown"
-                    }
-                }
-            };
-            match status {
-                "A" => status_value.push_str(format!("{}", path.green()).as_str()),
-                "M" => status_value.push_str(format!("{}", path.yellow()).as_str()),
-                "D" => status_value.push_str(format!("{}", path.red()).as_str()),
-                "R" => status_value.push_str(format!("{}", path.blue()).as_str()),
-                "!" => status_value.push_str(format!("{}", path.red()).as_str()),
-                "U" => status_value.push_str(format!("{}", path.red()).as_str()),
-                &_ => status_value.push_str(path),
-            }
-            status_value.push_str(format!(" {}", status).as_str());
-            status_value.push('\n');
-        }
-
-        Ok(status_value)
-    }
-
-    #[allow(dead_code)]
-    pub fn add(self: &Self, files: Option<&Vec<String>>) {
-        self.repo
-            .index()
-            .unwrap()
-            .add_all(
-                paths_to_git_paths(&files.unwrap_or(&vec![])).iter(),
-                git2::IndexAddOption::DEFAULT,
-                None,
-            )
-            .unwrap();
-    }
-
-    #[allow(dead_code)]
-    pub fn add_old(self: &Self, files: Option<&Vec<String>>) {
-        let mut add_command = vec!["git".to_owned(), "add".to_owned()];
-        let default = &vec![];
-        let files = files.unwrap_or(default);
-        if files.is_empty() {
-            add_command.push(".".to_owned());
-        } else {
-            for file in files {
-                let file = Path::new(file).normalize().unwrap();
-                let file = file.as_path().to_str().unwrap();
-                add_command.push(file.to_owned());
-            }
-        }
-
-        run_commands(&vec![add_command])
-    }
-
-    #[allow(dead_code)]
-    pub fn commit_old(self: &Self, message: &String) {
-        let mut commit_command = vec!["git".to_owned(), "commit".to_owned()];
-
-        for msg in message.split("\n") {
-            commit_command.push("-m".to_owned());
-            commit_command.push(msg.to_owned());
-        }
-
-        run_commands(&vec![commit_command])
-    }
-
-    #[allow(dead_code)]
-    pub fn commit(self: &Self, message: &String) -> Result<Oid, git2::Error> {
-        let mut index = self.repo.index().unwrap();
-        let oid = index.write_tree().unwrap();
-        let signature = self.repo.signature().unwrap();
-        let parent_commit = self.repo.head().unwrap().peel_to_commit().unwrap();
-        let tree = self.repo.find_tree(oid).unwrap();
-
-        let commit_response: Result<Oid, git2::Error> = self.repo.commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
-            &message,
-            &tree,
-            &[&parent_commit],
-        );
-
-        match commit_response {
-            Ok(commit) => {
-                let commit_hash = commit.to_string();
-                let commit_hash = commit_hash[..7].to_string();
-                let commit_message = message.trim();
-                let commit_message = commit_message.replace("\n", " ");
-                let commit_message = commit_message.replace("\r", " ");
-                let commit_message = commit_message.replace("\t", " ");
-                let commit_message = commit_message.replace("  ", " ");
-
-                println!(
-                    "[{} {}] {}",
-                    self.repo.head().unwrap().shorthand().unwrap(),
-                    commit_hash,
-                    replace_gitmoji_with_emoji(commit_message.as_str())
-                );
-            }
-            Err(err) => {
-                println!("Error: {}", err.message());
-                return Err(err);
-            }
-        };
-
-        commit_response
-    }
-
-    pub fn push(self: &Self) {
-        run_commands(&vec![vec!["git".to_owned(), "push".to_owned()]]);
-    }
-}
-
-fn paths_to_git_paths(paths: &Vec<String>) -> Vec<String> {
-    if paths.is_empty() {
-        return vec![".".to_owned()];
-    }
-    paths
-        .iter()
-        .map(|entry| Path::new(entry))
-        .map(|entry| {
-            entry
-                .into_iter()
-                .map(|entry| entry.to_str().unwrap().to_owned())
-                .filter(|entry| entry != &".")
-                .collect::<Vec<String>>()
-                .join("/")
-        })
-        .collect()
-}
+use colored::Colorize;
+use git2::{
+    ApplyLocation, ApplyOptions, BranchType, Commit, Delta, Diff, DiffOptions, ErrorCode, Index,
+    Oid, Patch, Repository, RevparseMode, Sort, StatusOptions, Tree,
+};
+use normpath::{BasePathBuf, PathExt};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str, vec,
+};
+
+use crate::command_utils::{replace_gitmoji_with_emoji, run_commands};
+
+const BODY_WIDTH: usize = 72;
+
+fn wrap_line(line: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for word in line.split_whitespace() {
+        if !current.is_empty() && current.chars().count() + word.chars().count() + 1 > BODY_WIDTH {
+            lines.push(current);
+            current = String::new();
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+    lines.push(current);
+    lines
+}
+
+/// Turns the response into a commit message: the first line is the subject,
+/// followed by a blank line and the rest as body, wrapped at 72 characters.
+pub fn format_commit_message(result: &str) -> String {
+    let mut result = result.trim();
+    if result.starts_with('"') && result.ends_with('"') && result.len() > 1 {
+        result = &result[1..result.len() - 1];
+    }
+
+    let mut lines = result.lines().map(|line| line.trim_end());
+    let subject = lines.find(|line| !line.trim().is_empty()).unwrap_or("");
+
+    let mut body: Vec<String> = Vec::new();
+    for line in lines {
+        if line.trim().is_empty() {
+            if body.last().map(|last| !last.is_empty()).unwrap_or(false) {
+                body.push(String::new());
+            }
+            continue;
+        }
+        body.extend(wrap_line(line));
+    }
+    while body.last().map(|last| last.is_empty()).unwrap_or(false) {
+        body.pop();
+    }
+
+    if body.is_empty() {
+        subject.trim().to_owned()
+    } else {
+        format!("{}\n\n{}", subject.trim(), body.join("\n"))
+    }
+}
+
+pub fn build_commands(
+    commit_message: &str,
+    include_push: bool,
+    files: &[String],
+    source: DiffSource,
+) -> Vec<Vec<String>> {
+    let mut commands: Vec<Vec<String>> = Vec::new();
+    if source == DiffSource::Worktree {
+        let mut add_command: Vec<String> = vec!["git".to_owned(), "add".to_owned()];
+        add_command.extend(paths_to_git_paths(files));
+        commands.push(add_command);
+    }
+    let mut commit_command: Vec<String> = vec!["git".to_owned(), "commit".to_owned()];
+    if source == DiffSource::Amend {
+        commit_command.push("--amend".to_owned());
+    }
+
+    for paragraph in commit_message.split("\n\n") {
+        if paragraph.trim().is_empty() {
+            continue;
+        }
+        commit_command.push("-m".to_owned());
+        commit_command.push(paragraph.to_owned());
+    }
+
+    commands.push(commit_command);
+    if include_push {
+        commands.push("git push".split(' ').map(|s| s.to_owned()).collect());
+    }
+
+    commands
+}
+
+/// Which changes are described to the model and committed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DiffSource {
+    /// Everything in the working tree, staged before committing.
+    Worktree,
+    /// Only what is in the index, committed as it is.
+    Staged,
+    /// The last commit together with what is staged on top of it, to rewrite
+    /// its message.
+    Amend,
+    /// The changes of an existing commit, to reword it.
+    Commit(Oid),
+    /// Everything committed after the given commit up to HEAD, for a pull request.
+    Since(Oid),
+}
+
+/// Where `reword` keeps the commits it replaced.
+pub const REWORD_BACKUP_REF: &str = "refs/gpt-commit-rust/reword-backup";
+
+#[derive(Clone)]
+pub struct FileDiff {
+    pub path: String,
+    pub status: char,
+    pub additions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+    pub hunks: Vec<String>,
+}
+
+fn patch_hunks(patch: &Patch) -> Result<Vec<String>, git2::Error> {
+    let mut hunks = Vec::new();
+    for hunk_index in 0..patch.num_hunks() {
+        let (hunk, lines) = patch.hunk(hunk_index)?;
+        let mut content = String::from_utf8_lossy(hunk.header()).into_owned();
+        for line_index in 0..lines {
+            let line = patch.line_in_hunk(hunk_index, line_index)?;
+            match line.origin() {
+                '+' | '-' | ' ' => content.push(line.origin()),
+                _ => {}
+            }
+            content.push_str(&String::from_utf8_lossy(line.content()));
+        }
+        hunks.push(content);
+    }
+    Ok(hunks)
+}
+
+/// The lines of a hunk without its header, which changes as soon as other
+/// hunks of the same file are committed.
+fn hunk_body(hunk: &str) -> &str {
+    hunk.split_once('\n').map(|(_, body)| body).unwrap_or("")
+}
+
+pub struct Git {
+    pub repo: Repository,
+    _path: String,
+}
+
+impl Git {
+    pub fn new(path: String) -> Result<Self, git2::Error> {
+        let repo = Repository::open(&path);
+        if repo.is_err() {
+            return Err(repo.err().unwrap());
+        }
+        let repo = repo.unwrap();
+
+        // git points hooks to a temporary index, e.g. for `git commit -a`
+        if let Ok(index_file) = std::env::var("GIT_INDEX_FILE") {
+            let mut index = Index::open(Path::new(&index_file))?;
+            repo.set_index(&mut index)?;
+        }
+
+        Ok(Self {
+            repo,
+            _path: path.to_owned(),
+        })
+    }
+
+    pub fn clone(&self) -> Git {
+        Git::new(self._path.clone()).unwrap()
+    }
+
+    fn workdir_diff(&self) -> Result<Diff<'_>, git2::Error> {
+        let tree = self.head_tree()?;
+        let options = &mut DiffOptions::new();
+        options.include_untracked(true);
+        options.recurse_untracked_dirs(true);
+        options.include_unmodified(true);
+        options.show_untracked_content(true);
+        options.include_typechange(true);
+        self.repo
+            .diff_tree_to_workdir_with_index(tree.as_ref(), Some(options))
+    }
+
+    fn head_tree(&self) -> Result<Option<Tree<'_>>, git2::Error> {
+        match self.repo.head() {
+            Ok(head) => Ok(Some(head.peel_to_tree()?)),
+            Err(err) if err.code() == ErrorCode::UnbornBranch => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn staged_diff(&self) -> Result<Diff<'_>, git2::Error> {
+        let tree = self.head_tree()?;
+        let options = &mut DiffOptions::new();
+        options.include_typechange(true);
+        self.repo
+            .diff_tree_to_index(tree.as_ref(), None, Some(options))
+    }
+
+    /// The changes of the HEAD commit, against its parent or the empty tree,
+    /// plus what is staged since.
+    fn amend_diff(&self) -> Result<Diff<'_>, git2::Error> {
+        let head = self.repo.head()?.peel_to_commit()?;
+        let parent_tree = match head.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let options = &mut DiffOptions::new();
+        options.include_typechange(true);
+        self.repo
+            .diff_tree_to_index(parent_tree.as_ref(), None, Some(options))
+    }
+
+    /// The changes of a commit against its first parent or the empty tree.
+    fn commit_diff(&self, commit: Oid) -> Result<Diff<'_>, git2::Error> {
+        let commit = self.repo.find_commit(commit)?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let options = &mut DiffOptions::new();
+        options.include_typechange(true);
+        self.repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), Some(options))
+    }
+
+    fn since_diff(&self, base: Oid) -> Result<Diff<'_>, git2::Error> {
+        let base_tree = self.repo.find_commit(base)?.tree()?;
+        let head_tree = self.repo.head()?.peel_to_tree()?;
+        let options = &mut DiffOptions::new();
+        options.include_typechange(true);
+        self.repo
+            .diff_tree_to_tree(Some(&base_tree), Some(&head_tree), Some(options))
+    }
+
+    /// Whether the index differs from HEAD, i.e. something was staged already.
+    pub fn has_staged_changes(&self) -> Result<bool, git2::Error> {
+        Ok(self.staged_diff()?.deltas().len() > 0)
+    }
+
+    /// Plain per-file patches of the changes of `source`, used to fit the diff
+    /// into the context window of the model.
+    pub fn get_file_diffs(
+        &self,
+        files: Option<Vec<String>>,
+        source: DiffSource,
+    ) -> Result<Vec<FileDiff>, git2::Error> {
+        let diff = match source {
+            DiffSource::Worktree => self.workdir_diff()?,
+            DiffSource::Staged => self.staged_diff()?,
+            DiffSource::Amend => self.amend_diff()?,
+            DiffSource::Commit(commit) => self.commit_diff(commit)?,
+            DiffSource::Since(base) => self.since_diff(base)?,
+        };
+
+        let paths: Vec<BasePathBuf> = files
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|entry| Path::new(entry).normalize())
+            .collect();
+
+        let mut file_diffs = Vec::new();
+        for index in 0..diff.deltas().len() {
+            let patch = match Patch::from_diff(&diff, index)? {
+                Some(patch) => patch,
+                None => continue,
+            };
+            let delta = patch.delta();
+            let status = match delta.status() {
+                Delta::Unmodified | Delta::Ignored => continue,
+                Delta::Added | Delta::Untracked => 'A',
+                Delta::Deleted => 'D',
+                Delta::Renamed => 'R',
+                Delta::Copied => 'C',
+                Delta::Typechange => 'T',
+                _ => 'M',
+            };
+            let path = match delta.new_file().path().or(delta.old_file().path()) {
+                Some(path) => path,
+                None => continue,
+            };
+            if !paths.is_empty()
+                && !path
+                    .normalize()
+                    .map(|path| paths.contains(&path))
+                    .unwrap_or(false)
+            {
+                continue;
+            }
+
+            let hunks = patch_hunks(&patch)?;
+
+            let (_, additions, deletions) = patch.line_stats()?;
+            file_diffs.push(FileDiff {
+                path: path.to_string_lossy().replace('\\', "/"),
+                status,
+                additions,
+                deletions,
+                binary: delta.flags().is_binary(),
+                hunks,
+            });
+        }
+
+        Ok(file_diffs)
+    }
+
+    pub fn get_status(&self, source: DiffSource) -> Result<String, git2::Error> {
+        if let DiffSource::Commit(_) | DiffSource::Since(_) = source {
+            // the working tree has nothing to do with existing commits
+            return Ok(String::new());
+        }
+        let repo = &self.repo;
+        let options = &mut StatusOptions::new();
+        options.include_untracked(source == DiffSource::Worktree);
+        options.include_ignored(false);
+        options.recurse_untracked_dirs(true);
+        let statuses = repo.statuses(Some(options))?;
+        let mut status_value = String::new();
+
+        for entry in statuses.iter() {
+            let path = entry.path().unwrap();
+            let status = match source {
+                DiffSource::Worktree => entry.status(),
+                DiffSource::Staged
+                | DiffSource::Amend
+                | DiffSource::Commit(_)
+                | DiffSource::Since(_) => {
+                    let status = entry.status()
+                        & (git2::Status::INDEX_NEW
+                            | git2::Status::INDEX_MODIFIED
+                            | git2::Status::INDEX_DELETED
+                            | git2::Status::INDEX_RENAMED
+                            | git2::Status::INDEX_TYPECHANGE);
+                    if status.is_empty() {
+                        continue;
+                    }
+                    status
+                }
+            };
+            let status = match status {
+                git2::Status::CURRENT => break,
+                git2::Status::INDEX_NEW => "A",
+                git2::Status::INDEX_MODIFIED => "M",
+                git2::Status::INDEX_DELETED => "D",
+                git2::Status::INDEX_RENAMED => "R",
+                git2::Status::INDEX_TYPECHANGE => "Typechange",
+                git2::Status::WT_NEW => "A",
+                git2::Status::WT_MODIFIED => "M",
+                git2::Status::WT_DELETED => "D",
+                git2::Status::WT_TYPECHANGE => "Typechange",
+                git2::Status::WT_RENAMED => "R",
+                git2::Status::IGNORED => "!",
+                git2::Status::CONFLICTED => "U",
+                _ => {
+                    if status.is_wt_new() || status.is_index_new() {
+                        "A"
+                    } else if status.is_wt_modified() || status.is_index_modified() {
+                        "M"
+                    } else if status.is_wt_deleted() || status.is_index_deleted() {
+                        "D"
+                    } else if status.is_wt_renamed() || status.is_index_renamed() {
+                        "R"
+                    } else if status.is_conflicted() {
+                        "U"
+                    } else {
+                        "Unknown"
+                    }
+                }
+            };
+            match status {
+                "A" => status_value.push_str(format!("{}", path.green()).as_str()),
+                "M" => status_value.push_str(format!("{}", path.yellow()).as_str()),
+                "D" => status_value.push_str(format!("{}", path.red()).as_str()),
+                "R" => status_value.push_str(format!("{}", path.blue()).as_str()),
+                "!" => status_value.push_str(format!("{}", path.red()).as_str()),
+                "U" => status_value.push_str(format!("{}", path.red()).as_str()),
+                &_ => status_value.push_str(path),
+            }
+            status_value.push_str(format!(" {}", status).as_str());
+            status_value.push('\n');
+        }
+
+        Ok(status_value)
+    }
+
+    /// Stages the given files, or everything when no files are given, including deletions.
+    pub fn add(&self, files: Option<&Vec<String>>) -> Result<(), git2::Error> {
+        let mut index = self.repo.index()?;
+        let paths = match files {
+            Some(files) if !files.is_empty() => paths_to_git_paths(files),
+            _ => vec!["*".to_owned()],
+        };
+        index.add_all(paths.iter(), git2::IndexAddOption::DEFAULT, None)?;
+        index.update_all(paths.iter(), None)?;
+        index.write()
+    }
+
+    /// Resets the index to HEAD without touching the working tree, like `git reset`.
+    pub fn unstage_all(&self) -> Result<(), git2::Error> {
+        let mut index = self.repo.index()?;
+        match self.head_tree()? {
+            Some(tree) => index.read_tree(&tree)?,
+            None => index.clear()?,
+        }
+        index.write()
+    }
+
+    /// Stages single hunks of a file, given by their position among the hunks
+    /// that are still unstaged and by their patch. Positions tell hunks with
+    /// the same lines apart, the patch makes sure the hunk is still the same.
+    pub fn stage_hunks(&self, path: &str, hunks: &[(usize, String)]) -> Result<(), String> {
+        let options = &mut DiffOptions::new();
+        options.pathspec(path);
+        options.disable_pathspec_match(true);
+        let diff = self
+            .repo
+            .diff_index_to_workdir(None, Some(options))
+            .map_err(|err| err.message().to_owned())?;
+        let patch = match Patch::from_diff(&diff, 0).map_err(|err| err.message().to_owned())? {
+            Some(patch) => patch,
+            None => return Err(format!("{} has no unstaged changes", path)),
+        };
+
+        let current = patch_hunks(&patch).map_err(|err| err.message().to_owned())?;
+        let mut selected = vec![false; current.len()];
+        for (index, wanted) in hunks {
+            match current.get(*index) {
+                Some(hunk) if hunk_body(hunk) == hunk_body(wanted) => selected[*index] = true,
+                _ => return Err(format!("A hunk of {} no longer applies", path)),
+            }
+        }
+
+        let mut position = 0;
+        let mut apply_options = ApplyOptions::new();
+        apply_options.hunk_callback(|_| {
+            let keep = selected.get(position).copied().unwrap_or(false);
+            position += 1;
+            keep
+        });
+        self.repo
+            .apply(&diff, ApplyLocation::Index, Some(&mut apply_options))
+            .map_err(|err| err.message().to_owned())
+    }
+
+    pub fn hook_path(&self, name: &str) -> PathBuf {
+        self.hooks_dir().join(name)
+    }
+
+    fn hooks_dir(&self) -> PathBuf {
+        let hooks_path = self
+            .repo
+            .config()
+            .and_then(|config| config.get_path("core.hooksPath"));
+        match (hooks_path, self.repo.workdir()) {
+            (Ok(path), _) if path.is_absolute() => path,
+            (Ok(path), Some(workdir)) => workdir.join(path),
+            _ => self.repo.path().join("hooks"),
+        }
+    }
+
+    /// Runs a hook the way git does. Returns `Ok(false)` when the hook doesn't exist.
+    pub fn run_hook(&self, name: &str, args: &[&str]) -> Result<bool, String> {
+        let hook = self.hook_path(name);
+        if !hook.is_file() {
+            return Ok(false);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let executable = hook
+                .metadata()
+                .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false);
+            if !executable {
+                return Ok(false);
+            }
+        }
+
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("sh");
+            command.arg(&hook);
+            command
+        } else {
+            Command::new(&hook)
+        };
+        let status = command
+            .args(args)
+            .current_dir(self.repo.workdir().unwrap_or(self.repo.path()))
+            .env("GIT_INDEX_FILE", self.repo.path().join("index"))
+            .status()
+            .map_err(|err| format!("Failed to run the {} hook: {}", name, err))?;
+
+        if !status.success() {
+            return Err(format!("The {} hook failed", name));
+        }
+        Ok(true)
+    }
+
+    /// The editor `git commit` would open: `GIT_EDITOR`, `core.editor`,
+    /// `VISUAL`, `EDITOR` and finally `vi`.
+    pub fn editor(&self) -> String {
+        let from_env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        from_env("GIT_EDITOR")
+            .or_else(|| {
+                self.repo
+                    .config()
+                    .and_then(|config| config.get_string("core.editor"))
+                    .ok()
+            })
+            .or_else(|| from_env("VISUAL"))
+            .or_else(|| from_env("EDITOR"))
+            .unwrap_or(if cfg!(windows) { "notepad" } else { "vi" }.to_owned())
+    }
+
+    fn comment_char(&self) -> char {
+        self.repo
+            .config()
+            .and_then(|config| config.get_string("core.commentChar"))
+            .ok()
+            .filter(|value| value != "auto")
+            .and_then(|value| value.chars().next())
+            .filter(|char| char.is_ascii() && !char.is_whitespace())
+            .unwrap_or('#')
+    }
+
+    /// The contents of the file configured as `commit.template`.
+    fn commit_template(&self) -> Option<String> {
+        let path = self
+            .repo
+            .config()
+            .and_then(|config| config.get_path("commit.template"))
+            .ok()?;
+        let path = match self.repo.workdir() {
+            Some(workdir) if path.is_relative() => workdir.join(path),
+            _ => path,
+        };
+        fs::read_to_string(path).ok()
+    }
+
+    /// Lets the user edit `message` in their editor like `git commit` does.
+    /// The file lists the status and a diffstat of the changes as comments
+    /// and the commit template, comments are stripped again afterwards.
+    pub fn edit_message(
+        &self,
+        message: &str,
+        files: &[String],
+        source: DiffSource,
+    ) -> Result<String, String> {
+        let comment = self.comment_char();
+        let diffs = self
+            .get_file_diffs(Some(files.to_vec()), source)
+            .map_err(|err| err.message().to_owned())?;
+        let branch = self
+            .repo
+            .head()
+            .ok()
+            .and_then(|head| head.shorthand().map(|name| name.to_owned()))
+            .or_else(|| {
+                self.repo
+                    .find_reference("HEAD")
+                    .ok()
+                    .and_then(|head| head.symbolic_target().map(|target| target.to_owned()))
+                    .map(|target| target.trim_start_matches("refs/heads/").to_owned())
+            })
+            .unwrap_or("HEAD".to_owned());
+
+        let mut content = format!("{}\n", message.trim_end());
+        if let Some(template) = self.commit_template() {
+            content.push('\n');
+            // the message is already written, so the template only serves as a guide
+            for line in template.lines() {
+                let line = if line.starts_with(comment) {
+                    line.to_owned()
+                } else if line.trim().is_empty() {
+                    comment.to_string()
+                } else {
+                    format!("{} {}", comment, line)
+                };
+                content.push_str(&format!("{}\n", line));
+            }
+        }
+
+        let mut comments = vec![
+            String::new(),
+            "Please enter the commit message for your changes. Lines starting".to_owned(),
+            format!(
+                "with '{}' will be ignored, and an empty message aborts the commit.",
+                comment
+            ),
+            String::new(),
+            format!("On branch {}", branch),
+            "Changes to be committed:".to_owned(),
+        ];
+        for file in &diffs {
+            let status = match file.status {
+                'A' => "new file",
+                'D' => "deleted",
+                'R' => "renamed",
+                'C' => "copied",
+                'T' => "typechange",
+                _ => "modified",
+            };
+            comments.push(format!("\t{:<12}{}", format!("{}:", status), file.path));
+        }
+        comments.push(String::new());
+        comments.extend(diffstat_lines(&diffs));
+        content.push('\n');
+        for line in comments {
+            if line.is_empty() || line.starts_with('\t') {
+                content.push_str(&format!("{}{}\n", comment, line));
+            } else {
+                content.push_str(&format!("{} {}\n", comment, line));
+            }
+        }
+
+        let message_file = self.repo.path().join("COMMIT_EDITMSG");
+        fs::write(&message_file, content).map_err(|err| err.to_string())?;
+
+        let editor = self.editor();
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command
+                .arg("/C")
+                .arg(format!("{} \"{}\"", editor, message_file.display()));
+            command
+        } else {
+            // through the shell like git, so editors with arguments work
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!("{} \"$@\"", editor))
+                .arg(&editor)
+                .arg(&message_file);
+            command
+        };
+        let status = command
+            .current_dir(self.repo.workdir().unwrap_or(self.repo.path()))
+            .status()
+            .map_err(|err| format!("Failed to start the editor {}: {}", editor, err))?;
+        if !status.success() {
+            return Err(format!("The editor {} failed", editor));
+        }
+
+        let content = fs::read_to_string(&message_file).map_err(|err| err.to_string())?;
+        let scissors = format!(
+            "{} ------------------------ >8 ------------------------",
+            comment
+        );
+        let content = content.split(&scissors).next().unwrap_or("");
+        let message =
+            git2::message_prettify(content, Some(comment as u8)).map_err(|err| err.to_string())?;
+        if message.trim().is_empty() {
+            return Err("Aborting commit due to empty commit message".to_owned());
+        }
+
+        Ok(message.trim_end().to_owned())
+    }
+
+    /// Runs the hooks `git commit` runs before committing and returns the
+    /// message as they left it, with whitespace cleaned up.
+    fn prepare_message(&self, message: &str, source: &[&str]) -> Result<String, String> {
+        self.run_hook("pre-commit", &[])?;
+
+        let message_file = self.repo.path().join("COMMIT_EDITMSG");
+        fs::write(&message_file, format!("{}\n", message.trim_end()))
+            .map_err(|err| err.to_string())?;
+        let message_file_arg = message_file.to_string_lossy().into_owned();
+        let mut args = vec![message_file_arg.as_str()];
+        args.extend(source);
+        self.run_hook("prepare-commit-msg", &args)?;
+        self.run_hook("commit-msg", &[&message_file_arg])?;
+
+        let message = fs::read_to_string(&message_file).map_err(|err| err.to_string())?;
+        let message = git2::message_prettify(message, None).map_err(|err| err.to_string())?;
+        if message.trim().is_empty() {
+            return Err("Aborting commit due to empty commit message".to_owned());
+        }
+        Ok(message)
+    }
+
+    fn index_tree(&self) -> Result<Tree<'_>, String> {
+        let mut index = self.repo.index().map_err(|err| err.to_string())?;
+        index.read(true).map_err(|err| err.to_string())?;
+        let oid = index.write_tree().map_err(|err| err.to_string())?;
+        self.repo.find_tree(oid).map_err(|err| err.to_string())
+    }
+
+    /// Prints the new commit like `git commit` does and runs `post-commit`.
+    fn finish_commit(&self, commit: Oid, message: &str, root: bool) {
+        let commit_hash = commit.to_string();
+        let subject = message.lines().next().unwrap_or("");
+        println!(
+            "[{}{} {}] {}",
+            self.repo
+                .head()
+                .ok()
+                .and_then(|head| head.shorthand().map(|name| name.to_owned()))
+                .unwrap_or("HEAD".to_owned()),
+            if root { " (root-commit)" } else { "" },
+            &commit_hash[..7],
+            replace_gitmoji_with_emoji(subject)
+        );
+
+        self.run_hook("post-commit", &[]).ok();
+    }
+
+    /// Commits the index with `message` like `git commit` does: runs the
+    /// `pre-commit`, `prepare-commit-msg` and `commit-msg` hooks, keeps the
+    /// message as it is apart from whitespace cleanup and works on an unborn HEAD.
+    pub fn commit(&self, message: &str) -> Result<Oid, String> {
+        let message = self.prepare_message(message, &["message"])?;
+        let tree = self.index_tree()?;
+        let signature = self.repo.signature().map_err(|err| err.to_string())?;
+
+        let parent_commit = match self.repo.head() {
+            Ok(head) => Some(head.peel_to_commit().map_err(|err| err.to_string())?),
+            Err(err) if err.code() == ErrorCode::UnbornBranch => None,
+            Err(err) => return Err(err.to_string()),
+        };
+        let parents = parent_commit.iter().collect::<Vec<&Commit>>();
+
+        let commit = self
+            .repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                &message,
+                &tree,
+                &parents,
+            )
+            .map_err(|err| err.message().to_owned())?;
+
+        self.finish_commit(commit, &message, parents.is_empty());
+        Ok(commit)
+    }
+
+    /// The message of a commit, without trailing whitespace.
+    pub fn commit_message(&self, commit: Oid) -> Result<String, String> {
+        let commit = self
+            .repo
+            .find_commit(commit)
+            .map_err(|err| err.message().to_owned())?;
+        Ok(commit.message().unwrap_or("").trim_end().to_owned())
+    }
+
+    /// The commits of a range like `main..HEAD`, oldest first. A single
+    /// revision stands for everything after it up to HEAD. The range has to
+    /// end in HEAD or one of its ancestors.
+    pub fn range_commits(&self, range: &str) -> Result<Vec<Oid>, String> {
+        let spec = self
+            .repo
+            .revparse(range)
+            .map_err(|err| err.message().to_owned())?;
+        if spec.mode().contains(RevparseMode::MERGE_BASE) {
+            return Err("Ranges with ... aren't supported, use ..".to_owned());
+        }
+        let head = self
+            .repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|_| "There are no commits yet".to_owned())?
+            .id();
+        let (from, to) = if spec.mode().contains(RevparseMode::RANGE) {
+            (spec.from(), spec.to())
+        } else {
+            (spec.from(), None)
+        };
+        let to = match to {
+            Some(to) => to
+                .peel_to_commit()
+                .map_err(|err| err.message().to_owned())?
+                .id(),
+            None => head,
+        };
+        if to != head && !self.repo.graph_descendant_of(head, to).unwrap_or(false) {
+            return Err(format!("{} isn't part of the current branch", to));
+        }
+
+        let mut walk = self
+            .repo
+            .revwalk()
+            .map_err(|err| err.message().to_owned())?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)
+            .map_err(|err| err.message().to_owned())?;
+        walk.push(to).map_err(|err| err.message().to_owned())?;
+        if let Some(from) = from {
+            let from = from
+                .peel_to_commit()
+                .map_err(|err| err.message().to_owned())?;
+            walk.hide(from.id())
+                .map_err(|err| err.message().to_owned())?;
+        }
+        walk.collect::<Result<Vec<Oid>, git2::Error>>()
+            .map_err(|err| err.message().to_owned())
+    }
+
+    /// Every commit a reword of `commits` creates anew: the commits themselves
+    /// and everything after them up to HEAD, oldest first.
+    pub fn replayed_commits(&self, commits: &[Oid]) -> Result<Vec<Oid>, String> {
+        let mut walk = self
+            .repo
+            .revwalk()
+            .map_err(|err| err.message().to_owned())?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)
+            .map_err(|err| err.message().to_owned())?;
+        walk.push_head().map_err(|err| err.message().to_owned())?;
+        for commit in commits {
+            let commit = self
+                .repo
+                .find_commit(*commit)
+                .map_err(|err| err.message().to_owned())?;
+            for parent in commit.parent_ids() {
+                if !commits.contains(&parent) {
+                    walk.hide(parent).map_err(|err| err.message().to_owned())?;
+                }
+            }
+        }
+        walk.collect::<Result<Vec<Oid>, git2::Error>>()
+            .map_err(|err| err.message().to_owned())
+    }
+
+    pub fn is_merge(&self, commit: Oid) -> bool {
+        self.repo
+            .find_commit(commit)
+            .map(|commit| commit.parent_count() > 1)
+            .unwrap_or(false)
+    }
+
+    /// The remote branch that contains the commit already, if any.
+    pub fn published_in(&self, commit: Oid) -> Option<String> {
+        let branches = self.repo.branches(Some(BranchType::Remote)).ok()?;
+        for (branch, _) in branches.flatten() {
+            let Some(target) = branch.get().target() else {
+                continue;
+            };
+            if target == commit
+                || self
+                    .repo
+                    .graph_descendant_of(target, commit)
+                    .unwrap_or(false)
+            {
+                return branch.name().ok().flatten().map(|name| name.to_owned());
+            }
+        }
+        None
+    }
+
+    /// Gives the commits new messages and replays everything after them on
+    /// top, like an interactive rebase that only rewords. Trees, authors and
+    /// dates stay the same, so the index and the working tree don't change.
+    /// The previous HEAD is kept in `REWORD_BACKUP_REF`.
+    pub fn reword(&self, messages: &HashMap<Oid, String>) -> Result<Oid, String> {
+        let commits = messages.keys().copied().collect::<Vec<Oid>>();
+        let replayed = self.replayed_commits(&commits)?;
+        let signature = self.repo.signature().map_err(|err| err.to_string())?;
+
+        let mut rewritten: HashMap<Oid, Oid> = HashMap::new();
+        for id in &replayed {
+            let commit = self
+                .repo
+                .find_commit(*id)
+                .map_err(|err| err.message().to_owned())?;
+            let parents = commit
+                .parent_ids()
+                .map(|parent| rewritten.get(&parent).copied().unwrap_or(parent))
+                .map(|parent| self.repo.find_commit(parent))
+                .collect::<Result<Vec<Commit>, git2::Error>>()
+                .map_err(|err| err.message().to_owned())?;
+            let message = match messages.get(id) {
+                Some(message) => {
+                    git2::message_prettify(message, None).map_err(|err| err.message().to_owned())?
+                }
+                None => commit.message().unwrap_or("").to_owned(),
+            };
+            let new_id = self
+                .repo
+                .commit(
+                    None,
+                    &commit.author(),
+                    &signature,
+                    &message,
+                    &commit.tree().map_err(|err| err.message().to_owned())?,
+                    &parents.iter().collect::<Vec<&Commit>>(),
+                )
+                .map_err(|err| err.message().to_owned())?;
+            rewritten.insert(*id, new_id);
+        }
+
+        let head = self.repo.head().map_err(|err| err.message().to_owned())?;
+        let old_head = head.target().ok_or("HEAD doesn't point to a commit")?;
+        let new_head = rewritten.get(&old_head).copied().unwrap_or(old_head);
+        let log_message = format!("gpt-commit-rust reword: {} commits", messages.len());
+        self.repo
+            .reference(REWORD_BACKUP_REF, old_head, true, &log_message)
+            .map_err(|err| err.message().to_owned())?;
+        if head.is_branch() {
+            let name = head.name().ok_or("Invalid branch name")?;
+            self.repo
+                .reference(name, new_head, true, &log_message)
+                .map_err(|err| err.message().to_owned())?;
+        } else {
+            self.repo
+                .set_head_detached(new_head)
+                .map_err(|err| err.message().to_owned())?;
+        }
+
+        Ok(new_head)
+    }
+
+    /// The branch pull requests go into by default: the default branch of
+    /// origin, or else main or master.
+    pub fn default_base(&self) -> Option<String> {
+        if let Some(target) = self
+            .repo
+            .find_reference("refs/remotes/origin/HEAD")
+            .ok()
+            .and_then(|head| head.symbolic_target().map(|target| target.to_owned()))
+        {
+            return Some(target.trim_start_matches("refs/remotes/").to_owned());
+        }
+        ["main", "master", "origin/main", "origin/master"]
+            .into_iter()
+            .find(|name| self.repo.revparse_single(name).is_ok())
+            .map(|name| name.to_owned())
+    }
+
+    /// Where the current branch forked off `base`.
+    pub fn merge_base(&self, base: &str) -> Result<Oid, String> {
+        let base = self
+            .repo
+            .revparse_single(base)
+            .and_then(|base| base.peel_to_commit())
+            .map_err(|_| format!("Unknown base branch \"{}\"", base))?;
+        let head = self
+            .repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|_| "There are no commits yet".to_owned())?;
+        self.repo
+            .merge_base(base.id(), head.id())
+            .map_err(|err| err.message().to_owned())
+    }
+
+    /// The message of the HEAD commit.
+    pub fn head_message(&self) -> Result<String, String> {
+        let head = self
+            .repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|_| "There is no commit to amend".to_owned())?;
+        Ok(head.message().unwrap_or("").trim_end().to_owned())
+    }
+
+    /// Replaces the HEAD commit with one of the index and `message`, like
+    /// `git commit --amend`. The author and the author date are kept.
+    pub fn amend(&self, message: &str) -> Result<Oid, String> {
+        let head = self
+            .repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(|_| "There is no commit to amend".to_owned())?;
+        let message = self.prepare_message(message, &["commit", "HEAD"])?;
+        let tree = self.index_tree()?;
+        let signature = self.repo.signature().map_err(|err| err.to_string())?;
+
+        let commit = head
+            .amend(
+                Some("HEAD"),
+                None,
+                Some(&signature),
+                None,
+                Some(&message),
+                Some(&tree),
+            )
+            .map_err(|err| err.message().to_owned())?;
+
+        self.finish_commit(commit, &message, head.parent_count() == 0);
+        Ok(commit)
+    }
+
+    /// The upstream of the current branch if it already contains HEAD, so
+    /// amending would rewrite published history.
+    pub fn pushed_upstream(&self) -> Option<String> {
+        let head = self.repo.head().ok()?;
+        let head_commit = head.target()?;
+        let branch = git2::Branch::wrap(head);
+        let upstream = branch.upstream().ok()?;
+        let upstream_commit = upstream.get().target()?;
+        let contains = upstream_commit == head_commit
+            || self
+                .repo
+                .graph_descendant_of(upstream_commit, head_commit)
+                .unwrap_or(false);
+        contains.then(|| {
+            upstream
+                .name()
+                .ok()
+                .flatten()
+                .unwrap_or("the upstream")
+                .to_owned()
+        })
+    }
+
+    pub fn push(&self) -> Result<(), String> {
+        run_commands(&vec![vec!["git".to_owned(), "push".to_owned()]])
+    }
+}
+
+/// A diffstat in the style of `git diff --stat`.
+fn diffstat_lines(diffs: &[FileDiff]) -> Vec<String> {
+    let width = diffs.iter().map(|file| file.path.len()).max().unwrap_or(0);
+    let largest = diffs
+        .iter()
+        .map(|file| file.additions + file.deletions)
+        .max()
+        .unwrap_or(0);
+    let scale = |count: usize| {
+        if largest <= 40 {
+            count
+        } else {
+            (count * 40).div_ceil(largest)
+        }
+    };
+
+    let mut lines = diffs
+        .iter()
+        .map(|file| {
+            if file.binary {
+                return format!(" {:<width$} | Bin", file.path);
+            }
+            format!(
+                " {:<width$} | {} {}{}",
+                file.path,
+                file.additions + file.deletions,
+                "+".repeat(scale(file.additions)),
+                "-".repeat(scale(file.deletions))
+            )
+        })
+        .collect::<Vec<String>>();
+    lines.push(format!(
+        " {} file{} changed, {} insertions(+), {} deletions(-)",
+        diffs.len(),
+        if diffs.len() == 1 { "" } else { "s" },
+        diffs.iter().map(|file| file.additions).sum::<usize>(),
+        diffs.iter().map(|file| file.deletions).sum::<usize>()
+    ));
+    lines
+}
+
+fn paths_to_git_paths(paths: &[String]) -> Vec<String> {
+    if paths.is_empty() {
+        return vec![".".to_owned()];
+    }
+    paths
+        .iter()
+        .map(Path::new)
+        .map(|entry| {
+            entry
+                .iter()
+                .map(|entry| entry.to_str().unwrap().to_owned())
+                .filter(|entry| entry != ".")
+                .collect::<Vec<String>>()
+                .join("/")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A repository in a temporary directory, removed again when dropped.
+    pub(crate) struct TestRepo {
+        pub dir: PathBuf,
+        pub git: Git,
+    }
+
+    impl TestRepo {
+        pub fn new(name: &str) -> TestRepo {
+            let dir = std::env::temp_dir().join(format!(
+                "gpt-commit-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::remove_dir_all(&dir).ok();
+            let repo = Repository::init(&dir).unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+            TestRepo {
+                git: Git::new(dir.to_string_lossy().into_owned()).unwrap(),
+                dir,
+            }
+        }
+
+        pub fn write(&self, path: &str, content: &str) {
+            let path = self.dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        /// Stages everything and commits it without running any hooks.
+        pub fn commit_all(&self, message: &str) -> Oid {
+            let repo = &self.git.repo;
+            let mut index = repo.index().unwrap();
+            index
+                .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+                .unwrap();
+            index.update_all(["*"], None).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = repo.signature().unwrap();
+            let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+            repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parent.iter().collect::<Vec<&Commit>>(),
+            )
+            .unwrap()
+        }
+
+        /// Installs an executable hook.
+        #[cfg(unix)]
+        pub fn hook(&self, name: &str, script: &str) {
+            use std::os::unix::fs::PermissionsExt;
+            let path = self.git.hook_path(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        /// The staged content of a file.
+        pub fn staged(&self, path: &str) -> String {
+            let index = self.git.repo.index().unwrap();
+            let entry = index.get_path(Path::new(path), 0).unwrap();
+            let blob = self.git.repo.find_blob(entry.id).unwrap();
+            String::from_utf8_lossy(blob.content()).into_owned()
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    #[test]
+    fn formats_the_reply_as_subject_and_wrapped_body() {
+        let long = "word ".repeat(20);
+        let message = format_commit_message(&format!(
+            "\"Fix the port  \n\n\n{}\n\n\nSecond paragraph.\n\n\"",
+            long
+        ));
+        let lines = message.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "Fix the port");
+        assert_eq!(lines[1], "");
+        assert!(lines[2].len() <= BODY_WIDTH && lines[3].len() <= BODY_WIDTH);
+        assert_eq!(format!("{} {}", lines[2], lines[3]), long.trim());
+        assert_eq!(&lines[4..], ["", "Second paragraph."]);
+
+        assert_eq!(
+            format_commit_message("\n\nFix the port\n\n"),
+            "Fix the port"
+        );
+    }
+
+    #[test]
+    fn builds_one_message_argument_per_paragraph() {
+        let files = vec!["src/main.rs".to_owned()];
+        let commands = build_commands(
+            "Subject\n\nBody\nmore\n\nFooter",
+            true,
+            &files,
+            DiffSource::Worktree,
+        );
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[0][..2], ["git", "add"]);
+        assert_eq!(
+            commands[1],
+            [
+                "git",
+                "commit",
+                "-m",
+                "Subject",
+                "-m",
+                "Body\nmore",
+                "-m",
+                "Footer"
+            ]
+        );
+        assert_eq!(commands[2], ["git", "push"]);
+
+        let commands = build_commands("Subject", false, &files, DiffSource::Staged);
+        assert_eq!(commands, vec![vec!["git", "commit", "-m", "Subject"]]);
+    }
+
+    #[test]
+    fn commits_an_unborn_head_with_the_whole_message() {
+        let test = TestRepo::new("commit");
+        test.write("a.txt", "a\n");
+        test.git.add(None).unwrap();
+
+        let message = "Add a\n\nFirst paragraph\nstill the first.\n\nSecond paragraph.";
+        let commit = test.git.commit(message).unwrap();
+        assert_eq!(test.git.commit_message(commit).unwrap(), message);
+        let commit = test.git.repo.find_commit(commit).unwrap();
+        assert_eq!(commit.parent_count(), 0);
+        assert_eq!(commit.author().name(), Some("Test"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commit_runs_the_hooks() {
+        let test = TestRepo::new("commit-hooks");
+        test.write("a.txt", "a\n");
+        test.git.add(None).unwrap();
+        test.hook(
+            "commit-msg",
+            "printf '\\nSigned-off-by: Test <test@example.com>\\n' >> \"$1\"",
+        );
+
+        let commit = test.git.commit("Add a").unwrap();
+        assert_eq!(
+            test.git.commit_message(commit).unwrap(),
+            "Add a\n\nSigned-off-by: Test <test@example.com>"
+        );
+
+        test.write("a.txt", "b\n");
+        test.git.add(None).unwrap();
+        test.hook("pre-commit", "exit 1");
+        assert!(test.git.commit("Change a").is_err());
+        assert_eq!(test.git.repo.head().unwrap().target(), Some(commit));
+    }
+
+    #[test]
+    fn staged_source_only_sees_the_index() {
+        let test = TestRepo::new("staged");
+        test.write("a.txt", "a\n");
+        test.write("b.txt", "b\n");
+        test.commit_all("init");
+        let git = &test.git;
+        assert!(!git.has_staged_changes().unwrap());
+
+        test.write("a.txt", "a\nstaged\n");
+        git.add(Some(&vec!["a.txt".to_owned()])).unwrap();
+        test.write("a.txt", "a\nstaged\nnot staged\n");
+        test.write("b.txt", "b\nnot staged\n");
+        test.write("new.txt", "untracked\n");
+        assert!(git.has_staged_changes().unwrap());
+
+        let diffs = git.get_file_diffs(None, DiffSource::Staged).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].path, "a.txt");
+        assert_eq!((diffs[0].additions, diffs[0].deletions), (1, 0));
+        assert!(diffs[0].hunks[0].contains("+staged\n"));
+        assert!(!diffs[0].hunks[0].contains("not staged"));
+
+        let status = git.get_status(DiffSource::Staged).unwrap();
+        assert!(status.contains("a.txt"));
+        assert!(!status.contains("b.txt") && !status.contains("new.txt"));
+
+        let worktree = git.get_file_diffs(None, DiffSource::Worktree).unwrap();
+        let paths = worktree
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(paths, ["a.txt", "b.txt", "new.txt"]);
+
+        // committing the index leaves the rest in the working tree
+        git.commit("Stage a").unwrap();
+        assert!(!git.has_staged_changes().unwrap());
+        assert_eq!(
+            fs::read_to_string(test.dir.join("a.txt")).unwrap(),
+            "a\nstaged\nnot staged\n"
+        );
+        assert_eq!(test.staged("a.txt"), "a\nstaged\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edits_the_message_like_git_commit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test = TestRepo::new("edit");
+        test.write("a.txt", "a\n");
+        test.commit_all("init");
+        test.write("a.txt", "b\n");
+        test.write("template.txt", "Why:\n\n# a hint\n");
+
+        let editor = test.git.repo.path().join("edit.sh");
+        let seen = test.git.repo.path().join("seen.txt");
+        fs::write(
+            &editor,
+            format!(
+                "#!/bin/sh\ncp \"$1\" '{}'\nprintf '%s\\n' 'Edited subject' '' '; a comment' 'Body' \
+                 '; ------------------------ >8 ------------------------' 'Kept out' > \"$1\"\n",
+                seen.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = test.git.repo.config().unwrap();
+        config
+            .set_str("core.editor", &editor.to_string_lossy())
+            .unwrap();
+        config.set_str("core.commentChar", ";").unwrap();
+        config.set_str("commit.template", "template.txt").unwrap();
+        // it would win over core.editor, some environments set it to `true`
+        std::env::remove_var("GIT_EDITOR");
+
+        let message = test
+            .git
+            .edit_message("Change a\n\nIt was a.", &[], DiffSource::Worktree)
+            .unwrap();
+        assert_eq!(message, "Edited subject\n\nBody");
+
+        let seen = fs::read_to_string(seen).unwrap();
+        assert!(seen.starts_with("Change a\n\nIt was a.\n\n; Why:\n;\n; # a hint\n"));
+        assert!(seen.contains("; Changes to be committed:\n"));
+        assert!(seen.contains(";\tmodified:   a.txt\n"));
+
+        fs::write(&editor, "#!/bin/sh\necho '; nothing left' > \"$1\"\n").unwrap();
+        assert!(test
+            .git
+            .edit_message("Change a", &[], DiffSource::Worktree)
+            .is_err());
+    }
+
+    #[test]
+    fn amend_rewrites_head_with_the_staged_changes() {
+        let test = TestRepo::new("amend");
+        let git = &test.git;
+        assert!(git.head_message().is_err());
+
+        test.write("a.txt", "a\n");
+        let root = test.commit_all("Add a");
+        test.write("b.txt", "b\n");
+        let old = test.commit_all("Add b");
+        test.write("c.txt", "c\n");
+        git.add(None).unwrap();
+
+        let paths = |source| {
+            git.get_file_diffs(None, source)
+                .unwrap()
+                .into_iter()
+                .map(|file| file.path)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(paths(DiffSource::Amend), ["b.txt", "c.txt"]);
+
+        let amended = git.amend("Add b and c\n\nBoth at once.").unwrap();
+        assert_eq!(git.head_message().unwrap(), "Add b and c\n\nBoth at once.");
+        let (old, amended) = (
+            git.repo.find_commit(old).unwrap(),
+            git.repo.find_commit(amended).unwrap(),
+        );
+        assert_eq!(amended.parent_id(0).unwrap(), root);
+        assert_eq!(amended.author().when(), old.author().when());
+        assert_eq!(amended.author().name(), old.author().name());
+        assert!(amended.tree().unwrap().get_name("c.txt").is_some());
+        assert!(!git.has_staged_changes().unwrap());
+    }
+
+    #[test]
+    fn finds_out_whether_head_is_pushed() {
+        let test = TestRepo::new("pushed");
+        test.write("a.txt", "a\n");
+        let head = test.commit_all("Add a");
+        let git = &test.git;
+        assert_eq!(git.pushed_upstream(), None);
+
+        let branch = git.repo.head().unwrap().shorthand().unwrap().to_owned();
+        git.repo
+            .remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        git.repo
+            .reference(&format!("refs/remotes/origin/{}", branch), head, true, "")
+            .unwrap();
+        let mut config = git.repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{}.remote", branch), "origin")
+            .unwrap();
+        config
+            .set_str(
+                &format!("branch.{}.merge", branch),
+                &format!("refs/heads/{}", branch),
+            )
+            .unwrap();
+        assert_eq!(git.pushed_upstream(), Some(format!("origin/{}", branch)));
+
+        // a local commit on top isn't published yet
+        test.write("b.txt", "b\n");
+        test.commit_all("Add b");
+        assert_eq!(git.pushed_upstream(), None);
+    }
+
+    #[test]
+    fn rewords_commits_and_replays_the_rest() {
+        let test = TestRepo::new("reword");
+        let git = &test.git;
+        test.write("a.txt", "a\n");
+        let a = test.commit_all("Add a");
+        test.write("b.txt", "b\n");
+        let b = test.commit_all("Add b");
+        test.write("c.txt", "c\n");
+        let c = test.commit_all("Add c");
+
+        assert_eq!(git.range_commits("HEAD~2..HEAD").unwrap(), [b, c]);
+        assert_eq!(git.range_commits("HEAD~2").unwrap(), [b, c]);
+        assert!(git.range_commits("HEAD~2...HEAD").is_err());
+        assert_eq!(git.replayed_commits(&[b]).unwrap(), [b, c]);
+        assert!(!git.is_merge(c));
+
+        let messages = HashMap::from([(b, "Rewrite b\n\n\nWith a body.  \n".to_owned())]);
+        let head = git.reword(&messages).unwrap();
+        let new_c = git.repo.find_commit(head).unwrap();
+        let new_b = new_c.parent(0).unwrap();
+        assert_eq!(new_b.message(), Some("Rewrite b\n\nWith a body.\n"));
+        assert_eq!(new_b.parent_id(0).unwrap(), a);
+        assert_eq!(new_c.message(), Some("Add c"));
+        let old_c = git.repo.find_commit(c).unwrap();
+        assert_eq!(new_c.tree_id(), old_c.tree_id());
+        assert_eq!(new_c.author().when(), old_c.author().when());
+        assert_eq!(git.repo.head().unwrap().target(), Some(head));
+        assert_eq!(
+            git.repo.refname_to_id(REWORD_BACKUP_REF).unwrap(),
+            c,
+            "the old HEAD is kept"
+        );
+    }
+
+    #[test]
+    fn tells_merges_apart() {
+        let test = TestRepo::new("merge");
+        let git = &test.git;
+        test.write("a.txt", "a\n");
+        let a = test.commit_all("Add a");
+        test.write("b.txt", "b\n");
+        let b = test.commit_all("Add b");
+
+        let (a, b) = (
+            git.repo.find_commit(a).unwrap(),
+            git.repo.find_commit(b).unwrap(),
+        );
+        let signature = git.repo.signature().unwrap();
+        let merge = git
+            .repo
+            .commit(
+                None,
+                &signature,
+                &signature,
+                "Merge",
+                &b.tree().unwrap(),
+                &[&b, &a],
+            )
+            .unwrap();
+        assert!(git.is_merge(merge));
+        assert!(!git.is_merge(b.id()));
+    }
+
+    #[test]
+    fn diffs_a_branch_against_its_base() {
+        let test = TestRepo::new("pull-request");
+        let git = &test.git;
+        test.write("a.txt", "a\n");
+        let a = test.commit_all("Add a");
+        let main = git.repo.head().unwrap().shorthand().unwrap().to_owned();
+        assert_eq!(git.default_base(), Some(main.clone()));
+
+        let fork = git.repo.find_commit(a).unwrap();
+        git.repo.branch("feature", &fork, false).unwrap();
+        git.repo.set_head("refs/heads/feature").unwrap();
+        test.write("a.txt", "a\nmore a\n");
+        test.commit_all("Extend a");
+        test.write("b.txt", "b\n");
+        test.commit_all("Add b");
+
+        let base = git.merge_base(&main).unwrap();
+        assert_eq!(base, a);
+        assert!(git.merge_base("no-such-branch").is_err());
+        let diffs = git.get_file_diffs(None, DiffSource::Since(base)).unwrap();
+        assert_eq!(
+            diffs
+                .iter()
+                .map(|file| (file.path.as_str(), file.status))
+                .collect::<Vec<(&str, char)>>(),
+            [("a.txt", 'M'), ("b.txt", 'A')]
+        );
+    }
+
+    /// The same change in two places, so both hunks have the same lines.
+    fn block(marker: &str) -> String {
+        format!("a\nb\nc\n{}\nd\ne\nf\n", marker)
+    }
+
+    fn content(first: &str, second: &str) -> String {
+        let middle = (1..=10)
+            .map(|line| format!("middle {}\n", line))
+            .collect::<String>();
+        format!("{}{}{}", block(first), middle, block(second))
+    }
+
+    #[test]
+    fn stages_hunks_with_the_same_lines_by_position() {
+        let test = TestRepo::new("hunks");
+        test.write("file.txt", &content("old", "old"));
+        test.commit_all("init");
+        test.write("file.txt", &content("new", "new"));
+
+        let git = &test.git;
+        let diff = git.repo.diff_index_to_workdir(None, None).unwrap();
+        let hunks = patch_hunks(&Patch::from_diff(&diff, 0).unwrap().unwrap()).unwrap();
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunk_body(&hunks[0]), hunk_body(&hunks[1]));
+
+        git.stage_hunks("file.txt", &[(1, hunks[1].to_owned())])
+            .unwrap();
+        assert_eq!(test.staged("file.txt"), content("old", "new"));
+
+        assert!(git
+            .stage_hunks("file.txt", &[(1, hunks[1].to_owned())])
+            .is_err());
+    }
+}
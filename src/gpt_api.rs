@@ -1,226 +1,1081 @@
-use std::borrow::ToOwned;
-use colored::Colorize;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-
-use crate::git::Git;
-use crate::query_params::{build_initial_message, build_query, build_readme_query};
-use crate::utils;
-
-pub(crate) const PORT: i32 = 1234;
-pub(crate) const API_URL: &str = "http://localhost";
-pub(crate) const MODEL_NAME: &str = "deepseek-coder-v2-lite-instruct";
-
-#[derive(Debug, Deserialize, Serialize)]
-struct OpenApiResponseBody {
-    id: String,
-    object: String,
-    created: u64,
-    choices: Vec<OpenApiResponseBodyChoice>,
-    usage: OpenApiResponseBodyUsage,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct OpenApiResponseBodyChoice {
-    index: i32,
-    message: OpenApiMessage,
-    finish_reason: String,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct OpenApiMessage {
-    role: String,
-    content: String,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct OpenApiResponseBodyUsage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct OpenApiRequestBody {
-    model: String,
-    messages: Vec<OpenApiMessage>,
-    temperature: f32,
-    max_tokens: i32,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct ErrorResponse {
-    error: Error,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct Error {
-    message: Option<String>,
-    #[serde(rename = "type")]
-    _type: Option<String>,
-    param: Option<String>,
-    code: Option<String>,
-}
-
-async fn post_api_call(
-    url: &str,
-    body: &str,
-    additional_headers: Option<HeaderMap>,
-) -> Result<String, String> {
-    let client = Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    if let Some(additional_headers) = additional_headers {
-        headers.extend(additional_headers);
-    }
-    let res = client
-        .post(url)
-        .headers(headers)
-        .body(body.to_owned())
-        .send()
-        .await;
-
-    if res.is_err() {
-        return Err(res.unwrap_err().to_string());
-    }
-
-    let res = res.unwrap().text().await;
-
-    if res.is_err() {
-        return Err(res.unwrap_err().to_string());
-    }
-
-    Ok(res.unwrap())
-}
-
-pub async fn query(
-    previous_response: Option<Vec<String>>,
-    git: &Git,
-    files: Vec<String>,
-) -> Result<String, String> {
-    let config = utils::get_config();
-
-    let mut messages: Vec<OpenApiMessage> = Vec::new();
-    messages.push(OpenApiMessage {
-        role: "system".to_owned(),
-        content: build_initial_message().to_owned(),
-    });
-    messages.push(OpenApiMessage {
-        role: "user".to_owned(),
-        content: build_query(git, files).to_owned(),
-    });
-    if let Some(previous_response) = previous_response {
-        for response in previous_response {
-            messages.push(OpenApiMessage {
-                role: "agent".to_owned(),
-                content: response,
-            });
-        }
-    }
-
-    let body = OpenApiRequestBody {
-        model: config.get_model_name(),
-        messages,
-        temperature: 0.9,
-        max_tokens: 250,
-    };
-
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        format!("Bearer {}", config.get_api_key()).parse().unwrap(),
-    );
-
-    let port = config.get_port(PORT);
-
-    let result = post_api_call(
-        format!("{API_URL}:{port}/v1/chat/completions").as_str(),
-        serde_json::to_string(&body).unwrap().as_str(),
-        Some(headers),
-    )
-    .await;
-
-    if let Err(err) = result {
-        println!("Error: {}", err.red());
-        std::process::exit(0);
-    }
-
-    let json =
-        serde_json::from_str::<OpenApiResponseBody>(&result.clone().unwrap()).or_else(|_| {
-            let json = serde_json::from_str::<ErrorResponse>(&result.unwrap());
-            if let Err(err) = json {
-                return Err(format!("{}", err));
-            }
-            let error = json.unwrap().error;
-            let message = error.message.unwrap_or("".to_owned());
-            let code = error.code.unwrap_or("".to_owned());
-            println!("Error: {} {}", message.red(), code.red());
-            std::process::exit(0);
-        });
-
-    if let Err(err) = json {
-        return Err(format!("{}", err));
-    }
-
-    Ok(json.unwrap().choices[0].message.content.to_owned())
-}
-
-pub async fn init(git: &Git, files: Vec<String>) -> Result<String, String> {
-    let config = utils::get_config();
-
-    let mut messages: Vec<OpenApiMessage> = Vec::new();
-    messages.push(OpenApiMessage {
-        role: "system".to_owned(),
-        content: build_readme_query(git, files),
-    });
-
-    let body = OpenApiRequestBody {
-        model: config.get_model_name(),
-        messages,
-        temperature: 0.9,
-        max_tokens: 1500,
-    };
-
-    let mut headers = HeaderMap::new();
-
-    let config = utils::get_config();
-
-    headers.insert(
-        AUTHORIZATION,
-        format!("Bearer {}", config.get_api_key()).parse().unwrap(),
-    );
-
-    let port = config.get_port(PORT);
-
-    let result = post_api_call(
-        format!("{API_URL}:{port}/v1/chat/completions").as_str(),
-        serde_json::to_string(&body).unwrap().as_str(),
-        Some(headers),
-    )
-    .await;
-
-    if let Err(err) = result {
-        println!("Error: {}", err.red());
-        std::process::exit(0);
-    }
-
-    let json =
-        serde_json::from_str::<OpenApiResponseBody>(&result.clone().unwrap()).or_else(|_| {
-            let json = serde_json::from_str::<ErrorResponse>(&result.unwrap());
-            if let Err(err) = json {
-                return Err(format!("{}", err));
-            }
-            let error = json.unwrap().error;
-            let message = error.message.unwrap_or("".to_owned());
-            let code = error.code.unwrap_or("".to_owned());
-            println!("Error: {} {}", message.red(), code.red());
-            std::process::exit(0);
-        });
-
-    if let Err(err) = json {
-        return Err(format!("{}", err));
-    }
-
-    Ok(json.unwrap().choices[0].message.content.to_owned())
-}
+use colored::Colorize;
+use futures_util::{stream, StreamExt};
+use git2::{ObjectType, Oid};
+use lazy_static::lazy_static;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::borrow::ToOwned;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use tokio::sync::Notify;
+
+use crate::budget::{diff_tokens, estimate_tokens, is_lockfile, pack_diffs, RESERVED_TOKENS};
+use crate::convention::{parse_header, Convention};
+use crate::exit::{ExitCode, Failure};
+use crate::git::{DiffSource, FileDiff, Git};
+use crate::providers::{self, ChatRequest, Message, Provider, ResponseFormat, StreamEvent, Usage};
+use crate::query_params::{
+    build_initial_message, build_pr_message, build_pr_query, build_query, build_readme_query,
+    build_split_message, build_split_query, build_summarized_query, build_summary_message,
+};
+use crate::utils;
+
+pub(crate) const DEFAULT_BASE_URL: &str = "http://localhost:1234";
+pub(crate) const MODEL_NAME: &str = "deepseek-coder-v2-lite-instruct";
+
+const COMMIT_MAX_TOKENS: i32 = 250;
+const README_MAX_TOKENS: i32 = 1500;
+const SUMMARY_MAX_TOKENS: i32 = 120;
+const SUMMARY_CONCURRENCY: usize = 4;
+/// Parallel requests for candidates, when the server can't return several at once.
+const CANDIDATE_CONCURRENCY: usize = 4;
+const SPLIT_MAX_TOKENS: i32 = 1000;
+const PR_MAX_TOKENS: i32 = 1000;
+/// The Conventional Commits type of messages the model gave none.
+const FALLBACK_TYPE: &str = "chore";
+
+const REGENERATE_MESSAGE: &str =
+    "Write a different commit message for the same changes, following the same instructions.";
+
+lazy_static! {
+    static ref INTERRUPTED: Notify = Notify::new();
+    static ref USAGE: Mutex<Usage> = Mutex::new(Usage::default());
+}
+
+static GENERATING: AtomicBool = AtomicBool::new(false);
+/// Set once the server refused a response format, so later requests go without.
+static RESPONSE_FORMAT_REJECTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPT_HANDLER: Once = Once::new();
+
+/// Installs a single Ctrl-C listener for the whole process. While a response is
+/// streaming, Ctrl-C only stops the generation, otherwise the process exits.
+fn watch_interrupts() {
+    INTERRUPT_HANDLER.call_once(|| {
+        tokio::spawn(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if GENERATING.load(Ordering::SeqCst) {
+                    INTERRUPTED.notify_waiters();
+                } else {
+                    std::process::exit(130);
+                }
+            }
+        });
+    });
+}
+
+fn build_headers(additional_headers: Option<HeaderMap>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let Some(additional_headers) = additional_headers {
+        headers.extend(additional_headers);
+    }
+    headers
+}
+
+fn unreachable(message: impl Into<String>) -> Failure {
+    Failure::new(ExitCode::ModelUnreachable, message)
+}
+
+async fn post_api_call(
+    provider: &dyn Provider,
+    url: &str,
+    body: &str,
+    additional_headers: Option<HeaderMap>,
+) -> Result<String, Failure> {
+    let client = Client::new();
+    let res = client
+        .post(url)
+        .headers(build_headers(additional_headers))
+        .body(body.to_owned())
+        .send()
+        .await
+        .map_err(|err| unreachable(err.to_string()))?;
+
+    let status = res.status();
+    let body = res
+        .text()
+        .await
+        .map_err(|err| unreachable(err.to_string()))?;
+    if !status.is_success() {
+        return Err(unreachable(
+            provider
+                .parse_error(&body)
+                .unwrap_or(format!("{} {}", status, body)),
+        ));
+    }
+    Ok(body)
+}
+
+/// Posts a streaming request and reads the response line by line as it
+/// arrives. Every content delta is handed to `on_token`. Pressing Ctrl-C stops
+/// the generation and returns what has been produced so far.
+async fn post_api_stream(
+    provider: &dyn Provider,
+    url: &str,
+    body: &str,
+    additional_headers: Option<HeaderMap>,
+    on_token: &mut dyn FnMut(&str),
+) -> Result<String, Failure> {
+    watch_interrupts();
+
+    let client = Client::new();
+    let res = client
+        .post(url)
+        .headers(build_headers(additional_headers))
+        .body(body.to_owned())
+        .send()
+        .await
+        .map_err(|err| unreachable(err.to_string()))?;
+
+    if !res.status().is_success() {
+        let status = res.status();
+        let body = res.text().await.unwrap_or_default();
+        return Err(unreachable(
+            provider
+                .parse_error(&body)
+                .unwrap_or(format!("{} {}", status, body)),
+        ));
+    }
+
+    let mut stream = res.bytes_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut content = String::new();
+    let mut interrupted = false;
+
+    let interrupt = INTERRUPTED.notified();
+    tokio::pin!(interrupt);
+    GENERATING.store(true, Ordering::SeqCst);
+
+    'stream: loop {
+        let chunk = tokio::select! {
+            _ = &mut interrupt => {
+                interrupted = true;
+                break;
+            }
+            chunk = stream.next() => chunk,
+        };
+
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(err)) => {
+                GENERATING.store(false, Ordering::SeqCst);
+                return Err(unreachable(err.to_string()));
+            }
+            None => break,
+        };
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+            let line = buffer.drain(..=pos).collect::<Vec<u8>>();
+            match provider.parse_stream_line(&String::from_utf8_lossy(&line)) {
+                StreamEvent::Token(token) => {
+                    on_token(&token);
+                    content.push_str(&token);
+                }
+                StreamEvent::Done => break 'stream,
+                StreamEvent::Error(error) => {
+                    GENERATING.store(false, Ordering::SeqCst);
+                    return Err(unreachable(error));
+                }
+                StreamEvent::Skip => {}
+            }
+        }
+    }
+
+    GENERATING.store(false, Ordering::SeqCst);
+
+    if interrupted {
+        if content.is_empty() {
+            return Err(Failure::new(ExitCode::Aborted, "Generation aborted"));
+        }
+        println!("\n{}", "Generation stopped".yellow());
+    }
+
+    Ok(content)
+}
+
+async fn complete(
+    messages: Vec<Message>,
+    max_tokens: i32,
+    response_format: Option<&ResponseFormat>,
+    on_token: Option<&mut dyn FnMut(&str)>,
+) -> Result<String, Failure> {
+    let mut replies = request(&messages, max_tokens, response_format, 1, on_token).await?;
+
+    Ok(replies.swap_remove(0))
+}
+
+/// Asks for `n` replies, in a single request where the server supports `n`
+/// and with parallel requests for the ones still missing.
+async fn complete_many(
+    messages: Vec<Message>,
+    max_tokens: i32,
+    response_format: Option<&ResponseFormat>,
+    n: usize,
+) -> Result<Vec<String>, Failure> {
+    let profile = utils::get_config().active_profile();
+    let mut replies = if providers::from_profile(&profile).supports_n() {
+        request(&messages, max_tokens, response_format, n, None).await?
+    } else {
+        Vec::new()
+    };
+
+    let missing = n.saturating_sub(replies.len());
+    let more = stream::iter(
+        (0..missing).map(|_| request(&messages, max_tokens, response_format, 1, None)),
+    )
+    .buffered(CANDIDATE_CONCURRENCY)
+    .collect::<Vec<Result<Vec<String>, Failure>>>()
+    .await;
+    for result in more {
+        replies.extend(result?);
+    }
+    replies.truncate(n);
+
+    Ok(replies)
+}
+
+/// Whether the server refused the request because of its response format, as
+/// OpenAI compatible servers without structured output do.
+fn rejects_response_format(failure: &Failure) -> bool {
+    let message = failure.message.to_lowercase();
+    failure.code == ExitCode::ModelUnreachable
+        && (message.contains("response_format") || message.contains("json_schema"))
+}
+
+/// Sends a request, once more without the response format if the server
+/// refuses it. The prompt still asks for JSON, so the reply parses the same.
+async fn request(
+    messages: &[Message],
+    max_tokens: i32,
+    response_format: Option<&ResponseFormat>,
+    n: usize,
+    mut on_token: Option<&mut dyn FnMut(&str)>,
+) -> Result<Vec<String>, Failure> {
+    let response_format =
+        response_format.filter(|_| !RESPONSE_FORMAT_REJECTED.load(Ordering::SeqCst));
+    let result = send(
+        messages,
+        max_tokens,
+        response_format,
+        n,
+        on_token
+            .as_mut()
+            .map(|on_token| &mut **on_token as &mut dyn FnMut(&str)),
+    )
+    .await;
+    match result {
+        Err(failure) if response_format.is_some() && rejects_response_format(&failure) => {
+            RESPONSE_FORMAT_REJECTED.store(true, Ordering::SeqCst);
+            utils::warn_once(format!(
+                "The server doesn't support the response format, asking without it: {}",
+                failure
+            ));
+            send(messages, max_tokens, None, n, on_token).await
+        }
+        result => result,
+    }
+}
+
+async fn send(
+    messages: &[Message],
+    max_tokens: i32,
+    response_format: Option<&ResponseFormat>,
+    n: usize,
+    on_token: Option<&mut dyn FnMut(&str)>,
+) -> Result<Vec<String>, Failure> {
+    let profile = utils::get_config().active_profile();
+    let provider = providers::from_profile(&profile);
+
+    let request = ChatRequest {
+        model: profile.get_model(),
+        messages,
+        temperature: 0.9,
+        max_tokens,
+        stream: on_token.is_some(),
+        response_format,
+        n,
+    };
+
+    let mut headers = provider.headers();
+    headers.extend(providers::auth_headers(
+        profile.auth.as_deref().unwrap_or(provider.default_auth()),
+        &profile.get_api_key(),
+    ));
+    for (name, value) in profile.get_headers() {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => return Err(format!("Invalid header in profile: {}", name).into()),
+        }
+    }
+
+    let url = profile.get_url(provider.chat_path());
+    let body = provider.build_body(&request).to_string();
+
+    if let Some(on_token) = on_token {
+        return post_api_stream(
+            provider.as_ref(),
+            url.as_str(),
+            body.as_str(),
+            Some(headers),
+            on_token,
+        )
+        .await
+        .map(|reply| vec![reply]);
+    }
+
+    let result = post_api_call(
+        provider.as_ref(),
+        url.as_str(),
+        body.as_str(),
+        Some(headers),
+    )
+    .await?;
+
+    if let Some(usage) = provider.parse_usage(&result) {
+        USAGE.lock().unwrap().add(usage);
+    }
+    provider
+        .parse_response(&result)
+        .map_err(|err| Failure::new(ExitCode::UnusableOutput, err))
+}
+
+/// Tokens used by all complete requests so far, streamed ones don't report any.
+pub fn usage() -> Usage {
+    *USAGE.lock().unwrap()
+}
+
+/// The commit message as the model is asked to reply with it.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CommitMessage {
+    pub gitmoji: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub scope: String,
+    pub subject: String,
+    pub body: String,
+    pub breaking: bool,
+    pub breaking_change: String,
+}
+
+fn commit_message_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "gitmoji": { "type": "string" },
+            "type": { "type": "string" },
+            "scope": { "type": "string" },
+            "subject": { "type": "string" },
+            "body": { "type": "string" },
+            "breaking": { "type": "boolean" },
+            "breaking_change": { "type": "string" },
+        },
+        "required": ["gitmoji", "type", "scope", "subject", "body", "breaking", "breaking_change"],
+        "additionalProperties": false,
+    })
+}
+
+/// Cuts the JSON object out of a reply, models like to wrap it in prose or
+/// code blocks.
+pub(crate) fn extract_json(text: &str) -> Option<&str> {
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    (start < end).then(|| &text[start..=end])
+}
+
+impl CommitMessage {
+    /// Parses the reply of the model. Replies that aren't the requested JSON
+    /// are read as plain text, the first line being the subject. JSON that was
+    /// cut off, like when the generation is stopped, keeps what is complete.
+    pub fn parse(reply: &str) -> CommitMessage {
+        if let Some(message) = extract_json(reply)
+            .and_then(|json| serde_json::from_str::<CommitMessage>(json).ok())
+            .filter(|message| !message.subject.trim().is_empty())
+        {
+            return message;
+        }
+
+        let structured = reply
+            .trim_start()
+            .trim_start_matches('`')
+            .trim_start_matches("json")
+            .trim_start()
+            .starts_with('{');
+        if structured || reply.contains("\"subject\"") {
+            return CommitMessage::recover(reply);
+        }
+
+        let reply = reply.trim();
+        let (subject, body) = reply.split_once('\n').unwrap_or((reply, ""));
+        CommitMessage {
+            subject: subject.to_owned(),
+            body: body.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads the fields of a truncated JSON reply. Without a complete subject
+    /// the message stays empty and is treated as unusable.
+    fn recover(json: &str) -> CommitMessage {
+        let complete = |field: &str| match utils::partial_json_string(json, field) {
+            Some((value, true)) => value,
+            _ => String::new(),
+        };
+        let subject = complete("subject");
+        if subject.trim().is_empty() {
+            return CommitMessage::default();
+        }
+
+        let breaking = json
+            .find("\"breaking\"")
+            .and_then(|start| json[start + 10..].trim_start().strip_prefix(':'))
+            .is_some_and(|value| value.trim_start().starts_with("true"));
+        CommitMessage {
+            gitmoji: complete("gitmoji"),
+            kind: complete("type"),
+            scope: complete("scope"),
+            subject,
+            body: utils::partial_json_string(json, "body")
+                .map(|(body, _)| body)
+                .unwrap_or_default(),
+            breaking,
+            breaking_change: complete("breaking_change"),
+        }
+    }
+
+    /// Puts the parts together in the format of the convention.
+    pub fn render(&self, convention: Convention) -> String {
+        let gitmoji = self.gitmoji.trim();
+        let subject = self.subject.trim();
+        let mut header = String::new();
+        // a subject that already is a header keeps its type
+        if convention.is_conventional()
+            && (!self.kind.trim().is_empty() || parse_header(subject).is_none())
+        {
+            let kind = self.kind.trim();
+            header.push_str(if kind.is_empty() { FALLBACK_TYPE } else { kind });
+            if !self.scope.trim().is_empty() {
+                header.push_str(&format!("({})", self.scope.trim()));
+            }
+            if self.breaking {
+                header.push('!');
+            }
+            header.push_str(": ");
+        }
+        let uses_gitmoji = matches!(
+            convention,
+            Convention::Gitmoji | Convention::ConventionalGitmoji
+        );
+        if uses_gitmoji && !gitmoji.is_empty() && !subject.starts_with(gitmoji) {
+            header.push_str(&format!("{} ", gitmoji));
+        }
+
+        let mut message = format!("{}{}", header, subject);
+        if !self.body.trim().is_empty() {
+            message.push_str(&format!("\n\n{}", self.body.trim()));
+        }
+        if convention.is_conventional() && self.breaking && !self.breaking_change.trim().is_empty()
+        {
+            message.push_str(&format!(
+                "\n\nBREAKING CHANGE: {}",
+                self.breaking_change.trim()
+            ));
+        }
+
+        convention.format_rendered(&message)
+    }
+}
+
+pub struct QueryOptions {
+    pub files: Vec<String>,
+    pub source: DiffSource,
+    pub summarize: bool,
+    /// Number of messages to generate, more than one can't be streamed.
+    pub candidates: usize,
+}
+
+fn summary_cache_path(key: Oid) -> PathBuf {
+    utils::cache_dir().join("summaries").join(key.to_string())
+}
+
+async fn summarize_file(file: &FileDiff, model: &str, budget: usize) -> Result<String, Failure> {
+    if file.binary {
+        return Ok("Binary file changed".to_owned());
+    }
+    if is_lockfile(&file.path) {
+        return Ok("Lockfile updated".to_owned());
+    }
+
+    let patch = pack_diffs(std::slice::from_ref(file), budget).diff;
+    let key = Oid::hash_object(ObjectType::Blob, format!("{}\n{}", model, patch).as_bytes())
+        .map_err(|err| err.to_string())?;
+    let cache_path = summary_cache_path(key);
+    if let Ok(summary) = fs::read_to_string(&cache_path) {
+        return Ok(summary);
+    }
+
+    let messages = vec![
+        Message::new("system", build_summary_message()),
+        Message::new("user", patch),
+    ];
+    let summary = complete(messages, SUMMARY_MAX_TOKENS, None, None).await?;
+
+    if let Some(dir) = cache_path.parent() {
+        if fs::create_dir_all(dir).is_ok() {
+            fs::write(&cache_path, &summary).ok();
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Asks the model for a short summary of every file, a few files at a time.
+/// Summaries are cached by the hash of the model name and the diff of the file.
+async fn summarize_files(
+    diffs: &[FileDiff],
+    model: &str,
+    budget: usize,
+) -> Result<Vec<String>, Failure> {
+    stream::iter(diffs.iter().map(|file| summarize_file(file, model, budget)))
+        .buffered(SUMMARY_CONCURRENCY)
+        .collect::<Vec<Result<String, Failure>>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Parses the replies, dropping the ones without a subject and repeated ones.
+fn usable_messages(replies: &[String]) -> Vec<CommitMessage> {
+    let mut messages: Vec<CommitMessage> = Vec::new();
+    for reply in replies {
+        let message = CommitMessage::parse(reply);
+        if !message.subject.trim().is_empty() && !messages.contains(&message) {
+            messages.push(message);
+        }
+    }
+    messages
+}
+
+/// A commit message request together with the answers and the feedback so
+/// far, so that every retry builds on the previous answer.
+pub struct Conversation {
+    messages: Vec<Message>,
+    response_format: Option<ResponseFormat>,
+    candidates: usize,
+    files: Vec<String>,
+}
+
+impl Conversation {
+    /// Builds the commit message prompt. Change sets larger than the context
+    /// window, or every change set with `summarize`, are first summarized per file.
+    pub async fn start(git: &Git, options: &QueryOptions) -> Result<Conversation, Failure> {
+        let profile = utils::get_config().active_profile();
+        let response_format = match profile.get_response_format().as_str() {
+            "text" => None,
+            "json" => Some(ResponseFormat::Json),
+            _ => Some(ResponseFormat::Schema(
+                "commit_message",
+                commit_message_schema(),
+            )),
+        };
+        let system = build_initial_message(response_format.is_some());
+        let budget = profile.get_context_tokens().saturating_sub(
+            estimate_tokens(&system) + COMMIT_MAX_TOKENS as usize + RESERVED_TOKENS,
+        );
+
+        let diffs = git
+            .get_file_diffs(Some(options.files.clone()), options.source)
+            .map_err(|err| Failure::new(ExitCode::GitFailed, err.message()))?;
+        if diffs.is_empty() {
+            return Err(Failure::new(ExitCode::NothingToCommit, "Nothing to commit"));
+        }
+
+        let user = if options.summarize || diff_tokens(&diffs) > budget {
+            let summary_budget = profile.get_context_tokens().saturating_sub(
+                estimate_tokens(&build_summary_message())
+                    + SUMMARY_MAX_TOKENS as usize
+                    + RESERVED_TOKENS,
+            );
+            let summaries = summarize_files(&diffs, &profile.get_model(), summary_budget).await?;
+            build_summarized_query(git, options.source, &diffs, &summaries)
+        } else {
+            build_query(git, options.source, &diffs, budget)
+        };
+
+        Ok(Conversation {
+            messages: vec![Message::new("system", system), Message::new("user", user)],
+            response_format,
+            candidates: options.candidates,
+            files: diffs.iter().map(|file| file.path.to_owned()).collect(),
+        })
+    }
+
+    pub fn candidates(&self) -> usize {
+        self.candidates
+    }
+
+    /// Paths of the files the message is written for.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Asks for the next message, or for several candidates, which can't be streamed.
+    pub async fn ask(
+        &self,
+        on_token: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<Vec<CommitMessage>, Failure> {
+        let replies = if self.candidates > 1 {
+            complete_many(
+                self.messages.clone(),
+                COMMIT_MAX_TOKENS,
+                self.response_format.as_ref(),
+                self.candidates,
+            )
+            .await?
+        } else {
+            vec![
+                complete(
+                    self.messages.clone(),
+                    COMMIT_MAX_TOKENS,
+                    self.response_format.as_ref(),
+                    on_token,
+                )
+                .await?,
+            ]
+        };
+
+        let candidates = usable_messages(&replies);
+        if candidates.is_empty() {
+            return Err(Failure::new(
+                ExitCode::UnusableOutput,
+                "The model didn't reply with a commit message",
+            ));
+        }
+        Ok(candidates)
+    }
+
+    /// Adds the `previous` message and what to do with it to the conversation.
+    fn follow_up(&mut self, previous: &str, feedback: Option<&str>) {
+        self.messages
+            .push(Message::new("assistant", previous.to_owned()));
+        self.messages.push(Message::new(
+            "user",
+            feedback
+                .map(|feedback| format!("Rewrite the commit message: {}", feedback))
+                .unwrap_or(REGENERATE_MESSAGE.to_owned()),
+        ));
+    }
+
+    /// Asks again after the `previous` message, with the user's `feedback` or
+    /// just for a different message. Failed retries aren't kept.
+    pub async fn retry(
+        &mut self,
+        previous: &str,
+        feedback: Option<&str>,
+        on_token: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<Vec<CommitMessage>, Failure> {
+        let length = self.messages.len();
+        self.follow_up(previous, feedback);
+
+        let result = self.ask(on_token).await;
+        if result.is_err() {
+            self.messages.truncate(length);
+        }
+        result
+    }
+}
+
+/// Asks the model how to split the changes into several commits. The reply is
+/// the raw JSON plan, see `Plan::parse`.
+pub async fn split(git: &Git, diffs: &[FileDiff]) -> Result<String, Failure> {
+    let profile = utils::get_config().active_profile();
+    let system = build_split_message();
+    let budget = profile
+        .get_context_tokens()
+        .saturating_sub(estimate_tokens(&system) + SPLIT_MAX_TOKENS as usize + RESERVED_TOKENS);
+
+    let messages = vec![
+        Message::new("system", system),
+        Message::new("user", build_split_query(git, diffs, budget)),
+    ];
+
+    let response_format = (profile.get_response_format() != "text").then_some(ResponseFormat::Json);
+
+    complete(messages, SPLIT_MAX_TOKENS, response_format.as_ref(), None).await
+}
+
+/// Writes the title and description of a pull request for the commits after
+/// `base`, given their messages.
+pub async fn pull_request(git: &Git, base: Oid, messages: &[String]) -> Result<String, Failure> {
+    let diffs = git
+        .get_file_diffs(None, DiffSource::Since(base))
+        .map_err(|err| Failure::new(ExitCode::GitFailed, err.message()))?;
+
+    let profile = utils::get_config().active_profile();
+    let system = build_pr_message();
+    let budget = profile
+        .get_context_tokens()
+        .saturating_sub(estimate_tokens(&system) + PR_MAX_TOKENS as usize + RESERVED_TOKENS);
+
+    let messages = vec![
+        Message::new("system", system),
+        Message::new("user", build_pr_query(git, base, messages, &diffs, budget)),
+    ];
+
+    let reply = complete(messages, PR_MAX_TOKENS, None, None).await?;
+    let reply = reply.trim();
+    if reply.is_empty() {
+        return Err(Failure::new(
+            ExitCode::UnusableOutput,
+            "The model returned an empty description",
+        ));
+    }
+
+    Ok(format!("{}\n", reply))
+}
+
+pub async fn init(
+    git: &Git,
+    files: Vec<String>,
+    on_token: Option<&mut dyn FnMut(&str)>,
+) -> Result<String, Failure> {
+    let messages = vec![Message::new("system", build_readme_query(git, files))];
+
+    complete(messages, README_MAX_TOKENS, None, on_token).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    /// Answers one request with `status` and then `chunks`, each written on
+    /// its own so the client receives them separately.
+    fn serve(status: &'static str, chunks: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/v1/chat/completions",
+            listener.local_addr().unwrap()
+        );
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            std::io::Read::read_exact(&mut reader, &mut vec![0; length]).unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            for chunk in chunks {
+                stream.write_all(&chunk).unwrap();
+                stream.flush().unwrap();
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+        url
+    }
+
+    fn token_line(token: &str) -> String {
+        format!(
+            "data: {}\n\n",
+            json!({ "choices": [{ "delta": { "content": token } }] })
+        )
+    }
+
+    async fn stream(url: &str) -> (Result<String, Failure>, Vec<String>) {
+        let mut tokens = Vec::new();
+        let result = post_api_stream(&providers::OpenAi, url, "{}", None, &mut |token| {
+            tokens.push(token.to_owned())
+        })
+        .await;
+        (result, tokens)
+    }
+
+    #[tokio::test]
+    async fn streams_tokens_split_across_chunks() {
+        let body = format!(
+            "{}{}: keep-alive\n\n{}data: [DONE]\n\n{}",
+            token_line("Fix "),
+            token_line("the pört"),
+            token_line(" ✨"),
+            token_line("ignored after done")
+        )
+        .into_bytes();
+        // chunks end in the middle of lines and of the ö and the ✨
+        let inside = |text: &str| {
+            body.windows(text.len())
+                .position(|w| w == text.as_bytes())
+                .unwrap()
+                + 1
+        };
+        let cuts = [7, inside("ö"), inside("✨"), body.len() - 30];
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for cut in cuts.into_iter().chain([body.len()]) {
+            chunks.push(body[start..cut].to_vec());
+            start = cut;
+        }
+
+        let (result, tokens) = stream(&serve("200 OK", chunks)).await;
+        assert_eq!(result.unwrap(), "Fix the pört ✨");
+        assert_eq!(tokens, vec!["Fix ", "the pört", " ✨"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_an_error_frame() {
+        let body = format!(
+            "{}data: {}\n\n",
+            token_line("Fix"),
+            json!({ "error": { "message": "Context length exceeded" } })
+        );
+
+        let (result, tokens) = stream(&serve("200 OK", vec![body.into_bytes()])).await;
+        let failure = result.unwrap_err();
+        assert_eq!(failure.code, ExitCode::ModelUnreachable);
+        assert_eq!(failure.message, "Context length exceeded");
+        assert_eq!(tokens, vec!["Fix"]);
+    }
+
+    #[tokio::test]
+    async fn reports_the_error_of_a_refused_stream() {
+        let body = json!({ "error": { "message": "Model not loaded" } }).to_string();
+
+        let (result, tokens) = stream(&serve("404 Not Found", vec![body.into_bytes()])).await;
+        let failure = result.unwrap_err();
+        assert_eq!(failure.code, ExitCode::ModelUnreachable);
+        assert_eq!(failure.message, "Model not loaded");
+        assert!(tokens.is_empty());
+    }
+
+    #[tokio::test]
+    async fn binary_files_and_lockfiles_are_summarized_without_a_request() {
+        let file = |path: &str, binary: bool| FileDiff {
+            path: path.to_owned(),
+            status: 'M',
+            additions: 1,
+            deletions: 1,
+            binary,
+            hunks: vec!["@@ -1 +1 @@\n-a\n+b\n".to_owned()],
+        };
+        let summaries = summarize_files(
+            &[file("logo.png", true), file("Cargo.lock", false)],
+            "model",
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summaries, vec!["Binary file changed", "Lockfile updated"]);
+    }
+
+    #[test]
+    fn parse_reads_the_json_reply() {
+        let message = CommitMessage::parse(
+            r#"```json
+{"gitmoji": ":bug:", "type": "fix", "scope": "api", "subject": "Fix the port", "body": "It was off by one.", "breaking": false, "breaking_change": ""}
+```"#,
+        );
+        assert_eq!(message.kind, "fix");
+        assert_eq!(message.subject, "Fix the port");
+        assert_eq!(message.body, "It was off by one.");
+    }
+
+    #[test]
+    fn parse_recovers_truncated_json() {
+        let message = CommitMessage::parse(
+            r#"{"gitmoji":"✨","type":"feat","scope":"","subject":"Add streaming","body":"Tokens are printed as they\narr"#,
+        );
+        assert_eq!(message.gitmoji, "✨");
+        assert_eq!(message.kind, "feat");
+        assert_eq!(message.subject, "Add streaming");
+        assert_eq!(message.body, "Tokens are printed as they\narr");
+        assert!(!message.breaking);
+        assert_eq!(
+            message.render(Convention::Conventional),
+            "feat: Add streaming\n\nTokens are printed as they\narr"
+        );
+    }
+
+    #[test]
+    fn parse_drops_json_truncated_in_the_subject() {
+        let message = CommitMessage::parse(r#"{"gitmoji":"✨","type":"feat","subject":"Add str"#);
+        assert!(message.subject.is_empty());
+        assert!(CommitMessage::parse(r#"{"gitmoji":"✨","ty"#)
+            .subject
+            .is_empty());
+    }
+
+    fn message(gitmoji: &str, kind: &str, subject: &str) -> CommitMessage {
+        CommitMessage {
+            gitmoji: gitmoji.to_owned(),
+            kind: kind.to_owned(),
+            scope: String::new(),
+            subject: subject.to_owned(),
+            body: "It was off by one.".to_owned(),
+            breaking: false,
+            breaking_change: String::new(),
+        }
+    }
+
+    #[test]
+    fn render_follows_the_convention() {
+        let fix = message(":bug:", "fix", "Fix the port");
+        let expected = [
+            (Convention::Gitmoji, ":bug: Fix the port"),
+            (Convention::Conventional, "fix: Fix the port"),
+            (Convention::ConventionalGitmoji, "fix: :bug: Fix the port"),
+            (Convention::Plain, "Fix the port"),
+        ];
+        for (convention, subject) in expected {
+            assert_eq!(
+                fix.render(convention),
+                format!("{}\n\nIt was off by one.", subject)
+            );
+        }
+    }
+
+    #[test]
+    fn render_adds_scope_and_breaking_change() {
+        let message = CommitMessage {
+            scope: "api".to_owned(),
+            breaking: true,
+            breaking_change: "The port moved.".to_owned(),
+            ..message(":boom:", "feat", "move the port")
+        };
+        assert_eq!(
+            message.render(Convention::Conventional),
+            "feat(api)!: move the port\n\nIt was off by one.\n\nBREAKING CHANGE: The port moved."
+        );
+        assert_eq!(
+            message.render(Convention::Gitmoji),
+            ":boom: Move the port\n\nIt was off by one."
+        );
+    }
+
+    #[test]
+    fn render_falls_back_to_a_type() {
+        let untyped = message(":bug:", "", "fix the port");
+        assert_eq!(
+            untyped.render(Convention::Conventional),
+            "chore: fix the port\n\nIt was off by one."
+        );
+        assert_eq!(
+            untyped.render(Convention::ConventionalGitmoji),
+            "chore: :bug: fix the port\n\nIt was off by one."
+        );
+
+        let typed_subject = message(":bug:", "", "fix(api): the port");
+        assert_eq!(
+            typed_subject.render(Convention::Conventional),
+            "fix(api): the port\n\nIt was off by one."
+        );
+        assert_eq!(
+            typed_subject.render(Convention::ConventionalGitmoji),
+            "fix(api): :bug: the port\n\nIt was off by one."
+        );
+    }
+
+    #[test]
+    fn render_keeps_label_like_lines() {
+        let message = message("", "docs", "Describe the config");
+        let message = CommitMessage {
+            body: "Title: is the key of the heading.".to_owned(),
+            ..message
+        };
+        assert_eq!(
+            message.render(Convention::Conventional),
+            "docs: Describe the config\n\nTitle: is the key of the heading."
+        );
+    }
+
+    #[test]
+    fn detects_rejected_response_formats() {
+        let rejected = |message: &str| {
+            rejects_response_format(&Failure::new(ExitCode::ModelUnreachable, message))
+        };
+        assert!(rejected(
+            "'response_format.type' must be 'json_schema' or 'text'"
+        ));
+        assert!(rejected("400 Bad Request json_schema is not supported"));
+        assert!(!rejected("Model not loaded model_not_found"));
+        assert!(!rejects_response_format(&Failure::new(
+            ExitCode::UnusableOutput,
+            "missing field response_format"
+        )));
+    }
+
+    #[test]
+    fn candidates_drop_unusable_and_repeated_replies() {
+        let replies = [
+            r#"{"gitmoji":":bug:","type":"fix","subject":"Fix the port"}"#,
+            "",
+            r#"{"gitmoji":"✨","ty"#,
+            r#"{"gitmoji":":bug:","type":"fix","subject":"Fix the port"}"#,
+            "Fix the port number\n\nIt was off by one.",
+        ]
+        .map(str::to_owned);
+        let subjects = usable_messages(&replies)
+            .into_iter()
+            .map(|message| message.subject)
+            .collect::<Vec<String>>();
+        assert_eq!(subjects, ["Fix the port", "Fix the port number"]);
+    }
+
+    #[test]
+    fn retries_build_on_the_previous_answers() {
+        let mut conversation = Conversation {
+            messages: vec![
+                Message::new("system", "Write commit messages.".to_owned()),
+                Message::new("user", "diff".to_owned()),
+            ],
+            response_format: None,
+            candidates: 1,
+            files: vec![],
+        };
+        conversation.follow_up("Fix the port", Some("mention the off by one"));
+        conversation.follow_up("Fix the off by one port", None);
+
+        let turns = conversation
+            .messages
+            .iter()
+            .map(|message| (message.role.as_str(), message.content.as_str()))
+            .collect::<Vec<(&str, &str)>>();
+        assert_eq!(
+            turns[2..],
+            [
+                ("assistant", "Fix the port"),
+                ("user", "Rewrite the commit message: mention the off by one"),
+                ("assistant", "Fix the off by one port"),
+                ("user", REGENERATE_MESSAGE),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_reads_plain_text() {
+        let message = CommitMessage::parse("Add streaming\n\nTokens are printed.");
+        assert_eq!(message.subject, "Add streaming");
+        assert_eq!(message.body, "\nTokens are printed.");
+    }
+}
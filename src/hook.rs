@@ -0,0 +1,185 @@
+use colored::Colorize;
+use std::{fs, path::PathBuf, time::Duration};
+
+use crate::{
+    git::{format_commit_message, DiffSource, Git},
+    gpt_api::{Conversation, QueryOptions},
+    utils::{self, get_executable_name},
+};
+
+const HOOK_NAME: &str = "prepare-commit-msg";
+const MARKER: &str = "# installed by gpt-commit-rust";
+
+/// The longest a commit waits for the model before it goes on without a message.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Message sources for which git already has a message to show.
+const MESSAGE_SOURCES: [&str; 4] = ["message", "merge", "squash", "commit"];
+
+fn script() -> Result<String, String> {
+    let executable = std::env::current_exe().map_err(|err| err.to_string())?;
+    Ok(format!(
+        "#!/bin/sh\n{}\nexec \"{}\" hook run \"$@\"\n",
+        MARKER,
+        executable.to_string_lossy().replace('\\', "/")
+    ))
+}
+
+/// Writes the `prepare-commit-msg` hook. Hooks that weren't installed by this
+/// tool are left alone.
+pub fn install(git: &Git) -> Result<PathBuf, String> {
+    let path = git.hook_path(HOOK_NAME);
+    if let Ok(existing) = fs::read_to_string(&path) {
+        if !existing.contains(MARKER) {
+            return Err(format!(
+                "{} already exists, call `{} hook run \"$@\"` from it instead",
+                path.display(),
+                get_executable_name()
+            ));
+        }
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    fs::write(&path, script()?).map_err(|err| err.to_string())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .map_err(|err| err.to_string())?;
+    }
+
+    Ok(path)
+}
+
+pub fn uninstall(git: &Git) -> Result<PathBuf, String> {
+    let path = git.hook_path(HOOK_NAME);
+    match fs::read_to_string(&path) {
+        Ok(existing) if existing.contains(MARKER) => {
+            fs::remove_file(&path).map_err(|err| err.to_string())?;
+            Ok(path)
+        }
+        Ok(_) => Err(format!(
+            "{} wasn't installed by {}",
+            path.display(),
+            get_executable_name()
+        )),
+        Err(_) => Err("No hook installed".to_owned()),
+    }
+}
+
+/// Entry point of the hook, called by git with the message file, the message
+/// source and a commit. Never fails the commit, if anything goes wrong the
+/// message file is left as it is.
+pub async fn run(git: &Git, file: &str, source: Option<&str>) {
+    if let Some(source) = source {
+        if MESSAGE_SOURCES.contains(&source) {
+            return;
+        }
+    }
+
+    let result = match tokio::time::timeout(HOOK_TIMEOUT, fill(git, file)).await {
+        Ok(result) => result,
+        Err(_) => Err("The model didn't answer in time".to_owned()),
+    };
+    if let Err(err) = result {
+        eprintln!("{} {}", "gpt-commit-rust:".yellow(), err);
+    }
+}
+
+async fn fill(git: &Git, file: &str) -> Result<(), String> {
+    // everything after this reads the config again, so it must be readable
+    let config = utils::try_get_config()?;
+    if config.active_profile().missing_api_key() {
+        return Err("No API key set".to_owned());
+    }
+    if !git
+        .has_staged_changes()
+        .map_err(|err| err.message().to_owned())?
+    {
+        return Ok(());
+    }
+
+    let options = QueryOptions {
+        files: vec![],
+        source: DiffSource::Staged,
+        summarize: false,
+        candidates: 1,
+    };
+    let conversation = Conversation::start(git, &options).await?;
+    let message = conversation
+        .ask(None)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or("No message generated".to_owned())?;
+    let message = format_commit_message(&message.render(config.get_convention()));
+
+    // keeps what git put into the file, like the status comments or a template
+    let existing = fs::read_to_string(file).unwrap_or_default();
+    fs::write(file, format!("{}\n{}", message, existing)).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::tests::TestRepo;
+
+    #[test]
+    fn installs_and_removes_its_own_hook() {
+        let test = TestRepo::new("hook-install");
+        let path = install(&test.git).unwrap();
+        assert_eq!(path, test.git.repo.path().join("hooks").join(HOOK_NAME));
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(&format!("#!/bin/sh\n{}\n", MARKER)));
+        assert!(content.ends_with(" hook run \"$@\"\n"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o755);
+        }
+
+        // installing again updates the own hook
+        install(&test.git).unwrap();
+        uninstall(&test.git).unwrap();
+        assert!(!path.exists());
+        assert!(uninstall(&test.git).is_err());
+    }
+
+    #[test]
+    fn leaves_other_hooks_alone() {
+        let test = TestRepo::new("hook-foreign");
+        let path = test.git.hook_path(HOOK_NAME);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "#!/bin/sh\nexit 0\n").unwrap();
+
+        assert!(install(&test.git).is_err());
+        assert!(uninstall(&test.git).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "#!/bin/sh\nexit 0\n");
+    }
+
+    #[test]
+    fn follows_core_hooks_path() {
+        let test = TestRepo::new("hook-path");
+        let mut config = test.git.repo.config().unwrap();
+        config.set_str("core.hooksPath", ".githooks").unwrap();
+
+        let path = install(&test.git).unwrap();
+        assert_eq!(path, test.dir.join(".githooks").join(HOOK_NAME));
+        assert!(path.is_file());
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_git_already_has() {
+        let test = TestRepo::new("hook-run");
+        let file = test.git.repo.path().join("COMMIT_EDITMSG");
+        fs::write(&file, "Written with -m\n").unwrap();
+
+        for source in MESSAGE_SOURCES {
+            run(&test.git, &file.to_string_lossy(), Some(source)).await;
+            assert_eq!(fs::read_to_string(&file).unwrap(), "Written with -m\n");
+        }
+    }
+}
@@ -1,145 +1,113 @@
+mod budget;
+mod cli;
 mod command_utils;
+mod convention;
+mod exit;
 mod git;
 mod gpt_api;
+mod hook;
 mod os_info;
+mod providers;
 mod query_params;
+mod split;
+mod update;
 mod utils;
 
 use std::{
+    collections::HashMap,
     env,
     fs::{self, File},
-    io::Write,
+    io::{IsTerminal, Write},
     path::Path,
     vec,
 };
 
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use colored::Colorize;
 
-use crate::gpt_api::PORT;
 use crate::{
-    command_utils::{parse_command, parse_commands},
-    git::{build_commands, Git},
+    cli::{
+        Cli, Command, CommitArgs, ConfigCommand, HookCommand, PrArgs, ReadmeArgs, RewordArgs,
+        Target,
+    },
+    command_utils::{parse_command, parse_commands, replace_gitmoji_with_emoji},
+    exit::{ExitCode, Failure},
+    git::{build_commands, format_commit_message, DiffSource, Git, REWORD_BACKUP_REF},
     gpt_api::init,
-    utils::{check_for_update, get_executable_name},
+    update::check_for_update,
+    utils::{get_executable_name, Config, Interaction, SETTING_KEYS},
 };
-use gpt_api::{query, MODEL_NAME};
-use inquire::{Select, Text};
+use git2::Oid;
+use gpt_api::{Conversation, QueryOptions};
+use inquire::{Confirm, InquireError, MultiSelect, Select, Text};
+use providers::Usage;
+use split::Plan;
 
 #[tokio::main]
 async fn main() {
-    dotenv::dotenv().ok();
+    utils::load_dotenv();
 
-    let args = std::env::args().collect::<Vec<String>>()[1..].to_vec();
-    let files = args
-        .iter()
-        .filter(|arg| !arg.starts_with("-"))
-        .map(|arg| arg.to_owned())
-        .collect::<Vec<String>>();
-
-    if args.contains(&"--help".to_owned()) || args.contains(&"-h".to_owned()) {
-        let usage_str = format!(
-            "{} [optional:option] [optional:files]",
-            get_executable_name()
-        );
-        println!(
-            "{} {}\n\n{}",
-            "Usage:",
-            parse_command(usage_str.as_str(), false),
-            "Options:".bright_blue()
-        );
-        println!("{} {}", "--help, -h:".magenta(), "Shows this help message");
-        println!(
-            "{} {}",
-            "--version, -v:".magenta(),
-            "Shows the current version of the program"
-        );
-        println!(
-            "{} {}",
-            "--update, -u:".magenta(),
-            "Updates the program to the latest version"
-        );
-        println!(
-            "{} {}",
-            "--force-update, -f:".magenta(),
-            "Forces the update to the latest version"
-        );
-        println!(
-            "{} {}",
-            "--init, -i [files]:".magenta(),
-            "Initializes a README.md file in the current directory based on the content of the given files"
-        );
-        println!(
-            "{} {}",
-            "--no-ai, -n:".magenta(),
-            format!("Commits the changes without using {MODEL_NAME}")
-        );
-        println!(
-            "{} {}",
-            "--push, -p:".magenta(),
-            "Pushes the changes to the remote repository after running the commands"
-        );
-        println!(
-            "{} {}",
-            "--api-key:".magenta(),
-            format!("Sets the API key to use for {MODEL_NAME}. You can also set the API key in the .env file")
-        );
-        println!(
-            "{} {}",
-            "--clear-api-key:".magenta(),
-            "Clears the API key from the config file"
-        );
-        println!(
-            "{} {}",
-            "--port:".magenta(),
-            format!("Sets the port to use for {MODEL_NAME} and lms server.")
-        );
-        println!(
-            "{} {}",
-            "--clear-port:".magenta(),
-            "Clears the port from the config file"
-        );
-        println!(
-            "{} {}",
-            "--model-name".magenta(),
-            "Sets the model name to use for the api"
-        );
-        println!(
-            "{} {}",
-            "--clear-model-name:".magenta(),
-            "Clears the model name from the config file"
-        );
-        return;
+    let cli = Cli::parse();
+    if cli.command.is_some() && cli.commit.is_used() {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "options and files of the commit command can't be combined with another command",
+            )
+            .exit();
     }
 
-    let update_ready = check_for_update().await;
+    if cli.yes {
+        utils::select_interaction(Interaction::Yes);
+    } else if cli.non_interactive {
+        utils::select_interaction(Interaction::Never);
+    }
 
-    if args.contains(&"--update".to_owned()) || args.contains(&"-u".to_owned()) {
-        if !update_ready {
-            println!("{}", "No update available".yellow());
-            return;
-        }
-        let result = utils::download_update().await;
-        match result {
-            Ok(_) => println!("{}", "Updated successfully".bright_green()),
-            Err(err) => println!("{} {}", "Error:".red(), err),
+    if let Some(Command::Hook(command)) = &cli.command {
+        let git = open_repository();
+        match command {
+            HookCommand::Install => match hook::install(&git) {
+                Ok(path) => println!("{} {}", "Hook installed:".green(), path.display()),
+                Err(err) => fail(err.into()),
+            },
+            HookCommand::Uninstall => match hook::uninstall(&git) {
+                Ok(path) => println!("{} {}", "Hook removed:".green(), path.display()),
+                Err(err) => fail(err.into()),
+            },
+            HookCommand::Run { file, source, .. } => hook::run(&git, file, source.as_deref()).await,
         }
         return;
     }
 
-    if args.contains(&"--force-update".to_owned()) || args.contains(&"-f".to_owned()) {
-        let result = utils::download_update().await;
-        match result {
-            Ok(_) => println!("{}", "Updated successfully".bright_green()),
-            Err(err) => println!("{} {}", "Error:".red(), err),
+    if let Some(Command::Update {
+        force,
+        to,
+        list,
+        rollback,
+    }) = cli.command
+    {
+        if let Err(err) = update_program(force, to, list, rollback).await {
+            fail(err.into());
         }
         return;
     }
 
-    if update_ready {
-        let update_message = parse_command(
-            format!("{} --update", get_executable_name()).as_str(),
-            false,
-        );
+    let scripted = match &cli.command {
+        None => cli.commit.is_scripted(),
+        Some(Command::Commit(args)) => args.is_scripted(),
+        _ => false,
+    };
+    if scripted {
+        colored::control::set_override(false);
+    }
+
+    let checks_update = matches!(
+        cli.command,
+        None | Some(Command::Commit(_) | Command::Readme(_) | Command::Reword(_))
+    );
+    if checks_update && !scripted && check_for_update() {
+        let update_message =
+            parse_command(format!("{} update", get_executable_name()).as_str(), false);
         println!(
             "{} Run `{}` to update\n",
             "Update ready".bright_green(),
@@ -147,271 +115,1141 @@ async fn main() {
         );
     }
 
-    if args.contains(&"--version".to_owned()) || args.contains(&"-v".to_owned()) {
-        println!(
-            "{} {}",
-            "Version:".bright_magenta(),
-            env!("CARGO_PKG_VERSION")
+    let config = utils::get_config();
+
+    if let Some(name) = &cli.profile {
+        let creates_profile = matches!(
+            cli.command,
+            Some(Command::Config(ConfigCommand::Set { .. }))
         );
-        return;
+        if !config.has_profile(name) && !creates_profile {
+            fail(format!("Unknown profile \"{}\"", name).into());
+        }
+        utils::select_profile(name);
     }
 
-    let config = &mut utils::get_config();
+    match cli.command {
+        Some(Command::Config(command)) => configure(command),
+        Some(Command::Readme(args)) => readme(args).await,
+        Some(Command::Reword(args)) => reword(args).await,
+        Some(Command::Pr(args)) => pull_request(args).await,
+        Some(Command::Commit(args)) => commit_changes(args).await,
+        None => commit_changes(cli.commit).await,
+        Some(Command::Update { .. } | Command::Hook(_)) => unreachable!(),
+    }
+}
 
-    if args.contains(&"--api-key".to_owned()) {
-        let pos = args.iter().position(|s| s == "--api-key").unwrap();
-        if pos + 1 >= args.len() {
-            let api_key = config.get_api_key();
-            if api_key.is_empty() {
-                println!("{}", "No API key set".yellow());
-            } else {
-                println!("{}: {}", "API key".bright_magenta(), api_key);
+async fn update_program(
+    force: bool,
+    to: Option<String>,
+    list: bool,
+    rollback: bool,
+) -> Result<(), String> {
+    let current_exe = std::env::current_exe().map_err(|err| err.to_string())?;
+    if rollback {
+        update::rollback(&current_exe)?;
+        println!("{}", "Rolled back to the previous version".bright_green());
+        return Ok(());
+    }
+
+    let current = env!("CARGO_PKG_VERSION");
+    let releases = update::releases().await?;
+    let latest = update::latest(&releases, &utils::get_config().get_update_channel());
+    if let Some(latest) = latest {
+        update::remember_latest_version(latest.version());
+    }
+
+    if list {
+        for release in &releases {
+            let mut line = release.version().to_owned();
+            if release.prerelease {
+                line = format!("{} {}", line, "(prerelease)".yellow());
+            }
+            if release.version() == current {
+                line = format!("{} {}", line, "(installed)".green());
             }
-            return;
+            println!("{}", line);
         }
-        let api_key = args[pos + 1].clone();
-        config.set_api_key(api_key);
-        config.save();
+        return Ok(());
+    }
 
-        println!("{}", "API key set".green());
-        return;
-    } else if config.get_api_key().is_empty()  {
-        println!("{}", "No API key set. Set API key first!".yellow());
-        return;
+    let release = match &to {
+        Some(version) => {
+            let version = version.trim_start_matches('v');
+            releases
+                .iter()
+                .find(|release| release.version() == version)
+                .ok_or(format!("Version {} not found", version))?
+        }
+        None => latest.ok_or("No release found".to_owned())?,
+    };
+
+    if !force {
+        if release.version() == current {
+            println!("{}", "This version is installed already".yellow());
+            return Ok(());
+        }
+        if to.is_none() && !update::is_newer(release.version(), current) {
+            println!("{}", "No update available".yellow());
+            return Ok(());
+        }
     }
 
-    if args.contains(&"--clear-api-key".to_owned()) {
-        config.set_api_key("".to_owned());
-        config.save();
+    if update::is_newer(release.version(), current) {
+        println!(
+            "{}\n\n{}\n",
+            "Release notes:".bright_magenta(),
+            update::release_notes(&releases, current, release.version())
+        );
+    } else if release.version() != current {
+        println!(
+            "{} {} is older than the installed version {}\n",
+            "Downgrade:".yellow(),
+            release.version(),
+            current
+        );
+    }
 
-        println!("{}", "API key cleared".green());
-        return;
+    let question = format!("Install version {}?", release.version());
+    match utils::get_interaction() {
+        Interaction::Yes => {}
+        Interaction::Never => unanswered(&question),
+        Interaction::Ask => match Confirm::new(&question).with_default(true).prompt() {
+            Ok(true) => {}
+            Ok(false) => abort(),
+            Err(err) => prompt_failed(err, &question),
+        },
+    }
+
+    update::download_update(release, &current_exe).await?;
+    println!(
+        "{} {}",
+        "Updated successfully to".bright_green(),
+        release.version()
+    );
+    Ok(())
+}
+
+fn open_repository() -> Git {
+    match Git::new(env::current_dir().unwrap().to_str().unwrap().to_owned()) {
+        Ok(git) => git,
+        Err(_) => fail("Not a git repository".to_owned().into()),
     }
+}
+
+/// Prints the error and ends the program with its exit code.
+fn fail(failure: Failure) -> ! {
+    println!("{} {}", "Error:".red(), failure);
+    exit::exit(failure.code)
+}
+
+fn git_failed(err: String) -> ! {
+    fail(Failure::new(ExitCode::GitFailed, err))
+}
+
+fn abort() -> ! {
+    println!("{}", "Aborted".red());
+    exit::exit(ExitCode::Aborted)
+}
+
+/// Ends a non-interactive run at a question that needs an answer.
+fn unanswered(question: &str) -> ! {
+    println!(
+        "{} \"{}\" needs an answer, pass --yes to accept the default",
+        "Aborted:".red(),
+        question.trim_end_matches('?')
+    );
+    exit::exit(ExitCode::Aborted)
+}
 
-    if args.contains(&"--port".to_owned()) {
-        let pos = args.iter().position(|s| s == "--port").unwrap();
-        if pos + 1 >= args.len() {
-            let port = config.get_port(PORT);
-            if port == PORT {
-                println!("{}", "No port set".yellow());
+/// Ends the run when a prompt couldn't be answered: without a terminal only
+/// `--yes` can answer it, otherwise it was cancelled.
+fn prompt_failed(err: InquireError, question: &str) -> ! {
+    match err {
+        InquireError::NotTTY => unanswered(question),
+        _ => abort(),
+    }
+}
+
+/// Whether the active profile can send requests, with a hint if it can't.
+fn has_api_key() -> bool {
+    if utils::get_config().active_profile().missing_api_key() {
+        let command = parse_command(
+            format!("{} config set api_key <key>", get_executable_name()).as_str(),
+            false,
+        );
+        println!(
+            "{} Set the API key first with `{}`",
+            "No API key set.".yellow(),
+            command
+        );
+        return false;
+    }
+    true
+}
+
+fn configure(command: ConfigCommand) {
+    let config = utils::get_config();
+    match command {
+        ConfigCommand::Profiles => {
+            let default_profile = config.get_default_profile();
+            let profiles = config.get_profiles();
+            if profiles.is_empty() {
+                println!("{}", "No profiles set".yellow());
+            }
+            for (name, profile) in profiles {
+                let provider = providers::from_profile(&profile);
+                if name == default_profile {
+                    println!("{} {}", name.bright_magenta(), "(default)".bright_black());
+                } else {
+                    println!("{}", name.bright_magenta());
+                }
+                println!("  url: {}", profile.get_url(provider.chat_path()));
+                println!("  model: {}", profile.get_model());
+                println!("  provider: {}", profile.get_provider());
+                println!(
+                    "  auth: {}",
+                    profile.auth.as_deref().unwrap_or(provider.default_auth())
+                );
+                println!(
+                    "  api key: {}",
+                    if profile.get_api_key().is_empty() {
+                        "not set"
+                    } else {
+                        "set"
+                    }
+                );
+                for (header, value) in profile.get_headers() {
+                    println!(
+                        "  {}: {}",
+                        header,
+                        display_value(&format!("header.{}", header), &value)
+                    );
+                }
+            }
+        }
+        ConfigCommand::List { show_origin } => {
+            for (key, value, origin) in utils::config_entries() {
+                if show_origin {
+                    print!("{}\t", origin.to_string().bright_black());
+                }
+                println!("{}={}", key.bright_magenta(), display_value(&key, &value));
+            }
+        }
+        ConfigCommand::Get { key, show_origin } => {
+            let name = config.active_profile_name();
+            let path = if SETTING_KEYS.contains(&key.as_str()) {
+                key.to_owned()
             } else {
-                println!("{}: {}", "Port".bright_magenta(), port);
+                format!("profiles.{}.{}", name, key)
+            };
+            let entry = utils::config_entries()
+                .into_iter()
+                .find(|(entry, _, _)| *entry == path);
+            match entry {
+                Some((_, value, origin)) if show_origin => println!(
+                    "{}\t{}",
+                    origin.to_string().bright_black(),
+                    display_value(&key, &value)
+                ),
+                Some((_, value, _)) => println!("{}", display_value(&key, &value)),
+                None => println!(
+                    "{} {}",
+                    format!("{} is not set", key).yellow(),
+                    format!("({})", name).bright_black()
+                ),
+            }
+        }
+        ConfigCommand::DefaultProfile { name, target } => {
+            if !config.has_profile(&name) {
+                fail(format!("Unknown profile \"{}\"", name).into());
+            }
+            change_config(target, |config| {
+                config.set_default_profile(name);
+                Ok("Default profile set".to_owned())
+            });
+        }
+        ConfigCommand::RemoveProfile { name, target } => change_config(target, |config| {
+            if !config.remove_profile(&name) {
+                return Err(format!("Unknown profile \"{}\"", name));
             }
-            return;
+            Ok("Profile removed".to_owned())
+        }),
+        ConfigCommand::DefaultConvention { convention, target } => {
+            change_config(target, |config| {
+                config.set_convention(convention);
+                Ok("Default convention set".to_owned())
+            })
+        }
+        ConfigCommand::Set { key, value, target } => {
+            set_value(&config.active_profile_name(), &key, Some(value), target)
+        }
+        ConfigCommand::Unset { key, target } => {
+            set_value(&config.active_profile_name(), &key, None, target)
+        }
+    }
+}
+
+/// The value of a key as it can be shown, with secrets masked.
+fn display_value(key: &str, value: &str) -> String {
+    // `profiles.<name>.<key>` as listed, or just the key
+    let key = match key.find("header.") {
+        Some(start) => &key[start..],
+        None => key.rsplit('.').next().unwrap_or(key),
+    };
+    if utils::is_secret(key) {
+        utils::mask_secret(value)
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Loads the config file chosen by `target`, changes it and saves it again.
+fn change_config(target: Target, change: impl FnOnce(&mut Config) -> Result<String, String>) {
+    let result = utils::load_config(target.repo).and_then(|(mut config, path)| {
+        let message = change(&mut config)?;
+        config.save(&path)?;
+        Ok(message)
+    });
+    match result {
+        Ok(message) => println!("{}", message.green()),
+        Err(err) => fail(err.into()),
+    }
+}
+
+fn set_value(name: &str, key: &str, value: Option<String>, target: Target) {
+    if target.repo && value.is_some() && utils::is_user_only(key) {
+        fail(format!("{} can only be set in the user config", key).into());
+    }
+
+    let set = value.is_some();
+    change_config(target, |config| {
+        if SETTING_KEYS.contains(&key) {
+            config.set_setting(key, value)?;
+            return Ok(format!("{} {}", key, if set { "set" } else { "cleared" }));
         }
-        let port = args[pos + 1].clone();
-        config.set_port(Some(port.parse::<i32>().unwrap()));
-        config.save();
+        config.profile_mut(name).set(key, value)?;
+        Ok(format!(
+            "{} {} ({})",
+            key,
+            if set { "set" } else { "cleared" },
+            name
+        ))
+    });
+}
 
-        println!("{}", "Port set".green());
-        return;
+async fn pull_request(args: PrArgs) {
+    if !has_api_key() {
+        exit::exit(ExitCode::Failure);
     }
+    let git = open_repository();
+    let config = utils::get_config();
 
-    if args.contains(&"--clear-port".to_owned()) {
-        config.set_port(None);
-        config.save();
+    let base = match args.base.or_else(|| config.get_pr_base()) {
+        Some(base) => base,
+        None => git
+            .default_base()
+            .unwrap_or_else(|| git_failed("No base branch found, pass one with --base".to_owned())),
+    };
+    let fork = git.merge_base(&base).unwrap_or_else(|err| git_failed(err));
 
-        println!("{}", "Port cleared".green());
-        return;
+    let commits = git
+        .range_commits(&format!("{}..HEAD", fork))
+        .unwrap_or_else(|err| git_failed(err));
+    if commits.is_empty() {
+        eprintln!("{}", format!("No commits since {}", base).yellow());
+        exit::exit(ExitCode::NothingToCommit);
     }
+    let messages = commits
+        .iter()
+        .map(|commit| git.commit_message(*commit))
+        .collect::<Result<Vec<String>, String>>()
+        .unwrap_or_else(|err| git_failed(err));
 
-    if args.contains(&"--model-name".to_owned()) {
-        let pos = args.iter().position(|s| s == "--model-name").unwrap();
-        if pos + 1 >= args.len() {
-            let model_name = args[pos + 1].clone();
-            if model_name.is_empty() {
-                println!("{}", "No model name set".yellow());
-            } else {
-                println!("{}: {}", "Model name".bright_magenta(), model_name);
+    // the loader would end up in the description when stdout is redirected
+    let loader = (args.output.is_some() || std::io::stdout().is_terminal()).then(|| {
+        utils::Loader::new(
+            format!(
+                "Writing the description of {} {} with {}",
+                commits.len(),
+                if commits.len() == 1 {
+                    "commit"
+                } else {
+                    "commits"
+                },
+                config.active_profile().get_model()
+            )
+            .as_str(),
+        )
+    });
+    let result = gpt_api::pull_request(&git, fork, &messages).await;
+    if let Some(loader) = loader {
+        loader.stop();
+    }
+
+    let description = result.unwrap_or_else(|err| fail(err));
+    match args.output {
+        Some(path) => match fs::write(&path, description) {
+            Ok(()) => println!("{} {}", "Description written to".green(), path.display()),
+            Err(err) => fail(format!("Couldn't write {}: {}", path.display(), err).into()),
+        },
+        None => print!("{}", description),
+    }
+}
+
+async fn readme(args: ReadmeArgs) {
+    if !has_api_key() {
+        exit::exit(ExitCode::Failure);
+    }
+    let git = open_repository();
+
+    let loader = utils::Loader::new(
+        format!(
+            "Waiting for response from {}",
+            utils::get_config().active_profile().get_model()
+        )
+        .as_str(),
+    );
+
+    let mut printer = utils::StreamPrinter::new(&loader);
+    let mut on_token = |token: &str| printer.print(token);
+
+    let result = init(
+        &git,
+        args.files,
+        (!args.no_stream).then_some(&mut on_token as _),
+    )
+    .await;
+
+    loader.stop();
+    printer.finish();
+
+    match result {
+        Ok(_) => {
+            let path = Path::new("README.md");
+            if path.exists() {
+                fs::remove_file(path).unwrap();
             }
-            return;
+            let mut file = match File::create(path) {
+                Err(why) => {
+                    println!("{} {}", "Error:".red(), why);
+                    std::process::exit(1);
+                }
+                Ok(file) => file,
+            };
+            if let Err(err) = file.write(result.unwrap().as_bytes()) {
+                println!("{} {}", "Error:".red(), err);
+                std::process::exit(1);
+            }
+            println!("{}", "README.md initialized successfully".bright_green());
+        }
+        Err(err) => fail(err),
+    }
+
+    run(
+        &vec!["README.md".to_owned()],
+        "Created README.md".to_owned(),
+        args.push,
+        DiffSource::Worktree,
+        &git,
+        None,
+    )
+    .await;
+    std::process::exit(0);
+}
+
+async fn commit_changes(args: CommitArgs) {
+    if let Some(convention) = args.convention {
+        utils::select_convention(convention);
+    }
+
+    let git = open_repository();
+
+    let source = if args.amend {
+        DiffSource::Amend
+    } else if args.staged
+        || (!args.all && args.files.is_empty() && git.has_staged_changes().unwrap_or(false))
+    {
+        DiffSource::Staged
+    } else {
+        DiffSource::Worktree
+    };
+
+    if args.amend {
+        if let Err(err) = git.head_message() {
+            fail(Failure::new(ExitCode::NothingToCommit, err));
+        }
+        if let Some(upstream) = git.pushed_upstream() {
+            eprintln!(
+                "{} HEAD is already pushed to {}, amending it rewrites published history\n",
+                "Warning:".yellow(),
+                upstream
+            );
         }
-        let model_name = args[pos + 1].clone();
-        config.set_model_name(model_name);
-        config.save();
+    }
 
-        println!("{}", "Model name cleared".green());
+    if args.is_scripted() {
+        if let Err(err) = print_message(&args, source, &git).await {
+            eprintln!("Error: {}", err);
+            exit::exit(err.code);
+        }
         return;
     }
 
-    let mut push = false;
-    if args.contains(&"--push".to_owned()) || args.contains(&"-p".to_owned()) {
-        push = true;
+    let files = args.files;
+    if args.no_ai {
+        let result = ["#Title", "##Body"].join("\n");
+        run(&files, result, args.push, source, &git, None).await;
+        return;
     }
 
-    let args = args
-        .into_iter()
-        .filter(|s| s != "--push" && s != "-p")
-        .collect::<Vec<String>>();
+    if !has_api_key() {
+        exit::exit(ExitCode::Failure);
+    }
+
+    let config = utils::get_config();
+    let model = config.active_profile().get_model();
+    let stream = !args.no_stream;
+
+    if args.split {
+        let diffs = match git.get_file_diffs(Some(files), DiffSource::Worktree) {
+            Ok(diffs) => diffs,
+            Err(err) => fail(Failure::new(ExitCode::GitFailed, err.message())),
+        };
+        if diffs.is_empty() {
+            println!("{}", "Nothing to commit".yellow());
+            exit::exit(ExitCode::NothingToCommit);
+        }
 
-    let git = Git::new(env::current_dir().unwrap().to_str().unwrap().to_owned());
+        let loader = utils::Loader::new(format!("Waiting for a plan from {}", model).as_str());
+        let result = gpt_api::split(&git, &diffs).await;
+        loader.stop();
 
-    if git.is_err() {
-        println!("{} {}", "Error:".red(), "Not a git repository");
+        let plan = result.and_then(|result| {
+            Plan::parse(&result, diffs, config.get_convention())
+                .map_err(|err| Failure::new(ExitCode::UnusableOutput, err))
+        });
+        match plan {
+            Ok(plan) => run_split(plan, args.push, &git),
+            Err(err) => fail(err),
+        }
         return;
     }
 
-    let git = git.unwrap();
+    let options = QueryOptions {
+        files: files.clone(),
+        source,
+        summarize: args.summarize,
+        candidates: args.candidates.unwrap_or(1),
+    };
 
-    if args.contains(&"--init".to_owned()) || args.contains(&"-i".to_owned()) {
-        let mut pos = args.iter().position(|s| s == "--init");
-        if pos.is_none() {
-            pos = args.iter().position(|s| s == "-i");
-            if pos.is_none() {
-                println!(
-                    "{} {}",
-                    "Error:".red(),
-                    "No files specified to initialize README.md"
-                );
-                return;
-            }
+    let loader = utils::Loader::new(format!("Waiting for response from {}", model).as_str());
+    let conversation = Conversation::start(&git, &options).await;
+    loader.stop();
+
+    let mut conversation = match conversation {
+        Ok(conversation) => conversation,
+        Err(err) => fail(err),
+    };
+
+    let result = match generate(&mut conversation, stream, None).await {
+        Ok(result) => result,
+        Err(err) => fail(err),
+    };
+
+    run(
+        &files,
+        result,
+        args.push,
+        source,
+        &git,
+        Some((&mut conversation, stream)),
+    )
+    .await;
+}
+
+/// A commit of `reword` with its current and its new message.
+struct Reword {
+    commit: Oid,
+    old: String,
+    new: String,
+}
+
+async fn reword(args: RewordArgs) {
+    if !has_api_key() {
+        exit::exit(ExitCode::Failure);
+    }
+    let git = open_repository();
+    let short = |commit: &Oid| commit.to_string()[..7].to_owned();
+
+    let commits = git
+        .range_commits(&args.range)
+        .unwrap_or_else(|err| git_failed(err));
+    if commits.is_empty() {
+        println!("{}", "No commits in the range".yellow());
+        exit::exit(ExitCode::NothingToCommit);
+    }
+
+    if !args.force {
+        let replayed = git
+            .replayed_commits(&commits)
+            .unwrap_or_else(|err| git_failed(err));
+        if let Some(merge) = replayed.iter().find(|commit| git.is_merge(**commit)) {
+            fail(
+                format!(
+                    "{} is a merge commit, pass --force to rewrite it anyway",
+                    short(merge)
+                )
+                .into(),
+            );
         }
-        let pos = pos.unwrap();
-        if pos + 1 >= args.len() {
-            println!(
-                "{} {}",
-                "Error:".red(),
-                "No files specified to initialize README.md"
+        let published = commits
+            .iter()
+            .find_map(|commit| git.published_in(*commit).map(|remote| (commit, remote)));
+        if let Some((commit, remote)) = published {
+            fail(
+                format!(
+                    "{} is already pushed to {}, pass --force to rewrite published history",
+                    short(commit),
+                    remote
+                )
+                .into(),
             );
-            return;
         }
-        let files = args[pos + 1..].to_vec();
-
-        let loader = utils::Loader::new(format!("Waiting for response from {MODEL_NAME}").as_str());
+    }
 
-        let result = init(&git, files).await;
+    let config = utils::get_config();
+    let model = config.active_profile().get_model();
+    let mut rewords = Vec::new();
+    for (position, commit) in commits.iter().enumerate() {
+        let old = git
+            .commit_message(*commit)
+            .unwrap_or_else(|err| git_failed(err));
+        let options = QueryOptions {
+            files: vec![],
+            source: DiffSource::Commit(*commit),
+            summarize: false,
+            candidates: 1,
+        };
 
+        let loader = utils::Loader::new(
+            format!(
+                "Writing message {} of {} with {}",
+                position + 1,
+                commits.len(),
+                model
+            )
+            .as_str(),
+        );
+        let result = match Conversation::start(&git, &options).await {
+            Ok(conversation) => conversation.ask(None).await,
+            Err(err) => Err(err),
+        };
         loader.stop();
 
-        match result {
-            Ok(_) => {
-                let path = Path::new("README.md");
-                if path.exists() {
-                    fs::remove_file(&path).unwrap();
-                }
-                let mut file = match File::create(&path) {
-                    Err(why) => {
-                        println!("{} {}", "Error:".red(), why);
-                        std::process::exit(1);
-                    }
-                    Ok(file) => file,
-                };
-                let write = file.write(result.unwrap().as_bytes());
-                if write.is_err() {
-                    println!("{} {}", "Error:".red(), write.unwrap_err());
-                    std::process::exit(1);
-                }
-                println!("{}", "README.md initialized successfully".bright_green());
+        let new = match result {
+            Ok(mut messages) => {
+                format_commit_message(&messages.swap_remove(0).render(config.get_convention()))
             }
-            Err(err) => return println!("{} {}", "Error:".red(), err),
+            // empty commits keep their message
+            Err(err) if err.code == ExitCode::NothingToCommit => old.to_owned(),
+            Err(err) => fail(err),
+        };
+        rewords.push(Reword {
+            commit: *commit,
+            old,
+            new,
+        });
+    }
+
+    loop {
+        for reword in &rewords {
+            print_reword(reword);
         }
 
-        run(
-            &vec!["README.md".to_owned()],
-            "Created README.md".to_owned(),
-            push,
-            &git,
-        );
-        std::process::exit(0);
+        match utils::get_interaction() {
+            Interaction::Yes => break,
+            Interaction::Never => unanswered("Rewrite the commits?"),
+            Interaction::Ask => {}
+        }
+
+        let mut prompt = Select::new("Action", vec!["Rewrite", "Edit", "Keep old", "Abort"]);
+        prompt.starting_cursor = 0;
+        let action = prompt
+            .prompt()
+            .unwrap_or_else(|err| prompt_failed(err, "Rewrite the commits?"));
+        if action == "Rewrite" {
+            break;
+        }
+        if action == "Abort" {
+            abort();
+        }
+
+        let options = rewords
+            .iter()
+            .map(|reword| {
+                format!(
+                    "{} {}",
+                    short(&reword.commit),
+                    reword.new.lines().next().unwrap_or("")
+                )
+            })
+            .collect::<Vec<String>>();
+        let Ok(selected) = Select::new("Commit", options.clone()).prompt() else {
+            continue;
+        };
+        let position = options
+            .iter()
+            .position(|option| *option == selected)
+            .unwrap();
+        let reword = &mut rewords[position];
+        reword.new = if action == "Edit" {
+            edit(reword.new.to_owned())
+        } else {
+            reword.old.to_owned()
+        };
     }
 
-    if args.contains(&"--no-ai".to_owned()) || args.contains(&"-n".to_owned()) {
-        let result = vec!["#Title", "##Body"].join("\n");
-        run(&files, result, push, &git);
+    let messages = rewords
+        .into_iter()
+        .filter(|reword| reword.new != reword.old)
+        .map(|reword| (reword.commit, reword.new))
+        .collect::<HashMap<Oid, String>>();
+    if messages.is_empty() {
+        println!("{}", "No message changed".yellow());
         return;
     }
 
-    let loader = utils::Loader::new(format!("Waiting for response from {MODEL_NAME}").as_str());
+    git.reword(&messages).unwrap_or_else(|err| git_failed(err));
+    println!(
+        "{} {} {}, the previous HEAD is kept in {}",
+        "Reworded".bright_green(),
+        messages.len(),
+        if messages.len() == 1 {
+            "commit"
+        } else {
+            "commits"
+        },
+        REWORD_BACKUP_REF
+    );
+}
 
-    let result = query(None, &git, args.clone()).await;
+/// Shows the current and the new message of a commit next to each other.
+fn print_reword(reword: &Reword) {
+    let width = (utils::terminal_width().saturating_sub(3) / 2).max(20);
+    let fit = |line: &str| {
+        let mut line = line.chars().collect::<Vec<char>>();
+        if line.len() > width {
+            line.truncate(width - 1);
+            line.push('…');
+        }
+        format!("{:<width$}", line.into_iter().collect::<String>())
+    };
 
-    loader.stop();
+    println!(
+        "{} {} {}",
+        fit(&reword.commit.to_string()[..7]).yellow(),
+        "│".bright_black(),
+        if reword.new == reword.old {
+            "unchanged".bright_black()
+        } else {
+            "new".bright_black()
+        }
+    );
+    let old = reword.old.lines().collect::<Vec<&str>>();
+    let new = reword.new.lines().collect::<Vec<&str>>();
+    for index in 0..old.len().max(new.len()) {
+        println!(
+            "{} {} {}",
+            fit(old.get(index).unwrap_or(&"")),
+            "│".bright_black(),
+            fit(new.get(index).unwrap_or(&"")).green()
+        );
+    }
+    println!();
+}
 
-    let result = match result {
-        Ok(result) => result,
-        Err(err) => {
-            println!("Error: {}", err);
-            return;
+/// Generates a message and prints it instead of committing, plain with
+/// `--print` and together with the details with `--json`.
+async fn print_message(args: &CommitArgs, source: DiffSource, git: &Git) -> Result<(), Failure> {
+    let config = utils::get_config();
+    let profile = config.active_profile();
+    if profile.missing_api_key() {
+        return Err("No API key set".to_owned().into());
+    }
+
+    let options = QueryOptions {
+        files: args.files.clone(),
+        source,
+        summarize: args.summarize,
+        candidates: 1,
+    };
+    let conversation = Conversation::start(git, &options).await?;
+    let message = conversation
+        .ask(None)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or("No message generated".to_owned())?;
+    let message = format_commit_message(&message.render(config.get_convention()));
+
+    if args.print {
+        println!("{}", message);
+        return Ok(());
+    }
+
+    let output = json_output(
+        &message,
+        &profile.get_model(),
+        gpt_api::usage(),
+        conversation.files(),
+        build_commands(&message, args.push, &args.files, source),
+    );
+    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    Ok(())
+}
+
+/// What `--json` prints for a message.
+fn json_output(
+    message: &str,
+    model: &str,
+    usage: Usage,
+    files: &[String],
+    commands: Vec<Vec<String>>,
+) -> serde_json::Value {
+    let (subject, body) = message.split_once("\n\n").unwrap_or((message, ""));
+    serde_json::json!({
+        "subject": subject,
+        "body": body,
+        "model": model,
+        "usage": usage,
+        "files": files,
+        "commands": commands,
+    })
+}
+
+/// Asks for the next message, or retries after `previous` with optional
+/// feedback, and lets the user pick one when there are several candidates.
+async fn generate(
+    conversation: &mut Conversation,
+    stream: bool,
+    previous: Option<(&str, Option<&str>)>,
+) -> Result<String, Failure> {
+    let config = utils::get_config();
+    let profile = config.active_profile();
+    let candidates = conversation.candidates();
+
+    let loader = utils::Loader::new(
+        if candidates > 1 {
+            format!(
+                "Waiting for {} responses from {}",
+                candidates,
+                profile.get_model()
+            )
+        } else {
+            format!("Waiting for response from {}", profile.get_model())
         }
+        .as_str(),
+    );
+
+    let mut printer = if profile.get_response_format() == "text" {
+        utils::StreamPrinter::new(&loader)
+    } else {
+        utils::StreamPrinter::json(&loader)
     };
+    let mut on_token = |token: &str| printer.print(token);
+    let on_token = (stream && candidates == 1).then_some(&mut on_token as _);
 
-    run(&files, result, push, &git);
+    let result = match previous {
+        Some((previous, feedback)) => conversation.retry(previous, feedback, on_token).await,
+        None => conversation.ask(on_token).await,
+    };
+
+    loader.stop();
+    printer.finish();
+
+    Ok(pick_candidate(
+        result?
+            .iter()
+            .map(|message| message.render(config.get_convention()))
+            .collect(),
+    ))
 }
 
-fn run(files: &Vec<String>, result: String, push: bool, git: &Git) {
-    let prefixes_to_remove = vec!["Title:", "Body:", "Summary:", "Gitmoji:", "feat:"];
+/// Lists the generated messages and lets the user pick one. Without a
+/// terminal, or without asking, the first one is used.
+fn pick_candidate(mut candidates: Vec<String>) -> String {
+    if candidates.len() < 2 || utils::get_interaction() != Interaction::Ask {
+        return candidates.swap_remove(0);
+    }
 
-    let result = result
-        .split("\n")
-        .map(|s| {
-            let mut s = s.to_owned();
-            for prefix in &prefixes_to_remove {
-                s = s.replace(prefix, "").trim().to_owned();
-            }
-            s.to_owned()
+    println!("{}", "Candidates:".bright_magenta());
+    for (index, candidate) in candidates.iter().enumerate() {
+        let (subject, body) = candidate.split_once('\n').unwrap_or((candidate, ""));
+        println!(
+            "{} {}",
+            format!("{}.", index + 1).yellow(),
+            replace_gitmoji_with_emoji(subject).bright_green()
+        );
+        for line in body.trim().lines() {
+            println!("   {}", line);
+        }
+        println!();
+    }
+
+    let options = candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| {
+            format!(
+                "{}. {}",
+                index + 1,
+                replace_gitmoji_with_emoji(candidate.lines().next().unwrap_or(""))
+            )
         })
-        .collect::<Vec<String>>()
-        .join("\n");
+        .collect::<Vec<String>>();
+    let position = match Select::new("Message", options.clone()).prompt() {
+        Ok(prompt) => options.iter().position(|option| *option == prompt).unwrap(),
+        Err(err) => prompt_failed(err, "Which message?"),
+    };
 
-    let command = build_commands(&result, push, &files);
+    candidates.swap_remove(position)
+}
 
-    let parsed_command = parse_commands(&command, true);
+/// Shows the commands for the message and asks what to do with it. With a
+/// conversation the message can also be regenerated or refined.
+async fn run(
+    files: &Vec<String>,
+    mut result: String,
+    push: bool,
+    source: DiffSource,
+    git: &Git,
+    mut conversation: Option<(&mut Conversation, bool)>,
+) {
+    // a message from the editor is final, rewrapping it would undo the edit
+    let mut edited: Option<String> = None;
+    loop {
+        let message = match &edited {
+            Some(edited) => edited.to_owned(),
+            None => format_commit_message(&result),
+        };
 
-    println!("{}\n{}\n", "Commands:".bright_magenta(), parsed_command);
+        let command = build_commands(&message, push, files, source);
 
-    let mut prompt = Select::new("Action", vec!["Run", "Edit", "Abort"]);
-    prompt.starting_cursor = 0;
-    let prompt = prompt.prompt();
-    if prompt.is_err() {
-        print!("\n{} {}: ", "Confirm".green(), "(Y/n)");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        println!();
-        if input.trim() == "y" || input.trim() == "Y" || input.trim() == "" {
-            git.add_old(Some(&files));
-            git.commit_old(&result);
+        let parsed_command = parse_commands(&command, true);
 
-            if push {
-                println!("");
-                git.push();
+        println!("{}\n{}\n", "Commands:".bright_magenta(), parsed_command);
+
+        match utils::get_interaction() {
+            Interaction::Yes => commit(git, files, &message, push, source),
+            Interaction::Never => unanswered("Run the commands?"),
+            Interaction::Ask => {}
+        }
+
+        let mut actions = vec!["Run", "Edit", "Open in editor"];
+        if conversation.is_some() {
+            actions.extend(["Regenerate", "Refine…"]);
+        }
+        actions.push("Abort");
+
+        let mut prompt = Select::new("Action", actions);
+        prompt.starting_cursor = 0;
+        let action = prompt
+            .prompt()
+            .unwrap_or_else(|err| prompt_failed(err, "Run the commands?"));
+
+        match action {
+            "Run" => {
+                commit(git, files, &message, push, source);
             }
+            "Edit" => {
+                result = edit(message);
+                edited = None;
+            }
+            "Open in editor" => match git.edit_message(&message, files, source) {
+                Ok(message) => edited = Some(message),
+                Err(err) => println!("{} {}", "Error:".red(), err),
+            },
+            action @ ("Regenerate" | "Refine…") => {
+                let (conversation, stream) = conversation.as_mut().unwrap();
+                let feedback = if action == "Refine…" {
+                    match Text::new("Instruction").prompt() {
+                        Ok(feedback) if !feedback.trim().is_empty() => Some(feedback),
+                        _ => continue,
+                    }
+                } else {
+                    None
+                };
 
-            std::process::exit(0);
-        } else if input.trim() == "n" || input.trim() == "N" {
-            println!("{}", "Aborted".red());
-            std::process::exit(0);
+                match generate(conversation, *stream, Some((&message, feedback.as_deref()))).await {
+                    Ok(message) => {
+                        result = message;
+                        edited = None;
+                    }
+                    Err(err) => println!("{} {}", "Error:".red(), err),
+                }
+            }
+            "Abort" => abort(),
+            _ => {
+                println!("{}", "Invalid input".red());
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn commit(git: &Git, files: &Vec<String>, message: &str, push: bool, source: DiffSource) -> ! {
+    if source == DiffSource::Worktree {
+        if let Err(err) = git.add(Some(files)) {
+            fail(Failure::new(ExitCode::GitFailed, err.message()));
+        }
+    }
+
+    let result = if source == DiffSource::Amend {
+        git.amend(message)
+    } else {
+        git.commit(message)
+    };
+    if let Err(err) = result {
+        git_failed(err);
+    }
+
+    if push {
+        println!();
+        if let Err(err) = git.push() {
+            git_failed(err);
         }
-        println!("{}", "Invalid input".red());
-        std::process::exit(1);
     }
 
+    std::process::exit(0);
+}
+
+fn select_commit(plan: &Plan, message: &str) -> usize {
+    let options = plan
+        .commits
+        .iter()
+        .enumerate()
+        .map(|(position, commit)| {
+            format!(
+                "{}. {}",
+                position + 1,
+                commit.message.lines().next().unwrap_or("")
+            )
+        })
+        .collect::<Vec<String>>();
+    let prompt = Select::new(message, options.clone()).prompt();
+    if prompt.is_err() {
+        abort();
+    }
     let prompt = prompt.unwrap();
 
-    match prompt {
-        "Run" => {
-            git.add_old(Some(&files));
-            git.commit_old(&result);
+    options.iter().position(|option| *option == prompt).unwrap()
+}
 
-            if push {
-                println!("");
-                git.push();
-            }
+fn run_split(mut plan: Plan, push: bool, git: &Git) {
+    loop {
+        println!("\n{}\n{}\n", "Plan:".bright_magenta(), plan.describe());
 
-            std::process::exit(0);
+        match utils::get_interaction() {
+            Interaction::Yes => commit_split(&plan, push, git),
+            Interaction::Never => unanswered("Run the plan?"),
+            Interaction::Ask => {}
         }
-        "Edit" => {
-            let result = edit(result);
-            run(&files, result, push, git);
+
+        let mut prompt = Select::new("Action", vec!["Run", "Edit", "Move", "Merge", "Abort"]);
+        prompt.starting_cursor = 0;
+        let action = prompt
+            .prompt()
+            .unwrap_or_else(|err| prompt_failed(err, "Run the plan?"));
+
+        match action {
+            "Run" => commit_split(&plan, push, git),
+            "Edit" => {
+                let position = select_commit(&plan, "Commit to edit");
+                let message = plan.commits[position].message.to_owned();
+                plan.commits[position].message = edit(message);
+            }
+            "Move" => {
+                let position = select_commit(&plan, "Commit to move");
+                let positions = (1..=plan.commits.len())
+                    .map(|position| position.to_string())
+                    .collect::<Vec<String>>();
+                let prompt = Select::new("New position", positions).prompt();
+                if let Ok(target) = prompt {
+                    plan.move_commit(position, target.parse::<usize>().unwrap() - 1);
+                }
+            }
+            "Merge" => {
+                let options = (1..=plan.commits.len())
+                    .map(|position| position.to_string())
+                    .collect::<Vec<String>>();
+                let prompt =
+                    MultiSelect::new("Commits to merge into the first one", options).prompt();
+                if let Ok(selected) = prompt {
+                    let positions = selected
+                        .iter()
+                        .map(|position| position.parse::<usize>().unwrap() - 1)
+                        .collect::<Vec<usize>>();
+                    if positions.len() < 2 {
+                        println!("{}", "Select at least two commits".yellow());
+                    } else {
+                        plan.merge(&positions);
+                    }
+                }
+            }
+            "Abort" => abort(),
+            _ => {
+                println!("{}", "Invalid input".red());
+                std::process::exit(1);
+            }
         }
-        "Abort" => {
-            println!("{}", "Aborted".red());
-            std::process::exit(0);
+    }
+}
+
+/// Makes the planned commits one after another. The index is reset first, so
+/// every commit contains exactly its own changes.
+fn commit_split(plan: &Plan, push: bool, git: &Git) -> ! {
+    if let Err(err) = git.unstage_all() {
+        fail(Failure::new(ExitCode::GitFailed, err.message()));
+    }
+
+    let total = plan.commits.len();
+    for position in 0..total {
+        let message = format_commit_message(&plan.commits[position].message);
+        let result = plan
+            .changes(position)
+            .iter()
+            .try_for_each(|change| match change.hunks {
+                Some(_) => git.stage_hunks(&change.path, &plan.hunk_patches(position, change)),
+                None => git
+                    .add(Some(&vec![change.path.to_owned()]))
+                    .map_err(|err| err.message().to_owned()),
+            })
+            .and_then(|_| git.commit(&message).map(|_| ()));
+
+        if let Err(err) = result {
+            git.unstage_all().ok();
+            println!("{} {}", "Error:".red(), err);
+            println!(
+                "{}",
+                format!(
+                    "Stopped after {} of {} commits, the remaining changes are left in the working tree",
+                    position, total
+                )
+                .yellow()
+            );
+            exit::exit(ExitCode::GitFailed);
         }
-        _ => {
-            println!("{}", "Invalid input".red());
-            std::process::exit(1);
+        println!();
+    }
+
+    if push {
+        if let Err(err) = git.push() {
+            git_failed(err);
         }
     }
+
+    std::process::exit(0);
 }
 
 fn edit(result: String) -> String {
@@ -437,14 +1275,12 @@ fn edit(result: String) -> String {
     );
     let prompt = Select::new("Line to edit", options).prompt();
     if prompt.is_err() {
-        println!("{}", "Aborted".red());
-        std::process::exit(0);
+        abort();
     }
     let prompt = prompt.unwrap();
 
     if prompt == "Abort" {
-        println!("{}", "Aborted".red());
-        std::process::exit(0);
+        abort();
     }
 
     let prompt = prompt.parse::<usize>();
@@ -460,8 +1296,7 @@ fn edit(result: String) -> String {
     let prompt = prompt.prompt();
 
     if prompt.is_err() {
-        println!("{}", "Aborted".red());
-        std::process::exit(0);
+        abort();
     }
 
     let prompt = prompt.unwrap();
@@ -475,3 +1310,39 @@ fn edit(result: String) -> String {
         .collect::<Vec<&str>>()
         .join("\n")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_output_splits_subject_and_body() {
+        let files = vec!["src/a.rs".to_owned()];
+        let message = "Fix the port\n\nIt was off by one.\n\nReally.";
+        let commands = build_commands(message, false, &files, DiffSource::Worktree);
+        let usage = Usage {
+            prompt_tokens: 120,
+            completion_tokens: 8,
+        };
+        let output = json_output(message, "llama3", usage, &files, commands);
+
+        assert_eq!(
+            output,
+            serde_json::json!({
+                "subject": "Fix the port",
+                "body": "It was off by one.\n\nReally.",
+                "model": "llama3",
+                "usage": { "prompt_tokens": 120, "completion_tokens": 8 },
+                "files": ["src/a.rs"],
+                "commands": [
+                    ["git", "add", "src/a.rs"],
+                    ["git", "commit", "-m", "Fix the port", "-m", "It was off by one.", "-m", "Really."],
+                ],
+            })
+        );
+
+        let output = json_output("Fix the port", "llama3", Usage::default(), &[], vec![]);
+        assert_eq!(output["subject"], "Fix the port");
+        assert_eq!(output["body"], "");
+    }
+}
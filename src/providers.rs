@@ -0,0 +1,743 @@
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::utils::Profile;
+
+pub(crate) const PROVIDERS: [&str; 3] = ["openai", "ollama", "anthropic"];
+pub(crate) const AUTH_STYLES: [&str; 4] = ["bearer", "api-key", "x-api-key", "none"];
+pub(crate) const RESPONSE_FORMATS: [&str; 3] = ["json_schema", "json", "text"];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: &str, content: String) -> Self {
+        Self {
+            role: role.to_owned(),
+            content,
+        }
+    }
+}
+
+/// Constrains the reply to JSON where the server supports it.
+pub enum ResponseFormat {
+    /// Any JSON object.
+    Json,
+    /// JSON following the schema with the given name.
+    Schema(&'static str, serde_json::Value),
+}
+
+pub struct ChatRequest<'a> {
+    pub model: String,
+    pub messages: &'a [Message],
+    pub temperature: f32,
+    pub max_tokens: i32,
+    pub stream: bool,
+    pub response_format: Option<&'a ResponseFormat>,
+    /// Number of replies, only sent to servers supporting it.
+    pub n: usize,
+}
+
+/// Tokens a request used, as reported by the server.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StreamEvent {
+    Token(String),
+    Done,
+    Error(String),
+    Skip,
+}
+
+/// Translates chat requests and responses between the tool and the wire format
+/// of a specific server.
+pub trait Provider {
+    fn chat_path(&self) -> &'static str;
+
+    /// The auth style used when the profile doesn't set one.
+    fn default_auth(&self) -> &'static str;
+
+    /// Headers the endpoint requires besides authentication.
+    fn headers(&self) -> HeaderMap {
+        HeaderMap::new()
+    }
+
+    /// Whether one request can ask for several replies.
+    fn supports_n(&self) -> bool {
+        false
+    }
+
+    fn build_body(&self, request: &ChatRequest) -> serde_json::Value;
+
+    /// Parses a complete response into its replies.
+    fn parse_response(&self, body: &str) -> Result<Vec<String>, String>;
+
+    /// Reads the token usage of a complete response, if the server reports it.
+    fn parse_usage(&self, body: &str) -> Option<Usage>;
+
+    /// Parses a single line of a streamed response.
+    fn parse_stream_line(&self, line: &str) -> StreamEvent;
+
+    fn parse_error(&self, body: &str) -> Option<String>;
+}
+
+pub fn from_profile(profile: &Profile) -> Box<dyn Provider> {
+    match profile.get_provider().as_str() {
+        "ollama" => Box::new(Ollama),
+        "anthropic" => Box::new(Anthropic),
+        _ => Box::new(OpenAi),
+    }
+}
+
+fn sse_data(line: &str) -> Option<&str> {
+    line.trim().strip_prefix("data:").map(|data| data.trim())
+}
+
+pub fn auth_headers(style: &str, api_key: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let header = match style {
+        "api-key" => Some((HeaderName::from_static("api-key"), api_key.to_owned())),
+        "x-api-key" => Some((HeaderName::from_static("x-api-key"), api_key.to_owned())),
+        "none" => None,
+        _ => Some((AUTHORIZATION, format!("Bearer {}", api_key))),
+    };
+    if let Some((name, value)) = header {
+        if let Ok(value) = HeaderValue::from_str(value.as_str()) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+/// OpenAI compatible `/v1/chat/completions`, also served by LM Studio,
+/// llama.cpp's server and Ollama's compatibility layer.
+pub struct OpenAi;
+
+#[derive(Debug, Deserialize)]
+struct OpenAiResponseBody {
+    choices: Vec<OpenAiResponseBodyChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiUsageBody {
+    usage: Option<OpenAiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiResponseBodyChoice {
+    message: Message,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiStreamChunk {
+    choices: Vec<OpenAiStreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiStreamChoice {
+    delta: OpenAiStreamDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiStreamDelta {
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiErrorResponse {
+    error: OpenAiError,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiError {
+    message: Option<String>,
+    code: Option<String>,
+}
+
+impl Provider for OpenAi {
+    fn chat_path(&self) -> &'static str {
+        "/v1/chat/completions"
+    }
+
+    fn default_auth(&self) -> &'static str {
+        "bearer"
+    }
+
+    fn supports_n(&self) -> bool {
+        true
+    }
+
+    fn build_body(&self, request: &ChatRequest) -> serde_json::Value {
+        let mut body = json!({
+            "model": request.model,
+            "messages": request.messages,
+            "temperature": request.temperature,
+            "max_tokens": request.max_tokens,
+            "stream": request.stream,
+        });
+        if request.n > 1 {
+            body["n"] = json!(request.n);
+        }
+        match request.response_format {
+            Some(ResponseFormat::Json) => {
+                body["response_format"] = json!({ "type": "json_object" });
+            }
+            Some(ResponseFormat::Schema(name, schema)) => {
+                body["response_format"] = json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": name, "schema": schema, "strict": true },
+                });
+            }
+            None => {}
+        }
+        body
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Vec<String>, String> {
+        match serde_json::from_str::<OpenAiResponseBody>(body) {
+            Ok(json) if json.choices.is_empty() => Err("Response contained no choices".to_owned()),
+            Ok(json) => Ok(json
+                .choices
+                .into_iter()
+                .map(|choice| choice.message.content)
+                .collect()),
+            Err(err) => Err(self.parse_error(body).unwrap_or(err.to_string())),
+        }
+    }
+
+    fn parse_usage(&self, body: &str) -> Option<Usage> {
+        let usage = serde_json::from_str::<OpenAiUsageBody>(body).ok()?.usage?;
+        Some(Usage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        })
+    }
+
+    fn parse_stream_line(&self, line: &str) -> StreamEvent {
+        let data = match sse_data(line) {
+            Some(data) => data,
+            None => return StreamEvent::Skip,
+        };
+
+        if data == "[DONE]" {
+            return StreamEvent::Done;
+        }
+
+        if let Ok(chunk) = serde_json::from_str::<OpenAiStreamChunk>(data) {
+            let token = chunk
+                .choices
+                .into_iter()
+                .filter_map(|choice| choice.delta.content)
+                .collect::<String>();
+            return StreamEvent::Token(token);
+        }
+
+        match self.parse_error(data) {
+            Some(error) => StreamEvent::Error(error),
+            None => StreamEvent::Skip,
+        }
+    }
+
+    fn parse_error(&self, body: &str) -> Option<String> {
+        let error = serde_json::from_str::<OpenAiErrorResponse>(body)
+            .ok()?
+            .error;
+        let message = error.message.unwrap_or("".to_owned());
+        let code = error.code.unwrap_or("".to_owned());
+        Some(format!("{} {}", message, code).trim().to_owned())
+    }
+}
+
+/// Ollama's native `/api/chat`, streamed as newline delimited JSON.
+pub struct Ollama;
+
+#[derive(Debug, Deserialize)]
+struct OllamaResponseBody {
+    message: Option<Message>,
+    done: Option<bool>,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaErrorResponse {
+    error: String,
+}
+
+impl Provider for Ollama {
+    fn chat_path(&self) -> &'static str {
+        "/api/chat"
+    }
+
+    fn default_auth(&self) -> &'static str {
+        "bearer"
+    }
+
+    fn build_body(&self, request: &ChatRequest) -> serde_json::Value {
+        let mut body = json!({
+            "model": request.model,
+            "messages": request.messages,
+            "stream": request.stream,
+            "options": {
+                "temperature": request.temperature,
+                "num_predict": request.max_tokens,
+            },
+        });
+        match request.response_format {
+            Some(ResponseFormat::Json) => body["format"] = json!("json"),
+            Some(ResponseFormat::Schema(_, schema)) => body["format"] = schema.to_owned(),
+            None => {}
+        }
+        body
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Vec<String>, String> {
+        match serde_json::from_str::<OllamaResponseBody>(body) {
+            Ok(OllamaResponseBody {
+                message: Some(message),
+                ..
+            }) => Ok(vec![message.content]),
+            Ok(_) => Err(self
+                .parse_error(body)
+                .unwrap_or("Response contained no message".to_owned())),
+            Err(err) => Err(self.parse_error(body).unwrap_or(err.to_string())),
+        }
+    }
+
+    fn parse_usage(&self, body: &str) -> Option<Usage> {
+        let body = serde_json::from_str::<OllamaResponseBody>(body).ok()?;
+        Some(Usage {
+            prompt_tokens: body.prompt_eval_count?,
+            completion_tokens: body.eval_count?,
+        })
+    }
+
+    fn parse_stream_line(&self, line: &str) -> StreamEvent {
+        let line = line.trim();
+        if line.is_empty() {
+            return StreamEvent::Skip;
+        }
+
+        if let Some(error) = self.parse_error(line) {
+            return StreamEvent::Error(error);
+        }
+
+        match serde_json::from_str::<OllamaResponseBody>(line) {
+            Ok(chunk) if chunk.done.unwrap_or(false) => StreamEvent::Done,
+            Ok(chunk) => match chunk.message {
+                Some(message) => StreamEvent::Token(message.content),
+                None => StreamEvent::Skip,
+            },
+            Err(_) => StreamEvent::Skip,
+        }
+    }
+
+    fn parse_error(&self, body: &str) -> Option<String> {
+        serde_json::from_str::<OllamaErrorResponse>(body)
+            .ok()
+            .map(|error| error.error)
+    }
+}
+
+/// Anthropic style `/v1/messages`. System messages are moved into the
+/// top-level `system` field as the endpoint only accepts user and assistant turns.
+/// There is no response format, JSON replies rely on the prompt alone.
+pub struct Anthropic;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Debug, Deserialize)]
+struct AnthropicResponseBody {
+    content: Vec<AnthropicContent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsageBody {
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicContent {
+    #[serde(rename = "type")]
+    _type: String,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamEvent {
+    #[serde(rename = "type")]
+    _type: String,
+    delta: Option<AnthropicContent>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicErrorResponse {
+    error: AnthropicError,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicError {
+    #[serde(rename = "type")]
+    _type: Option<String>,
+    message: Option<String>,
+}
+
+impl Provider for Anthropic {
+    fn chat_path(&self) -> &'static str {
+        "/v1/messages"
+    }
+
+    fn default_auth(&self) -> &'static str {
+        "x-api-key"
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "anthropic-version",
+            HeaderValue::from_static(ANTHROPIC_VERSION),
+        );
+        headers
+    }
+
+    fn build_body(&self, request: &ChatRequest) -> serde_json::Value {
+        let system = request
+            .messages
+            .iter()
+            .filter(|message| message.role == "system")
+            .map(|message| message.content.to_owned())
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        let mut messages = request
+            .messages
+            .iter()
+            .filter(|message| message.role != "system")
+            .cloned()
+            .collect::<Vec<Message>>();
+
+        if messages.is_empty() {
+            messages.push(Message::new("user", system.to_owned()));
+        }
+
+        json!({
+            "model": request.model,
+            "system": system,
+            "messages": messages,
+            "temperature": request.temperature,
+            "max_tokens": request.max_tokens,
+            "stream": request.stream,
+        })
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Vec<String>, String> {
+        match serde_json::from_str::<AnthropicResponseBody>(body) {
+            Ok(json) => Ok(vec![json
+                .content
+                .into_iter()
+                .filter(|content| content._type == "text")
+                .filter_map(|content| content.text)
+                .collect::<String>()]),
+            Err(err) => Err(self.parse_error(body).unwrap_or(err.to_string())),
+        }
+    }
+
+    fn parse_usage(&self, body: &str) -> Option<Usage> {
+        let usage = serde_json::from_str::<AnthropicUsageBody>(body)
+            .ok()?
+            .usage?;
+        Some(Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        })
+    }
+
+    fn parse_stream_line(&self, line: &str) -> StreamEvent {
+        let data = match sse_data(line) {
+            Some(data) => data,
+            None => return StreamEvent::Skip,
+        };
+
+        match serde_json::from_str::<AnthropicStreamEvent>(data) {
+            Ok(event) => match event._type.as_str() {
+                "content_block_delta" => match event.delta.and_then(|delta| delta.text) {
+                    Some(text) => StreamEvent::Token(text),
+                    None => StreamEvent::Skip,
+                },
+                "message_stop" => StreamEvent::Done,
+                "error" => StreamEvent::Error(
+                    self.parse_error(data)
+                        .unwrap_or("Unknown streaming error".to_owned()),
+                ),
+                _ => StreamEvent::Skip,
+            },
+            Err(_) => StreamEvent::Skip,
+        }
+    }
+
+    fn parse_error(&self, body: &str) -> Option<String> {
+        let error = serde_json::from_str::<AnthropicErrorResponse>(body)
+            .ok()?
+            .error;
+        let message = error.message.unwrap_or("".to_owned());
+        let kind = error._type.unwrap_or("".to_owned());
+        Some(format!("{} {}", message, kind).trim().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::new("system", "Write commit messages.".to_owned()),
+            Message::new("user", "diff --git a/x b/x".to_owned()),
+        ]
+    }
+
+    fn request<'a>(
+        messages: &'a [Message],
+        response_format: Option<&'a ResponseFormat>,
+        n: usize,
+    ) -> ChatRequest<'a> {
+        ChatRequest {
+            model: "model".to_owned(),
+            messages,
+            temperature: 0.5,
+            max_tokens: 300,
+            stream: true,
+            response_format,
+            n,
+        }
+    }
+
+    fn schema() -> ResponseFormat {
+        ResponseFormat::Schema("commit_message", json!({ "type": "object" }))
+    }
+
+    #[test]
+    fn sends_the_key_in_the_auth_style() {
+        let header = |style: &str, name: &str| {
+            auth_headers(style, "sk-1")
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+        assert_eq!(
+            header("bearer", "authorization").as_deref(),
+            Some("Bearer sk-1")
+        );
+        assert_eq!(header("api-key", "api-key").as_deref(), Some("sk-1"));
+        assert_eq!(header("x-api-key", "x-api-key").as_deref(), Some("sk-1"));
+        assert!(auth_headers("none", "sk-1").is_empty());
+        assert_eq!(
+            Anthropic.headers().get("anthropic-version").unwrap(),
+            ANTHROPIC_VERSION
+        );
+    }
+
+    #[test]
+    fn openai_body() {
+        let messages = messages();
+        let schema = schema();
+        let body = OpenAi.build_body(&request(&messages, Some(&schema), 3));
+        assert_eq!(body["model"], "model");
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["max_tokens"], 300);
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["n"], 3);
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(
+            body["response_format"]["json_schema"]["name"],
+            "commit_message"
+        );
+
+        let body = OpenAi.build_body(&request(&messages, Some(&ResponseFormat::Json), 1));
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+        assert!(body.get("n").is_none());
+
+        let body = OpenAi.build_body(&request(&messages, None, 1));
+        assert!(body.get("response_format").is_none());
+    }
+
+    #[test]
+    fn openai_stream() {
+        assert_eq!(
+            OpenAi.parse_stream_line(r#"data: {"choices":[{"delta":{"content":"fix"}}]}"#),
+            StreamEvent::Token("fix".to_owned())
+        );
+        assert_eq!(
+            OpenAi.parse_stream_line(r#"data: {"choices":[{"delta":{}}]}"#),
+            StreamEvent::Token(String::new())
+        );
+        assert_eq!(OpenAi.parse_stream_line("data: [DONE]"), StreamEvent::Done);
+        assert_eq!(
+            OpenAi.parse_stream_line(
+                r#"data: {"error":{"message":"Model not loaded","code":"model_not_found"}}"#
+            ),
+            StreamEvent::Error("Model not loaded model_not_found".to_owned())
+        );
+        assert_eq!(OpenAi.parse_stream_line(""), StreamEvent::Skip);
+        assert_eq!(OpenAi.parse_stream_line(": keep-alive"), StreamEvent::Skip);
+    }
+
+    #[test]
+    fn openai_response() {
+        let body = r#"{"choices":[
+            {"message":{"role":"assistant","content":"fix: one"}},
+            {"message":{"role":"assistant","content":"fix: two"}}
+        ],"usage":{"prompt_tokens":120,"completion_tokens":8}}"#;
+        assert_eq!(
+            OpenAi.parse_response(body).unwrap(),
+            vec!["fix: one", "fix: two"]
+        );
+        let usage = OpenAi.parse_usage(body).unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (120, 8));
+
+        assert!(OpenAi.parse_response(r#"{"choices":[]}"#).is_err());
+        assert_eq!(
+            OpenAi.parse_response(r#"{"error":{"message":"Invalid API key"}}"#),
+            Err("Invalid API key".to_owned())
+        );
+    }
+
+    #[test]
+    fn ollama_body() {
+        let messages = messages();
+        let schema = schema();
+        let body = Ollama.build_body(&request(&messages, Some(&schema), 3));
+        assert_eq!(body["options"]["num_predict"], 300);
+        assert_eq!(body["options"]["temperature"], 0.5);
+        assert_eq!(body["format"], json!({ "type": "object" }));
+        assert!(body.get("n").is_none());
+
+        let body = Ollama.build_body(&request(&messages, Some(&ResponseFormat::Json), 1));
+        assert_eq!(body["format"], "json");
+    }
+
+    #[test]
+    fn ollama_stream() {
+        assert_eq!(
+            Ollama.parse_stream_line(
+                r#"{"message":{"role":"assistant","content":"fix"},"done":false}"#
+            ),
+            StreamEvent::Token("fix".to_owned())
+        );
+        assert_eq!(
+            Ollama.parse_stream_line(
+                r#"{"message":{"role":"assistant","content":""},"done":true,"eval_count":8}"#
+            ),
+            StreamEvent::Done
+        );
+        assert_eq!(
+            Ollama.parse_stream_line(r#"{"error":"model 'x' not found"}"#),
+            StreamEvent::Error("model 'x' not found".to_owned())
+        );
+        assert_eq!(Ollama.parse_stream_line("  "), StreamEvent::Skip);
+    }
+
+    #[test]
+    fn ollama_response() {
+        let body = r#"{"message":{"role":"assistant","content":"fix: one"},"done":true,
+            "prompt_eval_count":120,"eval_count":8}"#;
+        assert_eq!(Ollama.parse_response(body).unwrap(), vec!["fix: one"]);
+        let usage = Ollama.parse_usage(body).unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (120, 8));
+
+        assert_eq!(
+            Ollama.parse_response(r#"{"error":"model 'x' not found"}"#),
+            Err("model 'x' not found".to_owned())
+        );
+    }
+
+    #[test]
+    fn anthropic_body() {
+        let messages = messages();
+        let schema = schema();
+        let body = Anthropic.build_body(&request(&messages, Some(&schema), 3));
+        assert_eq!(body["system"], "Write commit messages.");
+        assert_eq!(
+            body["messages"],
+            json!([{ "role": "user", "content": "diff --git a/x b/x" }])
+        );
+        assert_eq!(body["max_tokens"], 300);
+        assert!(body.get("response_format").is_none());
+        assert!(body.get("n").is_none());
+
+        // the endpoint needs at least one user turn
+        let system_only = &messages[..1];
+        let body = Anthropic.build_body(&request(system_only, None, 1));
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert_eq!(body["messages"][0]["content"], "Write commit messages.");
+    }
+
+    #[test]
+    fn anthropic_stream() {
+        assert_eq!(
+            Anthropic.parse_stream_line(
+                r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"fix"}}"#
+            ),
+            StreamEvent::Token("fix".to_owned())
+        );
+        assert_eq!(
+            Anthropic.parse_stream_line(r#"data: {"type":"message_stop"}"#),
+            StreamEvent::Done
+        );
+        assert_eq!(
+            Anthropic.parse_stream_line(
+                r#"data: {"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#
+            ),
+            StreamEvent::Error("Overloaded overloaded_error".to_owned())
+        );
+        assert_eq!(
+            Anthropic.parse_stream_line("event: content_block_delta"),
+            StreamEvent::Skip
+        );
+        assert_eq!(
+            Anthropic.parse_stream_line(r#"data: {"type":"ping"}"#),
+            StreamEvent::Skip
+        );
+    }
+
+    #[test]
+    fn anthropic_response() {
+        let body = r#"{"content":[{"type":"text","text":"fix: "},{"type":"text","text":"one"}],
+            "usage":{"input_tokens":120,"output_tokens":8}}"#;
+        assert_eq!(Anthropic.parse_response(body).unwrap(), vec!["fix: one"]);
+        let usage = Anthropic.parse_usage(body).unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (120, 8));
+
+        assert_eq!(
+            Anthropic.parse_response(
+                r#"{"type":"error","error":{"type":"authentication_error","message":"invalid x-api-key"}}"#
+            ),
+            Err("invalid x-api-key authentication_error".to_owned())
+        );
+    }
+}
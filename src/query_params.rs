@@ -1,86 +1,314 @@
-use std::path::Path;
-
-use normpath::{BasePathBuf, PathExt};
-
-use crate::{git::Git, os_info::get_os_info};
-
-fn get_params() -> Vec<String> {
-    let params = vec![
-        "You write an informative commit message.",
-        "You write a commit subject and body, separated with a new line.",
-        "You just reply with 2 lines in total.",
-        "Subject line should include One Gitmoji, A Short Summary, using imperative, start with upper case, doesn't end with a period and should not be longer than 50 characters",
-        "1. Limit the subject line to 50 characters",
-        "2. Use one Gitmoji at the start of the subject line",
-        "3. Use imperative in the subject line",
-        "4. Wrap the body at 72 characters",
-        "5. Use the body to explain what and why vs. how",
-        "6. Do not use markdown headings or code blocks",
-    ];
-
-    params.iter().map(|s| s.to_string()).collect()
-}
-
-fn get_readme_params() -> Vec<String> {
-    let params = vec!["create a readme based on the content of the given info"];
-
-    params.iter().map(|s| s.to_string()).collect()
-}
-
-pub fn build_initial_message() -> String {
-    let params = get_params().join("\n");
-    let os_info = get_os_info();
-
-    format!(
-        "# The system information:\n{}\n\n# Your instructions:\n{}",
-        os_info, params
-    )
-}
-
-pub fn build_query(git: &Git, files: Vec<String>) -> String {
-    let diff = git.clone().get_diff(Some(files)).unwrap();
-    let status = git.clone().get_status().unwrap();
-
-    format!(
-        "# Git-Status:\n{}\n\n# Git-Diffs, everything from here is the diff:\n{}",
-        status, diff
-    )
-}
-
-pub fn build_readme_query(git: &Git, files: Vec<String>) -> String {
-    let remotes = git.clone().repo.remotes().unwrap();
-
-    let params = get_readme_params().join("\n");
-    let origin = remotes.get(0).unwrap();
-    let content = get_contents(files);
-
-    let main = format!("{}\n\n{}\n\n", origin, params);
-
-    let content = if content.len() > 4096 - main.len() {
-        content[..4096 - main.len()].to_owned()
-    } else {
-        content
-    };
-
-    format!("{}\n\n{}", main, content)
-}
-
-fn get_contents(files: Vec<String>) -> String {
-    let mut contents = String::new();
-
-    let paths: Vec<BasePathBuf> = files
-        .iter()
-        .map(|entry| Path::new(entry).normalize())
-        .filter(|entry| entry.is_ok())
-        .map(|entry| entry.unwrap())
-        .collect();
-
-    for path in paths {
-        let file_name = path.file_name().unwrap().to_str().unwrap();
-        let content = std::fs::read_to_string(path.as_path()).unwrap();
-        contents.push_str(format!("## {}\n\n", file_name).as_str());
-        contents.push_str(content.as_str());
-    }
-
-    contents
-}
+use std::path::Path;
+
+use git2::Oid;
+
+use normpath::{BasePathBuf, PathExt};
+
+use crate::{
+    budget::{diffstat, estimate_tokens, pack_diffs},
+    git::{DiffSource, FileDiff, Git},
+    os_info::get_os_info,
+    utils::get_config,
+};
+
+fn get_params(structured: bool) -> Vec<String> {
+    let reply = if structured {
+        "You reply with a JSON object only: {\"gitmoji\": one Gitmoji code like :sparkles:, \"type\": the Conventional Commits type, \"scope\": the scope or an empty string, \"subject\": the summary without Gitmoji and type, \"body\": the body, \"breaking\": true for breaking changes, \"breaking_change\": what breaks or an empty string}."
+    } else {
+        "You just reply with 2 lines in total."
+    };
+    let mut params = vec![
+        "You write an informative commit message.".to_owned(),
+        "You write a commit subject and body, separated with a new line.".to_owned(),
+        reply.to_owned(),
+        "Subject line should be a short summary, using imperative, doesn't end with a period and should not be longer than 50 characters".to_owned(),
+    ];
+    params.extend(get_config().get_convention().rules());
+
+    params
+}
+
+fn get_split_params() -> Vec<String> {
+    let mut params = [
+        "You split the given changes into a few atomic commits, one per logical change.",
+        "Every change belongs to exactly one commit. Commits are listed in the order they should be made.",
+        "A change is either a whole file, written as its path, or a single hunk, written as the path followed by # and the number of the hunk, e.g. src/main.rs#2.",
+        "Only split a file into hunks when its hunks belong to different commits.",
+        "Reply with JSON only, in the form {\"commits\": [{\"message\": \"<subject>\\n\\n<body>\", \"changes\": [\"<path>\", \"<path>#<hunk>\"]}]}",
+        "Every message has a subject and a body:",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect::<Vec<String>>();
+    params.extend(get_config().get_convention().rules());
+
+    params
+}
+
+fn get_readme_params() -> Vec<String> {
+    let params = ["create a readme based on the content of the given info"];
+
+    params.iter().map(|s| s.to_string()).collect()
+}
+
+/// The system prompt for the commit message. With `structured` the model is
+/// asked for the JSON of `gpt_api::CommitMessage` instead of plain text.
+pub fn build_initial_message(structured: bool) -> String {
+    let params = get_params(structured).join("\n");
+    let os_info = get_os_info();
+
+    format!(
+        "# The system information:\n{}\n\n# Your instructions:\n{}",
+        os_info, params
+    )
+}
+
+/// The message of the commit that is amended or reworded, as a starting point
+/// for the new one.
+fn amend_context(git: &Git, source: DiffSource) -> String {
+    let message = match source {
+        DiffSource::Amend => git.head_message(),
+        DiffSource::Commit(commit) => git.commit_message(commit),
+        _ => return String::new(),
+    };
+    match message {
+        Ok(message) => format!(
+            "# Current message of the commit, rewrite it for the changes:\n{}\n\n",
+            message
+        ),
+        Err(_) => String::new(),
+    }
+}
+
+fn status_section(status: &str) -> String {
+    if status.is_empty() {
+        String::new()
+    } else {
+        format!("# Git-Status:\n{}\n\n", status)
+    }
+}
+
+/// Builds the user message, fitting the diff into `budget` tokens while the
+/// status and the list of changed files are always kept complete.
+pub fn build_query(git: &Git, source: DiffSource, diffs: &[FileDiff], budget: usize) -> String {
+    let status = git.get_status(source).unwrap();
+
+    let summary = format!(
+        "{}{}# Changed files:\n{}\n\n",
+        amend_context(git, source),
+        status_section(&status),
+        diffstat(diffs)
+    );
+    let heading = "# Git-Diffs, everything from here is the diff:\n";
+    let elided_heading = "# Elided:\nThe diff was shortened to fit into the context window, these parts are missing:\n";
+
+    let budget = budget.saturating_sub(
+        estimate_tokens(&summary) + estimate_tokens(heading) + estimate_tokens(elided_heading),
+    );
+    let packed = pack_diffs(diffs, budget);
+
+    let elided = if packed.elided.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "{}{}\n\n",
+            elided_heading,
+            packed
+                .elided
+                .iter()
+                .map(|part| format!("- {}", part))
+                .collect::<Vec<String>>()
+                .join("\n")
+        )
+    };
+
+    format!("{}{}{}{}", summary, elided, heading, packed.diff)
+}
+
+pub fn build_split_message() -> String {
+    let params = get_split_params().join("\n");
+
+    format!("# Your instructions:\n{}", params)
+}
+
+/// Same as `build_query`, with every hunk labeled so the model can refer to it.
+pub fn build_split_query(git: &Git, diffs: &[FileDiff], budget: usize) -> String {
+    let diffs = diffs
+        .iter()
+        .map(|file| FileDiff {
+            hunks: file
+                .hunks
+                .iter()
+                .enumerate()
+                .map(|(index, hunk)| format!("# hunk {}#{}\n{}", file.path, index + 1, hunk))
+                .collect(),
+            ..file.clone()
+        })
+        .collect::<Vec<FileDiff>>();
+
+    build_query(git, DiffSource::Worktree, &diffs, budget)
+}
+
+pub fn build_pr_message() -> String {
+    let params = [
+        "You write the title and description of a pull request in Markdown.",
+        "The first line is the title as a level 1 heading, a short summary in imperative mood.",
+        "Then follow the sections \"## Summary\" with a few sentences on what the pull request does and why, \"## Changes\" with a bullet list of the notable changes and \"## Test notes\" with what reviewers should test or check.",
+        "Base the description on the commit messages and the diff, do not invent changes.",
+        "Reply with the Markdown only, without wrapping it in a code block.",
+    ];
+
+    params.join("\n")
+}
+
+/// Builds the user message for a pull request: the messages of its commits,
+/// which are always kept, and the combined diff within `budget` tokens.
+pub fn build_pr_query(
+    git: &Git,
+    base: Oid,
+    messages: &[String],
+    diffs: &[FileDiff],
+    budget: usize,
+) -> String {
+    let commits = format!(
+        "# Commit messages, oldest first:\n{}\n\n",
+        messages
+            .iter()
+            .map(|message| format!("- {}", message.trim().replace('\n', "\n  ")))
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+    let budget = budget.saturating_sub(estimate_tokens(&commits));
+
+    format!(
+        "{}{}",
+        commits,
+        build_query(git, DiffSource::Since(base), diffs, budget)
+    )
+}
+
+pub fn build_summary_message() -> String {
+    let params = [
+        "You summarize the diff of a single file for a commit message.",
+        "Reply with one to three short sentences describing what changed and why, if it is apparent.",
+        "Do not use markdown, do not repeat the file name and do not quote code.",
+    ];
+
+    params.join("\n")
+}
+
+/// Builds the user message from summaries of the single files instead of the
+/// diff, used when the change set is too large to describe it in detail.
+pub fn build_summarized_query(
+    git: &Git,
+    source: DiffSource,
+    diffs: &[FileDiff],
+    summaries: &[String],
+) -> String {
+    let status = git.get_status(source).unwrap();
+    let summaries = diffs
+        .iter()
+        .zip(summaries)
+        .map(|(file, summary)| {
+            let summary = summary.split_whitespace().collect::<Vec<&str>>().join(" ");
+            format!("- {}: {}", file.path, summary)
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    format!(
+        "{}{}# Changed files:\n{}\n\n# Summaries of the changes per file:\n{}",
+        amend_context(git, source),
+        status_section(&status),
+        diffstat(diffs),
+        summaries
+    )
+}
+
+pub fn build_readme_query(git: &Git, files: Vec<String>) -> String {
+    let remotes = git.clone().repo.remotes().unwrap();
+
+    let params = get_readme_params().join("\n");
+    let origin = remotes.get(0).unwrap();
+    let content = get_contents(files);
+
+    let main = format!("{}\n\n{}\n\n", origin, params);
+
+    let content = if content.len() > 4096 - main.len() {
+        content[..4096 - main.len()].to_owned()
+    } else {
+        content
+    };
+
+    format!("{}\n\n{}", main, content)
+}
+
+fn get_contents(files: Vec<String>) -> String {
+    let mut contents = String::new();
+
+    let paths: Vec<BasePathBuf> = files
+        .iter()
+        .flat_map(|entry| Path::new(entry).normalize())
+        .collect();
+
+    for path in paths {
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let content = std::fs::read_to_string(path.as_path()).unwrap();
+        contents.push_str(format!("## {}\n\n", file_name).as_str());
+        contents.push_str(content.as_str());
+    }
+
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::tests::TestRepo;
+
+    #[test]
+    fn summarized_query_lists_every_file_with_its_summary() {
+        let test = TestRepo::new("summarized-query");
+        test.write("src/a.rs", "fn a() {}\n");
+        test.write("src/b.rs", "fn b() {}\n");
+        test.commit_all("init");
+        test.write("src/a.rs", "fn a() -> u8 { 1 }\n");
+        test.write("src/b.rs", "fn b() -> u8 { 2 }\n");
+
+        let diffs = test.git.get_file_diffs(None, DiffSource::Worktree).unwrap();
+        let summaries = vec![
+            "Returns one.".to_owned(),
+            "Returns\n  two,\tnow.\n".to_owned(),
+        ];
+        let query = build_summarized_query(&test.git, DiffSource::Worktree, &diffs, &summaries);
+
+        assert!(query.contains("# Git-Status:\n"));
+        assert!(query.contains("# Changed files:\n"));
+        assert!(query.ends_with(
+            "# Summaries of the changes per file:\n- src/a.rs: Returns one.\n- src/b.rs: Returns two, now."
+        ));
+        assert!(!query.contains("fn a() -> u8"));
+    }
+
+    #[test]
+    fn pr_query_keeps_every_commit_message() {
+        let test = TestRepo::new("pr-query");
+        test.write("a.txt", "a\n");
+        let base = test.commit_all("init");
+        test.write("a.txt", "a\nb\n");
+        test.commit_all("Add b\n\nBecause b was missing.");
+
+        let diffs = test
+            .git
+            .get_file_diffs(None, DiffSource::Since(base))
+            .unwrap();
+        let messages = vec!["Add b\n\nBecause b was missing.".to_owned()];
+        let query = build_pr_query(&test.git, base, &messages, &diffs, 10_000);
+
+        assert!(query.starts_with(
+            "# Commit messages, oldest first:\n- Add b\n  \n  Because b was missing.\n\n"
+        ));
+        assert!(query.contains("+b"));
+        assert!(build_pr_message().contains("## Test notes"));
+    }
+}
@@ -0,0 +1,402 @@
+use colored::Colorize;
+use serde::Deserialize;
+
+use crate::convention::Convention;
+use crate::git::FileDiff;
+use crate::gpt_api::extract_json;
+
+/// Written in the fullest form, `Convention::format_message` drops what the
+/// convention doesn't use.
+const REMAINING_MESSAGE: &str = "chore: :wrench: commit the remaining changes";
+
+#[derive(Deserialize)]
+struct RawPlan {
+    commits: Vec<RawCommit>,
+}
+
+#[derive(Deserialize)]
+struct RawCommit {
+    message: String,
+    #[serde(default)]
+    changes: Vec<String>,
+}
+
+/// A whole file, or some of its hunks, to stage for a commit.
+pub struct Change {
+    pub path: String,
+    /// Indices of the hunks, `None` for the whole file.
+    pub hunks: Option<Vec<usize>>,
+}
+
+pub struct PlannedCommit {
+    id: usize,
+    pub message: String,
+}
+
+/// Commits proposed by the model. Every hunk of a splittable file, and every
+/// other file as a whole, is owned by exactly one commit.
+pub struct Plan {
+    pub commits: Vec<PlannedCommit>,
+    diffs: Vec<FileDiff>,
+    owners: Vec<Vec<Option<usize>>>,
+}
+
+/// Hunks can only be staged on their own for files git already tracks.
+fn splittable(file: &FileDiff) -> bool {
+    file.status == 'M' && !file.binary && file.hunks.len() > 1
+}
+
+fn units(file: &FileDiff) -> usize {
+    if splittable(file) {
+        file.hunks.len()
+    } else {
+        1
+    }
+}
+
+impl Plan {
+    pub fn parse(text: &str, diffs: Vec<FileDiff>, convention: Convention) -> Result<Plan, String> {
+        let json = extract_json(text).ok_or("The model didn't reply with a plan".to_owned())?;
+        let raw = serde_json::from_str::<RawPlan>(json)
+            .map_err(|err| format!("Invalid plan: {}", err))?;
+
+        let mut plan = Plan {
+            commits: Vec::new(),
+            owners: diffs.iter().map(|file| vec![None; units(file)]).collect(),
+            diffs,
+        };
+
+        for (id, commit) in raw.commits.into_iter().enumerate() {
+            for change in &commit.changes {
+                if let Some((file, unit)) = plan.find_change(change) {
+                    let owners = &mut plan.owners[file];
+                    match unit {
+                        Some(unit) => {
+                            owners[unit].get_or_insert(id);
+                        }
+                        None => owners.iter_mut().for_each(|owner| {
+                            owner.get_or_insert(id);
+                        }),
+                    }
+                }
+            }
+            plan.commits.push(PlannedCommit {
+                id,
+                message: convention.format_message(&commit.message),
+            });
+        }
+
+        let remaining = plan.commits.len();
+        let mut has_remaining = false;
+        for owner in plan.owners.iter_mut().flatten() {
+            if owner.is_none() {
+                *owner = Some(remaining);
+                has_remaining = true;
+            }
+        }
+        if has_remaining {
+            plan.commits.push(PlannedCommit {
+                id: remaining,
+                message: convention.format_message(REMAINING_MESSAGE),
+            });
+        }
+
+        plan.drop_empty();
+        if plan.commits.is_empty() {
+            return Err("The plan contains no commits".to_owned());
+        }
+
+        Ok(plan)
+    }
+
+    /// Resolves `path` or `path#hunk` to a file and, for splittable files, a hunk.
+    fn find_change(&self, change: &str) -> Option<(usize, Option<usize>)> {
+        let change = change.trim();
+        if let Some(file) = self.diffs.iter().position(|file| file.path == change) {
+            return Some((file, None));
+        }
+
+        let (path, hunk) = change.rsplit_once('#')?;
+        let file = self.diffs.iter().position(|file| file.path == path)?;
+        match hunk.trim().parse::<usize>() {
+            Ok(hunk)
+                if splittable(&self.diffs[file])
+                    && (1..=units(&self.diffs[file])).contains(&hunk) =>
+            {
+                Some((file, Some(hunk - 1)))
+            }
+            _ => Some((file, None)),
+        }
+    }
+
+    fn drop_empty(&mut self) {
+        let owners = &self.owners;
+        self.commits.retain(|commit| {
+            owners
+                .iter()
+                .flatten()
+                .any(|owner| *owner == Some(commit.id))
+        });
+    }
+
+    /// What has to be staged for the commit at `position`.
+    pub fn changes(&self, position: usize) -> Vec<Change> {
+        let id = self.commits[position].id;
+        let mut changes = Vec::new();
+
+        for (file, owners) in self.diffs.iter().zip(&self.owners) {
+            let owned = owners
+                .iter()
+                .enumerate()
+                .filter(|(_, owner)| **owner == Some(id))
+                .map(|(unit, _)| unit)
+                .collect::<Vec<usize>>();
+            if owned.is_empty() {
+                continue;
+            }
+
+            changes.push(Change {
+                path: file.path.to_owned(),
+                hunks: (owned.len() < owners.len()).then_some(owned),
+            });
+        }
+
+        changes
+    }
+
+    pub fn move_commit(&mut self, from: usize, to: usize) {
+        let commit = self.commits.remove(from);
+        self.commits.insert(to.min(self.commits.len()), commit);
+    }
+
+    /// Merges the commits at `positions` into the first of them, keeping its message.
+    pub fn merge(&mut self, positions: &[usize]) {
+        let ids = positions
+            .iter()
+            .map(|position| self.commits[*position].id)
+            .collect::<Vec<usize>>();
+        let Some(target) = ids.first().copied() else {
+            return;
+        };
+
+        for owner in self.owners.iter_mut().flatten() {
+            if owner.map(|owner| ids.contains(&owner)).unwrap_or(false) {
+                *owner = Some(target);
+            }
+        }
+        self.drop_empty();
+    }
+
+    /// The hunks of a change of the commit at `position`, as `Git::stage_hunks`
+    /// expects them: each with its patch and its position among the hunks left
+    /// once the commits before it are made.
+    pub fn hunk_patches(&self, position: usize, change: &Change) -> Vec<(usize, String)> {
+        let earlier = self.commits[..position]
+            .iter()
+            .map(|commit| Some(commit.id))
+            .collect::<Vec<Option<usize>>>();
+        let file = self.diffs.iter().position(|file| file.path == change.path);
+        match (file, &change.hunks) {
+            (Some(file), Some(hunks)) => hunks
+                .iter()
+                .map(|hunk| {
+                    let committed = self.owners[file][..*hunk]
+                        .iter()
+                        .filter(|owner| earlier.contains(owner))
+                        .count();
+                    (hunk - committed, self.diffs[file].hunks[*hunk].to_owned())
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        (0..self.commits.len())
+            .map(|position| {
+                let subject = self.commits[position].message.lines().next().unwrap_or("");
+                let changes = self
+                    .changes(position)
+                    .iter()
+                    .map(|change| match &change.hunks {
+                        Some(hunks) => format!(
+                            "   {} {}",
+                            change.path,
+                            format!(
+                                "(hunks {})",
+                                hunks
+                                    .iter()
+                                    .map(|hunk| (hunk + 1).to_string())
+                                    .collect::<Vec<String>>()
+                                    .join(", ")
+                            )
+                            .bright_black()
+                        ),
+                        None => format!("   {}", change.path),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                format!(
+                    "{} {}\n{}",
+                    format!("{}.", position + 1).yellow(),
+                    subject.bright_green(),
+                    changes
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(path: &str, status: char, hunks: usize) -> FileDiff {
+        FileDiff {
+            path: path.to_owned(),
+            status,
+            additions: hunks,
+            deletions: 0,
+            binary: false,
+            hunks: (1..=hunks)
+                .map(|hunk| format!("@@ -{0} +{0} @@\n+line\n", hunk))
+                .collect(),
+        }
+    }
+
+    fn owners(plan: &Plan) -> Vec<Vec<(String, Option<Vec<usize>>)>> {
+        (0..plan.commits.len())
+            .map(|position| {
+                plan.changes(position)
+                    .into_iter()
+                    .map(|change| (change.path, change.hunks))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn assigns_files_and_hunks() {
+        let reply = r#"{"commits": [
+            {"message": "Add a", "changes": ["a.rs", "c.rs#2"]},
+            {"message": "Add b", "changes": ["b.rs", "c.rs#1", "c.rs#3"]}
+        ]}"#;
+        let diffs = vec![
+            diff("a.rs", 'A', 1),
+            diff("b.rs", 'M', 1),
+            diff("c.rs", 'M', 3),
+        ];
+        let plan = Plan::parse(reply, diffs, Convention::Plain).unwrap();
+
+        assert_eq!(
+            owners(&plan),
+            vec![
+                vec![
+                    ("a.rs".to_owned(), None),
+                    ("c.rs".to_owned(), Some(vec![1]))
+                ],
+                vec![
+                    ("b.rs".to_owned(), None),
+                    ("c.rs".to_owned(), Some(vec![0, 2]))
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn hunks_are_numbered_among_the_ones_left() {
+        let reply = r#"{"commits": [
+            {"message": "First", "changes": ["c.rs#2"]},
+            {"message": "Second", "changes": ["c.rs#1", "c.rs#3"]},
+            {"message": "Third", "changes": ["c.rs#4"]}
+        ]}"#;
+        let plan = Plan::parse(reply, vec![diff("c.rs", 'M', 4)], Convention::Plain).unwrap();
+        let positions = |position: usize| {
+            plan.changes(position)
+                .iter()
+                .flat_map(|change| plan.hunk_patches(position, change))
+                .map(|(index, patch)| (index, patch.lines().next().unwrap().to_owned()))
+                .collect::<Vec<(usize, String)>>()
+        };
+
+        assert_eq!(positions(0), vec![(1, "@@ -2 +2 @@".to_owned())]);
+        assert_eq!(
+            positions(1),
+            vec![(0, "@@ -1 +1 @@".to_owned()), (1, "@@ -3 +3 @@".to_owned())]
+        );
+        assert_eq!(positions(2), vec![(0, "@@ -4 +4 @@".to_owned())]);
+    }
+
+    #[test]
+    fn ignores_unknown_changes() {
+        let reply = r#"{"commits": [
+            {"message": "Add a", "changes": ["a.rs", "missing.rs", "missing.rs#1"]},
+            {"message": "Add nothing", "changes": ["other.rs"]}
+        ]}"#;
+        let plan = Plan::parse(reply, vec![diff("a.rs", 'A', 1)], Convention::Plain).unwrap();
+
+        assert_eq!(plan.commits.len(), 1);
+        assert_eq!(owners(&plan), vec![vec![("a.rs".to_owned(), None)]]);
+    }
+
+    #[test]
+    fn unknown_hunks_take_the_whole_file() {
+        let reply = r#"{"commits": [{"message": "Change c", "changes": ["c.rs#7"]}]}"#;
+        let plan = Plan::parse(reply, vec![diff("c.rs", 'M', 2)], Convention::Plain).unwrap();
+
+        assert_eq!(owners(&plan), vec![vec![("c.rs".to_owned(), None)]]);
+    }
+
+    #[test]
+    fn duplicate_changes_stay_with_the_first_commit() {
+        let reply = r#"{"commits": [
+            {"message": "First", "changes": ["a.rs", "c.rs#1"]},
+            {"message": "Second", "changes": ["a.rs", "c.rs", "c.rs#1"]}
+        ]}"#;
+        let diffs = vec![diff("a.rs", 'A', 1), diff("c.rs", 'M', 2)];
+        let plan = Plan::parse(reply, diffs, Convention::Plain).unwrap();
+
+        assert_eq!(
+            owners(&plan),
+            vec![
+                vec![
+                    ("a.rs".to_owned(), None),
+                    ("c.rs".to_owned(), Some(vec![0]))
+                ],
+                vec![("c.rs".to_owned(), Some(vec![1]))],
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_replies_without_a_plan() {
+        let diffs = || vec![diff("a.rs", 'A', 1)];
+        assert!(Plan::parse("no plan here", diffs(), Convention::Plain).is_err());
+        assert!(Plan::parse(r#"{"commits": 3}"#, diffs(), Convention::Plain).is_err());
+        assert!(Plan::parse(r#"{"commits": []}"#, vec![], Convention::Plain).is_err());
+    }
+
+    #[test]
+    fn remaining_changes_follow_the_convention() {
+        let reply = r#"{"commits": [{"message": "feat: add a\n\nBody", "changes": ["a.rs"]}]}"#;
+        let expected = [
+            (Convention::Gitmoji, ":wrench: Commit the remaining changes"),
+            (
+                Convention::Conventional,
+                "chore: commit the remaining changes",
+            ),
+            (
+                Convention::ConventionalGitmoji,
+                "chore: :wrench: commit the remaining changes",
+            ),
+            (Convention::Plain, "Commit the remaining changes"),
+        ];
+        for (convention, message) in expected {
+            let diffs = vec![diff("a.rs", 'A', 1), diff("b.rs", 'A', 1)];
+            let plan = Plan::parse(reply, diffs, convention).unwrap();
+            assert_eq!(plan.commits.len(), 2);
+            assert_eq!(plan.commits[1].message, message);
+        }
+    }
+}
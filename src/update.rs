@@ -0,0 +1,624 @@
+use colored::Colorize;
+use futures_util::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::utils::{get_config, user_config_path};
+
+/// Releases in the format of the GitHub releases API, which mirrors can serve as well.
+const RELEASES_URL: &str =
+    "https://api.github.com/repos/DerTyp7214/gpt-commit-rust/releases?per_page=100";
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Deserialize)]
+pub struct Release {
+    tag_name: String,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    fn asset_url(&self, name: &str) -> Result<&str, String> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.browser_download_url.as_str())
+            .ok_or(format!("Version {} has no {}", self.version(), name))
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct UpdateCache {
+    /// Unix time of the last check.
+    checked: u64,
+    latest: Option<String>,
+}
+
+/// The name of the release asset for this platform.
+fn asset_name() -> Result<&'static str, String> {
+    if cfg!(windows) {
+        Ok("gpt-commit-rust-Windows.exe")
+    } else if cfg!(target_os = "macos") {
+        Ok("gpt-commit-rust-macOS")
+    } else if cfg!(target_os = "linux") {
+        Ok("gpt-commit-rust-Linux")
+    } else {
+        Err("Unsupported OS".to_owned())
+    }
+}
+
+/// Where the version before the last update or rollback is kept.
+fn previous_path(current_exe: &Path) -> PathBuf {
+    let mut name = current_exe.file_name().unwrap_or_default().to_owned();
+    name.push(".previous");
+    current_exe.with_file_name(name)
+}
+
+impl UpdateCache {
+    /// Whether the last check is at least `interval` before `now`.
+    fn is_due(&self, interval: Duration, now: u64) -> bool {
+        now.saturating_sub(self.checked) >= interval.as_secs()
+    }
+
+    /// Whether the last check found a version newer than `current`.
+    fn has_update(&self, current: &str) -> bool {
+        self.latest
+            .as_deref()
+            .is_some_and(|latest| is_newer(latest, current))
+    }
+}
+
+fn update_cache_path() -> PathBuf {
+    user_config_path().with_file_name("update-check.toml")
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Orders versions like `1.2.3` and `1.3.0-beta.1`, prereleases before the
+/// release they lead up to.
+pub fn compare_versions(left: &str, right: &str) -> Ordering {
+    let parse = |version: &str| {
+        let version = version.trim_start_matches('v');
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, Some(pre.to_owned())),
+            None => (version, None),
+        };
+        let core = core
+            .split('.')
+            .map(|part| part.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<u64>>();
+        (core, pre)
+    };
+    let (left_core, left_pre) = parse(left);
+    let (right_core, right_pre) = parse(right);
+
+    left_core
+        .cmp(&right_core)
+        .then_with(|| match (left_pre, right_pre) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(left), Some(right)) => {
+                let identifiers = |pre: &str| {
+                    pre.split('.')
+                        .map(|part| match part.parse::<u64>() {
+                            Ok(number) => (0, number, String::new()),
+                            Err(_) => (1, 0, part.to_owned()),
+                        })
+                        .collect::<Vec<(u8, u64, String)>>()
+                };
+                identifiers(&left).cmp(&identifiers(&right))
+            }
+        })
+}
+
+/// Whether `latest` is a higher version than `current`.
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    compare_versions(latest, current) == Ordering::Greater
+}
+
+fn client(timeout: Option<Duration>) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        // the GitHub API rejects requests without one
+        .user_agent(concat!("gpt-commit-rust/", env!("CARGO_PKG_VERSION")));
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder.build().map_err(|err| err.to_string())
+}
+
+async fn fetch_releases(client: &reqwest::Client) -> Result<Vec<Release>, String> {
+    let url = get_config()
+        .get_setting("update.releases_url")
+        .unwrap_or(RELEASES_URL.to_owned());
+    let mut releases = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("Failed to fetch the releases: {}", err))?
+        .json::<Vec<Release>>()
+        .await
+        .map_err(|err| format!("Failed to read the releases: {}", err))?;
+
+    releases.retain(|release| !release.draft);
+    releases.sort_by(|left, right| compare_versions(right.version(), left.version()));
+    Ok(releases)
+}
+
+/// Every published release, the newest first.
+pub async fn releases() -> Result<Vec<Release>, String> {
+    fetch_releases(&client(None)?).await
+}
+
+/// The newest release of the configured channel.
+pub fn latest<'a>(releases: &'a [Release], channel: &str) -> Option<&'a Release> {
+    releases
+        .iter()
+        .find(|release| channel == "prerelease" || !release.prerelease)
+}
+
+/// Fetches the version of the latest release of the configured channel.
+pub async fn latest_version() -> Result<String, String> {
+    let releases = fetch_releases(&client(Some(CHECK_TIMEOUT))?).await?;
+    latest(&releases, &get_config().get_update_channel())
+        .map(|release| release.version().to_owned())
+        .ok_or("No release found".to_owned())
+}
+
+/// The notes of the releases after `current` up to `target`, oldest first.
+pub fn release_notes(releases: &[Release], current: &str, target: &str) -> String {
+    releases
+        .iter()
+        .rev()
+        .filter(|release| {
+            is_newer(release.version(), current)
+                && compare_versions(release.version(), target) != Ordering::Greater
+        })
+        .map(|release| {
+            let body = release
+                .body
+                .as_deref()
+                .unwrap_or("")
+                .trim()
+                .replace("\r\n", "\n");
+            format!(
+                "{}\n{}",
+                release.version().bright_magenta(),
+                if body.is_empty() {
+                    "No release notes"
+                } else {
+                    &body
+                }
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+fn save_update_cache(cache: &UpdateCache) {
+    let path = update_cache_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).ok();
+    }
+    fs::write(path, toml::to_string(cache).unwrap()).ok();
+}
+
+/// Remembers the latest version, so the next runs don't have to ask again.
+pub fn remember_latest_version(latest: &str) {
+    save_update_cache(&UpdateCache {
+        checked: unix_time(),
+        latest: Some(latest.to_owned()),
+    });
+}
+
+/// Whether the last check found a newer version. Never waits for the network:
+/// when the last check is older than the interval, a new one runs in the
+/// background and its result shows up on a later run.
+pub fn check_for_update() -> bool {
+    // left behind by versions that renamed the running executable
+    let old_executable = std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("gpt-commit-rust-old")));
+    if let Some(old_executable) = old_executable.filter(|path| path.exists()) {
+        fs::remove_file(old_executable).ok();
+    }
+
+    let config = get_config();
+    if !config.get_update_check() {
+        return false;
+    }
+
+    let cache = fs::read_to_string(update_cache_path())
+        .ok()
+        .and_then(|cache| toml::from_str::<UpdateCache>(&cache).ok())
+        .unwrap_or_default();
+
+    if cache.is_due(config.get_update_interval(), unix_time()) {
+        // the check is remembered before it is made, as most runs end before the
+        // request does, and failed checks count as well, so being offline doesn't
+        // mean a request every run
+        save_update_cache(&UpdateCache {
+            checked: unix_time(),
+            latest: cache.latest.to_owned(),
+        });
+        tokio::spawn(async move {
+            if let Ok(latest) = latest_version().await {
+                remember_latest_version(&latest);
+            }
+        });
+    }
+
+    cache.has_update(env!("CARGO_PKG_VERSION"))
+}
+
+/// The published SHA-256 of an asset, from a `sha256sum` style file.
+async fn expected_checksum(
+    client: &reqwest::Client,
+    release: &Release,
+    asset: &str,
+) -> Result<String, String> {
+    let checksum = client
+        .get(release.asset_url(&format!("{}.sha256", asset))?)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("Failed to download the checksum: {}", err))?
+        .text()
+        .await
+        .map_err(|err| format!("Failed to download the checksum: {}", err))?;
+
+    checksum
+        .split_whitespace()
+        .next()
+        .filter(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|hash| hash.to_lowercase())
+        .ok_or("The release has an invalid checksum file".to_owned())
+}
+
+/// Downloads `url` to `path` and returns its SHA-256.
+async fn download(client: &reqwest::Client, url: &str, path: &Path) -> Result<String, String> {
+    let update = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("Failed to download the update: {}", err))?;
+
+    let progress_bar = match update.content_length() {
+        Some(total_size) => ProgressBar::new(total_size),
+        None => ProgressBar::new_spinner(),
+    };
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{bar:38.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("=>-"),
+    );
+
+    let mut hasher = Sha256::new();
+    let mut file = File::create(path).map_err(|err| err.to_string())?;
+    let mut stream = update.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|err| format!("Failed to download the update: {}", err))?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).map_err(|err| err.to_string())?;
+        progress_bar.inc(chunk.len() as u64);
+    }
+    file.sync_all().map_err(|err| err.to_string())?;
+    progress_bar.finish();
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Puts `new` in place of the running executable and keeps the running one
+/// as the previous version.
+fn replace_executable(current_exe: &Path, new: &Path) -> Result<(), String> {
+    let previous = previous_path(current_exe);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(new, fs::Permissions::from_mode(0o755))
+            .map_err(|err| err.to_string())?;
+        fs::copy(current_exe, &previous)
+            .map_err(|err| format!("Failed to keep the current version: {}", err))?;
+        // a rename within a directory replaces the file in one step
+        fs::rename(new, current_exe)
+            .map_err(|err| format!("Failed to install the update: {}", err))?;
+    }
+
+    #[cfg(not(unix))]
+    {
+        // the running executable can't be overwritten, but it can be renamed
+        if previous.exists() {
+            fs::remove_file(&previous)
+                .map_err(|err| format!("Failed to remove {}: {}", previous.display(), err))?;
+        }
+        fs::rename(current_exe, &previous)
+            .map_err(|err| format!("Failed to keep the current version: {}", err))?;
+        if let Err(err) = fs::rename(new, current_exe) {
+            fs::rename(&previous, current_exe).ok();
+            return Err(format!("Failed to install the update: {}", err));
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads a release, verifies it against its published SHA-256 and
+/// installs it in place of `current_exe`.
+pub async fn download_update(release: &Release, current_exe: &Path) -> Result<(), String> {
+    let asset = asset_name()?;
+    let url = release.asset_url(asset)?;
+    let mut download_name = current_exe.file_name().unwrap_or_default().to_owned();
+    download_name.push(".download");
+    // next to the executable, so it can be renamed into place
+    let download_path = current_exe.with_file_name(download_name);
+
+    let client = client(None)?;
+    let expected = expected_checksum(&client, release, asset).await?;
+
+    let result = download(&client, url, &download_path)
+        .await
+        .and_then(|actual| {
+            if actual != expected {
+                return Err(format!(
+                    "The download doesn't match the published checksum (expected {}, got {})",
+                    expected, actual
+                ));
+            }
+            println!("\n{}\n", "Checksum verified. Installing...".bright_green());
+            replace_executable(current_exe, &download_path)
+        });
+
+    if result.is_err() {
+        fs::remove_file(&download_path).ok();
+    }
+    result
+}
+
+/// Swaps `current_exe` with the version kept by the last update, so a second
+/// rollback undoes the first.
+pub fn rollback(current_exe: &Path) -> Result<(), String> {
+    let previous = previous_path(current_exe);
+    if !previous.exists() {
+        return Err("No previous version to roll back to".to_owned());
+    }
+
+    let mut swap_name = current_exe.file_name().unwrap_or_default().to_owned();
+    swap_name.push(".swap");
+    let swap = current_exe.with_file_name(swap_name);
+
+    fs::rename(current_exe, &swap).map_err(|err| err.to_string())?;
+    if let Err(err) = fs::rename(&previous, current_exe) {
+        fs::rename(&swap, current_exe).ok();
+        return Err(format!("Failed to restore the previous version: {}", err));
+    }
+    fs::rename(&swap, &previous).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    const NEW_VERSION: &[u8] = b"new version";
+
+    fn release(tag: &str, prerelease: bool) -> Release {
+        Release {
+            tag_name: tag.to_owned(),
+            body: None,
+            prerelease,
+            draft: false,
+            assets: vec![],
+        }
+    }
+
+    #[test]
+    fn orders_release_versions() {
+        assert_eq!(compare_versions("1.2.3", "1.2.3"), Ordering::Equal);
+        assert_eq!(compare_versions("v1.2.3", "1.2.3"), Ordering::Equal);
+        assert_eq!(compare_versions("1.10.0", "1.9.9"), Ordering::Greater);
+        assert_eq!(compare_versions("2.0", "1.99.99"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2", "1.2.1"), Ordering::Less);
+    }
+
+    #[test]
+    fn orders_prereleases_before_their_release() {
+        assert_eq!(compare_versions("1.3.0-beta.1", "1.3.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.3.0-beta.1", "1.2.9"), Ordering::Greater);
+        assert_eq!(
+            compare_versions("1.3.0-alpha", "1.3.0-beta"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_versions("1.3.0-beta.2", "1.3.0-beta.10"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_versions("1.3.0-beta.2", "1.3.0-beta"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_versions("1.3.0-rc.1", "1.3.0-beta.9"),
+            Ordering::Greater
+        );
+        assert!(is_newer("1.3.0", "1.3.0-rc.1"));
+        assert!(!is_newer("1.3.0-rc.1", "1.3.0"));
+    }
+
+    #[test]
+    fn picks_the_latest_release_of_the_channel() {
+        let releases = vec![
+            release("v1.3.0-beta.1", true),
+            release("v1.2.0", false),
+            release("v1.1.0", false),
+        ];
+        assert_eq!(latest(&releases, "stable").unwrap().version(), "1.2.0");
+        assert_eq!(
+            latest(&releases, "prerelease").unwrap().version(),
+            "1.3.0-beta.1"
+        );
+        assert!(latest(&releases[..1], "stable").is_none());
+    }
+
+    #[test]
+    fn checks_again_after_the_interval() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let cache = UpdateCache {
+            checked: 1_000_000,
+            latest: None,
+        };
+        assert!(!cache.is_due(day, 1_000_000));
+        assert!(!cache.is_due(day, 1_000_000 + day.as_secs() - 1));
+        assert!(cache.is_due(day, 1_000_000 + day.as_secs()));
+        assert!(UpdateCache::default().is_due(day, 1_000_000));
+    }
+
+    #[test]
+    fn reports_the_cached_update() {
+        let cache = |latest: Option<&str>| UpdateCache {
+            checked: 0,
+            latest: latest.map(str::to_owned),
+        };
+        assert!(cache(Some("1.3.0")).has_update("1.2.9"));
+        assert!(!cache(Some("1.2.9")).has_update("1.2.9"));
+        assert!(!cache(None).has_update("1.2.9"));
+
+        let saved = toml::to_string(&cache(Some("1.3.0"))).unwrap();
+        assert!(toml::from_str::<UpdateCache>(&saved)
+            .unwrap()
+            .has_update("1.2.9"));
+        // caches of older versions only have the time
+        assert!(toml::from_str::<UpdateCache>("checked = 5").is_ok());
+    }
+
+    /// Serves the binary and the checksum file of a release on a local port,
+    /// in place of GitHub.
+    fn serve_release(checksum: String) -> Release {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request_line).unwrap();
+                // the rest of the request doesn't matter
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let body = if request_line.contains(".sha256") {
+                    checksum.as_bytes()
+                } else {
+                    NEW_VERSION
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+
+        let asset = |name: String| Asset {
+            browser_download_url: format!("http://{}/{}", address, name),
+            name,
+        };
+        Release {
+            assets: vec![
+                asset(asset_name().unwrap().to_owned()),
+                asset(format!("{}.sha256", asset_name().unwrap())),
+            ],
+            ..release("v9.0.0", false)
+        }
+    }
+
+    /// An executable in a directory of its own.
+    fn install(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gpt-commit-update-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("gpt-commit-rust");
+        fs::write(&exe, "old version").unwrap();
+        exe
+    }
+
+    #[tokio::test]
+    async fn keeps_the_executable_on_a_checksum_mismatch() {
+        let exe = install("mismatch");
+        let release = serve_release(format!("{}  gpt-commit-rust", "0".repeat(64)));
+
+        let err = download_update(&release, &exe).await.unwrap_err();
+        assert!(err.contains("checksum"), "{}", err);
+        assert_eq!(fs::read(&exe).unwrap(), b"old version");
+        assert!(!previous_path(&exe).exists());
+        assert_eq!(fs::read_dir(exe.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn installs_the_update_and_rolls_back() {
+        let exe = install("swap");
+        let checksum = format!("{:x}", Sha256::digest(NEW_VERSION));
+        let release = serve_release(format!("{}  gpt-commit-rust", checksum));
+
+        download_update(&release, &exe).await.unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), NEW_VERSION);
+        assert_eq!(fs::read(previous_path(&exe)).unwrap(), b"old version");
+
+        rollback(&exe).unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), b"old version");
+        assert_eq!(fs::read(previous_path(&exe)).unwrap(), NEW_VERSION);
+
+        // a second rollback undoes the first
+        rollback(&exe).unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), NEW_VERSION);
+        fs::remove_dir_all(exe.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn rollback_needs_a_previous_version() {
+        let exe = install("no-previous");
+        assert!(rollback(&exe).is_err());
+        assert_eq!(fs::read(&exe).unwrap(), b"old version");
+        fs::remove_dir_all(exe.parent().unwrap()).ok();
+    }
+}
@@ -1,320 +1,1523 @@
-use colored::Colorize;
-use futures_util::StreamExt;
-use std::{
-    fs::{self, File},
-    io::Write,
-    io::{self},
-    path::{Path, PathBuf},
-    process::Command,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
-
-use indicatif::{ProgressBar, ProgressStyle};
-use lazy_static::lazy_static;
-use serde::{Deserialize, Serialize};
-use crate::gpt_api::MODEL_NAME;
-
-const FRAMES: [&str; 12] = [
-    "🕐", "🕑", "🕒", "🕓", "🕔", "🕕", "🕖", "🕗", "🕘", "🕙", "🕚", "🕛",
-];
-
-lazy_static! {
-    static ref LAST_FRAME: Mutex<std::time::Instant> = Mutex::new(std::time::Instant::now());
-    static ref FRAME_INDEX: Mutex<usize> = Mutex::new(0);
-}
-
-fn loading_str(line: &str, index: Option<usize>) -> String {
-    let mut frame_index = FRAME_INDEX.lock().unwrap();
-    let mut last_frame = LAST_FRAME.lock().unwrap();
-
-    if *frame_index >= FRAMES.len() {
-        *frame_index = 0;
-    }
-
-    let f_index = if last_frame.elapsed().as_millis() > 100 {
-        index.unwrap_or_else(|| {
-            *frame_index += 1;
-            *frame_index - 1
-        })
-    } else {
-        index.unwrap_or(*frame_index)
-    };
-
-    if last_frame.elapsed().as_millis() > 100 {
-        *last_frame = std::time::Instant::now();
-    }
-
-    line.replace("[ ]", FRAMES[f_index])
-}
-
-pub fn terminal_width() -> usize {
-    let (width, _) = term_size::dimensions().unwrap_or((80, 24));
-    width
-}
-
-#[derive(Deserialize, Serialize)]
-pub struct Config {
-    pub api_key: Option<String>,
-    pub port: Option<i32>,
-    pub model_name: Option<String>,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Self { api_key: None, port: None, model_name: None }
-    }
-}
-
-fn app_dir() -> PathBuf {
-    let mut dir = std::env::current_exe().unwrap();
-    dir.pop();
-    dir.push(".gpt-commit-rust");
-    dir
-}
-
-fn config_path() -> String {
-    let mut dir = app_dir();
-    dir.push("config.toml");
-    dir.to_str().unwrap().to_owned()
-}
-
-impl Config {
-    pub fn save(&self) {
-        let config = toml::to_string(self).unwrap();
-        let dir = app_dir();
-        if !dir.exists() {
-            std::fs::create_dir_all(&dir).unwrap();
-        }
-        let mut file = File::create(config_path()).unwrap();
-        file.write_all(config.as_bytes()).unwrap();
-    }
-
-    pub fn set_api_key(&mut self, api_key: String) {
-        self.api_key = Some(api_key);
-    }
-
-    pub fn set_port(&mut self, port: Option<i32>) {
-        self.port = port;
-    }
-
-    pub fn set_model_name(&mut self, model_name: String) {
-        self.model_name = Some(model_name);
-    }
-
-    pub fn get_api_key(&self) -> String {
-        self.api_key.to_owned().unwrap_or(
-            std::env::var("CHAT_GPT_TOKEN")
-                .unwrap_or_else(|_| "".to_owned())
-                .to_owned(),
-        )
-    }
-
-    pub fn get_port(&self, fallback: i32) -> i32 {
-        self.port.unwrap_or(fallback)
-    }
-
-    pub fn get_model_name(&self) -> String {
-        self.model_name.to_owned().unwrap_or(MODEL_NAME.to_string())
-    }
-}
-
-pub fn get_config() -> Config {
-    let config =
-        toml::from_str::<Config>(&std::fs::read_to_string(config_path()).unwrap_or_else(|_| {
-            let config = &mut Config::default();
-            let api_key = std::env::var("CHAT_GPT_TOKEN");
-            if api_key.is_ok() {
-                let api_key = api_key.unwrap();
-                config.set_api_key(api_key);
-                config.save();
-            }
-            toml::to_string(&config).unwrap()
-        }))
-        .unwrap();
-    config
-}
-
-pub fn get_executable_name() -> String {
-    std::env::current_exe()
-        .unwrap()
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_owned()
-}
-
-pub async fn download_update() -> Result<(), String> {
-    let update_url = if cfg!(windows) {
-        "https://github.com/DerTyp7214/gpt-commit-rust/releases/latest/download/gpt-commit-rust-Windows.exe"
-    } else if cfg!(target_os = "macos") {
-        "https://github.com/DerTyp7214/gpt-commit-rust/releases/latest/download/gpt-commit-rust-Linux"
-    } else if cfg!(target_os = "linux") {
-        "https://github.com/DerTyp7214/gpt-commit-rust/releases/latest/download/gpt-commit-rust-macOS"
-    } else {
-        return Err("Unsupported OS".to_owned());
-    };
-
-    let update_file_path = if cfg!(windows) {
-        Path::new(app_dir().as_os_str()).join("gpt-commit-rust-update.exe")
-    } else {
-        Path::new(app_dir().as_os_str()).join("gpt-commit-rust-update")
-    };
-    let client = reqwest::Client::new();
-    let update = client
-        .get(update_url)
-        .send()
-        .await
-        .or(Err(
-            "Failed to download update. Please try again later or download the update manually.",
-        ))
-        .unwrap();
-
-    let total_size = update.content_length().unwrap();
-
-    let progress_bar = ProgressBar::new(total_size);
-    progress_bar.set_style(
-        ProgressStyle::default_bar()
-            .template("[{bar:38.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=>-"),
-    );
-    progress_bar.set_message("Downloading update");
-
-    let mut downloaded = 0;
-    let mut stream = update.bytes_stream();
-    let mut update_file = File::create(update_file_path.to_owned()).unwrap();
-
-    while let Some(item) = stream.next().await {
-        let item = item.unwrap();
-        downloaded += item.len();
-        progress_bar.set_position(downloaded as u64);
-        update_file.write_all(&item).unwrap();
-    }
-
-    progress_bar.finish();
-
-    println!("\n{}\n", "Downloaded update. Installing...".bright_green());
-
-    let current_exe = &std::env::current_exe().unwrap();
-    let current_dir = current_exe.clone();
-    let current_dir = current_dir.parent().unwrap();
-
-    let res = fs::rename(current_exe, current_dir.join("gpt-commit-rust-old"));
-
-    if res.is_err() {
-        println!("Failed to rename current executable. Please rename it manually to \"gpt-commit-rust-old\".");
-        return Ok(());
-    }
-
-    let res = fs::rename(update_file_path.to_owned(), current_exe);
-
-    if res.is_err() {
-        println!(
-            "Failed to rename update executable. Please rename it manually to \"{}\".",
-            get_executable_name()
-        );
-        return Ok(());
-    }
-
-    if cfg!(unix) {
-        Command::new("chmod")
-            .arg("+x")
-            .arg(update_file_path.to_owned().as_os_str())
-            .spawn()
-            .unwrap()
-            .wait()
-            .unwrap();
-    }
-
-    Ok(())
-}
-
-#[derive(Debug, Deserialize)]
-struct CargoPackage {
-    version: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct CargoToml {
-    package: CargoPackage,
-}
-
-pub async fn check_for_update() -> bool {
-    const VERSION: &str = env!("CARGO_PKG_VERSION");
-
-    let update_url =
-        "https://github.com/DerTyp7214/gpt-commit-rust/releases/latest/download/Cargo.toml";
-
-    let update = reqwest::get(update_url)
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-
-    let toml = toml::from_str::<CargoToml>(&update);
-
-    if toml.is_err() {
-        return false;
-    }
-
-    let old_executable = std::env::current_exe().unwrap();
-    let old_executable = old_executable.parent().unwrap();
-    let old_executable = old_executable.join("gpt-commit-rust-old");
-    if old_executable.exists() {
-        fs::remove_file(old_executable).unwrap();
-    }
-
-    let toml = toml.unwrap();
-
-    toml.package.version != VERSION
-}
-
-pub struct Loader {
-    loading: Arc<Mutex<bool>>,
-}
-
-impl Loader {
-    pub fn new(message: &str) -> Self {
-        let loading: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
-        let loading_clone = Arc::clone(&loading);
-        let message = Arc::new(Mutex::new(message.to_owned()));
-
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_millis(100));
-            let mut stdout = io::stdout();
-            loop {
-                let is_loading = {
-                    let guard = loading_clone.lock().unwrap();
-                    *guard
-                };
-
-                if is_loading {
-                    let total_message =
-                        loading_str(&format!("[ ] {}", message.lock().unwrap()), None);
-                    let message_len = total_message.len();
-                    let spaces = terminal_width() - message_len;
-
-                    print!("\r{}{}", total_message, " ".repeat(spaces + 2));
-                } else {
-                    break;
-                }
-
-                interval.tick().await;
-                stdout.flush().unwrap();
-            }
-        });
-
-        Self { loading }
-    }
-
-    pub fn stop(&self) {
-        let mut guard = self.loading.lock().unwrap();
-        *guard = false;
-        io::stdout().flush().unwrap();
-        print!("\r{}", " ".repeat(terminal_width()));
-        print!("\r");
-    }
-}
+use colored::Colorize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    io::{self},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::budget;
+use crate::convention::Convention;
+use crate::gpt_api::{DEFAULT_BASE_URL, MODEL_NAME};
+use crate::providers::{self, AUTH_STYLES, PROVIDERS, RESPONSE_FORMATS};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+const FRAMES: [&str; 12] = [
+    "🕐", "🕑", "🕒", "🕓", "🕔", "🕕", "🕖", "🕗", "🕘", "🕙", "🕚", "🕛",
+];
+
+lazy_static! {
+    static ref LAST_FRAME: Mutex<std::time::Instant> = Mutex::new(std::time::Instant::now());
+    static ref FRAME_INDEX: Mutex<usize> = Mutex::new(0);
+}
+
+fn loading_str(line: &str, index: Option<usize>) -> String {
+    let mut frame_index = FRAME_INDEX.lock().unwrap();
+    let mut last_frame = LAST_FRAME.lock().unwrap();
+
+    if *frame_index >= FRAMES.len() {
+        *frame_index = 0;
+    }
+
+    let f_index = if last_frame.elapsed().as_millis() > 100 {
+        index.unwrap_or_else(|| {
+            *frame_index += 1;
+            *frame_index - 1
+        })
+    } else {
+        index.unwrap_or(*frame_index)
+    };
+
+    if last_frame.elapsed().as_millis() > 100 {
+        *last_frame = std::time::Instant::now();
+    }
+
+    line.replace("[ ]", FRAMES[f_index])
+}
+
+pub fn terminal_width() -> usize {
+    let (width, _) = term_size::dimensions().unwrap_or((80, 24));
+    width
+}
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+pub(crate) const PROFILE_KEYS: [&str; 11] = [
+    "base_url",
+    "path",
+    "model",
+    "context_tokens",
+    "provider",
+    "api_key",
+    "api_key_command",
+    "api_key_env",
+    "auth",
+    "response_format",
+    "header.<name>",
+];
+
+/// Parts of header names whose values are as secret as the api key.
+const SECRET_HEADERS: [&str; 4] = ["auth", "key", "token", "secret"];
+
+lazy_static! {
+    static ref SELECTED_PROFILE: Mutex<Option<String>> = Mutex::new(None);
+    static ref SELECTED_CONVENTION: Mutex<Option<Convention>> = Mutex::new(None);
+    static ref INTERACTION: Mutex<Interaction> = Mutex::new(Interaction::Ask);
+    static ref COMMAND_KEYS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+}
+
+/// Selects the profile used for the rest of the process instead of the default one.
+pub fn select_profile(name: &str) {
+    *SELECTED_PROFILE.lock().unwrap() = Some(name.to_owned());
+}
+
+/// Selects the commit convention used for the rest of the process.
+pub fn select_convention(convention: Convention) {
+    *SELECTED_CONVENTION.lock().unwrap() = Some(convention);
+}
+
+/// How the questions of a run are answered.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Interaction {
+    Ask,
+    /// Every question gets its default answer, like running the commands.
+    Yes,
+    /// Questions that need an answer abort the run.
+    Never,
+}
+
+pub fn select_interaction(interaction: Interaction) {
+    *INTERACTION.lock().unwrap() = interaction;
+}
+
+pub fn get_interaction() -> Interaction {
+    *INTERACTION.lock().unwrap()
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct Config {
+    #[serde(skip_serializing)]
+    pub api_key: Option<String>,
+    #[serde(skip_serializing)]
+    pub port: Option<i32>,
+    #[serde(skip_serializing)]
+    pub model_name: Option<String>,
+    #[serde(skip_serializing)]
+    pub provider: Option<String>,
+    pub default_profile: Option<String>,
+    pub convention: Option<String>,
+    pub update: Option<UpdateSettings>,
+    pub pr: Option<PrSettings>,
+    pub profiles: Option<BTreeMap<String, Profile>>,
+    /// Values of the `GPT_COMMIT_*` variables, applied to the active profile.
+    #[serde(skip)]
+    overrides: Profile,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct UpdateSettings {
+    pub check: Option<bool>,
+    pub interval_hours: Option<u64>,
+    pub channel: Option<String>,
+    pub releases_url: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct PrSettings {
+    /// The branch pull requests go into.
+    pub base: Option<String>,
+}
+
+/// A named endpoint the requests are sent to.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct Profile {
+    pub base_url: Option<String>,
+    pub path: Option<String>,
+    pub model: Option<String>,
+    pub context_tokens: Option<usize>,
+    pub provider: Option<String>,
+    pub api_key: Option<String>,
+    pub api_key_command: Option<String>,
+    pub api_key_env: Option<String>,
+    pub auth: Option<String>,
+    pub response_format: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+const APP_NAME: &str = "gpt-commit-rust";
+const UPDATE_INTERVAL_HOURS: u64 = 24;
+
+/// Keys outside of the profiles.
+pub(crate) const SETTING_KEYS: [&str; 5] = [
+    "update.check",
+    "update.interval_hours",
+    "update.channel",
+    "update.releases_url",
+    "pr.base",
+];
+
+pub(crate) const UPDATE_CHANNELS: [&str; 2] = ["stable", "prerelease"];
+const REPO_CONFIG: &str = ".gpt-commit.toml";
+const ENV_PREFIX: &str = "GPT_COMMIT_";
+
+/// Keys a repository can't set, as they decide where requests and keys are
+/// sent or where updates come from.
+const USER_ONLY_KEYS: [&str; 9] = [
+    "base_url",
+    "path",
+    "provider",
+    "api_key",
+    "api_key_command",
+    "api_key_env",
+    "update.releases_url",
+    "auth",
+    "header.<name>",
+];
+
+/// The directory next to the executable, where older versions kept their config.
+fn app_dir() -> PathBuf {
+    let mut dir = std::env::current_exe().unwrap();
+    dir.pop();
+    dir.push(".gpt-commit-rust");
+    dir
+}
+
+/// A per user directory, following the XDG base directories and using the
+/// given variable on Windows.
+fn user_dir(xdg_var: &str, fallback: &str, windows_var: &str) -> PathBuf {
+    let from_var = |var: &str| {
+        std::env::var_os(var)
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+    };
+    let base = if cfg!(windows) {
+        from_var(windows_var)
+    } else {
+        from_var(xdg_var)
+    };
+    match base.or_else(|| from_var("HOME").map(|home| home.join(fallback))) {
+        Some(base) => base.join(APP_NAME),
+        None => app_dir(),
+    }
+}
+
+pub fn cache_dir() -> PathBuf {
+    user_dir("XDG_CACHE_HOME", ".cache", "LOCALAPPDATA")
+}
+
+pub fn user_config_path() -> PathBuf {
+    user_dir("XDG_CONFIG_HOME", ".config", "APPDATA").join("config.toml")
+}
+
+/// `.gpt-commit.toml` in the root of the repository in the current directory.
+pub fn repo_config_path() -> Option<PathBuf> {
+    let repo = git2::Repository::discover(std::env::current_dir().ok()?).ok()?;
+    Some(repo.workdir()?.join(REPO_CONFIG))
+}
+
+/// Where a setting comes from.
+#[derive(Clone)]
+pub enum Origin {
+    User(PathBuf),
+    Repo(PathBuf),
+    Env(String),
+    CommandLine,
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::User(path) | Origin::Repo(path) => write!(f, "file:{}", path.display()),
+            Origin::Env(var) => write!(f, "env:{}", var),
+            Origin::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+fn env_var(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase().replace('.', "_"))
+}
+
+/// Whether a variable may come from a `.env` file, which usually comes with the
+/// repository just like `.gpt-commit.toml`.
+fn dotenv_allowed(var: &str) -> bool {
+    !USER_ONLY_KEYS.iter().any(|key| env_var(key) == var) && !var.starts_with(&env_var("header."))
+}
+
+/// Loads the `.env` file of the current directory, without the variables of
+/// the keys a repository can't set. Variables that are set already win.
+pub fn load_dotenv() {
+    // the replacement, `from_path`, sets every variable right away
+    #[allow(deprecated)]
+    let entries = match dotenv::dotenv_iter() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut dropped = Vec::new();
+    for (var, value) in entries.flatten() {
+        if !dotenv_allowed(&var) {
+            dropped.push(var);
+        } else if std::env::var_os(&var).is_none() {
+            std::env::set_var(var, value);
+        }
+    }
+    if !dropped.is_empty() {
+        warn_once(format!(
+            ".env can't set {}, they are ignored",
+            dropped.join(", ")
+        ));
+    }
+}
+
+/// Moves the config from next to the executable to the user config directory.
+fn migrate_location() {
+    let old = app_dir().join("config.toml");
+    let new = user_config_path();
+    if new.exists() || !old.exists() {
+        return;
+    }
+
+    if let Some(dir) = new.parent() {
+        if fs::create_dir_all(dir).is_err() {
+            return;
+        }
+    }
+    if fs::copy(&old, &new).is_ok() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&new, fs::Permissions::from_mode(0o600)).ok();
+        }
+        fs::remove_file(&old).ok();
+        eprintln!(
+            "{} {}",
+            "Moved the config to".bright_black(),
+            new.display().to_string().bright_black()
+        );
+    }
+}
+
+fn read_config(path: &Path) -> Result<Config, String> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            toml::from_str::<Config>(&content).map_err(|err| format!("{}: {}", path.display(), err))
+        }
+        Err(_) => Ok(Config::default()),
+    }
+}
+
+fn config_failed(err: String) -> ! {
+    println!("{} {}", "Error:".red(), err);
+    std::process::exit(1);
+}
+
+impl Config {
+    /// Writes the config, readable only by the owner as it can contain keys.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let config = toml::to_string(self).unwrap();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // files written by older versions keep their mode otherwise
+            if path.exists() {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                    .map_err(|err| format!("{}: {}", path.display(), err))?;
+            }
+        }
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(config.as_bytes()))
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn set_default_profile(&mut self, name: String) {
+        self.default_profile = Some(name);
+    }
+
+    pub fn has_profile(&self, name: &str) -> bool {
+        self.profiles
+            .as_ref()
+            .map(|profiles| profiles.contains_key(name))
+            .unwrap_or(false)
+    }
+
+    pub fn get_profiles(&self) -> BTreeMap<String, Profile> {
+        self.profiles.to_owned().unwrap_or_default()
+    }
+
+    pub fn get_default_profile(&self) -> String {
+        self.default_profile
+            .to_owned()
+            .unwrap_or(DEFAULT_PROFILE.to_owned())
+    }
+
+    pub fn set_convention(&mut self, convention: Convention) {
+        self.convention = Some(convention.name().to_owned());
+    }
+
+    pub fn get_update_check(&self) -> bool {
+        self.get_setting("update.check")
+            .map(|check| check == "true")
+            .unwrap_or(true)
+    }
+
+    pub fn get_update_interval(&self) -> Duration {
+        let hours = self
+            .update
+            .as_ref()
+            .and_then(|update| update.interval_hours)
+            .unwrap_or(UPDATE_INTERVAL_HOURS);
+        Duration::from_secs(hours * 60 * 60)
+    }
+
+    /// `stable`, or `prerelease` to get prereleases as well.
+    pub fn get_update_channel(&self) -> String {
+        self.get_setting("update.channel")
+            .unwrap_or(UPDATE_CHANNELS[0].to_owned())
+    }
+
+    /// The branch pull requests go into, if one is set.
+    pub fn get_pr_base(&self) -> Option<String> {
+        self.get_setting("pr.base")
+    }
+
+    /// A value of the `update` or the `pr` table.
+    pub fn get_setting(&self, key: &str) -> Option<String> {
+        if key == "pr.base" {
+            return self.pr.as_ref()?.base.to_owned();
+        }
+        let update = self.update.as_ref()?;
+        match key {
+            "update.check" => update.check.map(|check| check.to_string()),
+            "update.interval_hours" => update.interval_hours.map(|hours| hours.to_string()),
+            "update.channel" => update.channel.to_owned(),
+            "update.releases_url" => update.releases_url.to_owned(),
+            _ => None,
+        }
+    }
+
+    pub fn set_setting(&mut self, key: &str, value: Option<String>) -> Result<(), String> {
+        if key == "pr.base" {
+            self.pr = value.map(|base| PrSettings { base: Some(base) });
+            return Ok(());
+        }
+        let update = self.update.get_or_insert_with(UpdateSettings::default);
+        match key {
+            "update.check" => {
+                update.check = match value.as_deref() {
+                    Some("true") => Some(true),
+                    Some("false") => Some(false),
+                    Some(_) => return Err("update.check has to be true or false".to_owned()),
+                    None => None,
+                }
+            }
+            "update.interval_hours" => {
+                update.interval_hours = match value {
+                    Some(value) => Some(
+                        value
+                            .parse::<u64>()
+                            .map_err(|_| "update.interval_hours has to be a number".to_owned())?,
+                    ),
+                    None => None,
+                }
+            }
+            "update.channel" => {
+                if let Some(channel) = &value {
+                    if !UPDATE_CHANNELS.contains(&channel.as_str()) {
+                        return Err(format!(
+                            "Unknown channel, expected one of: {}",
+                            UPDATE_CHANNELS.join(", ")
+                        ));
+                    }
+                }
+                update.channel = value
+            }
+            "update.releases_url" => update.releases_url = value,
+            _ => {
+                return Err(format!(
+                    "Unknown key, expected one of: {}",
+                    SETTING_KEYS.join(", ")
+                ))
+            }
+        }
+        if update.check.is_none()
+            && update.interval_hours.is_none()
+            && update.channel.is_none()
+            && update.releases_url.is_none()
+        {
+            self.update = None;
+        }
+        Ok(())
+    }
+
+    /// The convention selected with `--convention`, falling back to the saved one.
+    pub fn get_convention(&self) -> Convention {
+        SELECTED_CONVENTION.lock().unwrap().unwrap_or(
+            self.convention
+                .as_deref()
+                .and_then(Convention::from_name)
+                .unwrap_or(Convention::Gitmoji),
+        )
+    }
+
+    /// The profile selected with `--profile`, falling back to the default profile.
+    pub fn active_profile_name(&self) -> String {
+        SELECTED_PROFILE
+            .lock()
+            .unwrap()
+            .to_owned()
+            .unwrap_or(self.get_default_profile())
+    }
+
+    /// The active profile with the `GPT_COMMIT_*` variables applied.
+    pub fn active_profile(&self) -> Profile {
+        let mut profile = self
+            .get_profiles()
+            .remove(&self.active_profile_name())
+            .unwrap_or_default();
+        profile.merge(self.overrides.to_owned());
+        profile
+    }
+
+    /// Drops the keys a repository can't set and returns them.
+    fn restrict(&mut self) -> Vec<String> {
+        let mut dropped = Vec::new();
+        for profile in self
+            .profiles
+            .iter_mut()
+            .flat_map(|profiles| profiles.values_mut())
+        {
+            dropped.extend(profile.restrict());
+        }
+        for key in SETTING_KEYS.iter().filter(|key| is_user_only(key)) {
+            if self.get_setting(key).is_some() {
+                self.set_setting(key, None).ok();
+                dropped.push(key.to_string());
+            }
+        }
+        dropped
+    }
+
+    /// Puts the values set in `other` over the ones of this config.
+    fn merge(&mut self, other: Config) {
+        for key in SETTING_KEYS {
+            if let Some(value) = other.get_setting(key) {
+                self.set_setting(key, Some(value)).ok();
+            }
+        }
+        self.default_profile = other.default_profile.or(self.default_profile.take());
+        self.convention = other.convention.or(self.convention.take());
+        for (name, profile) in other.profiles.unwrap_or_default() {
+            self.profile_mut(&name).merge(profile);
+        }
+    }
+
+    pub fn profile_mut(&mut self, name: &str) -> &mut Profile {
+        self.profiles
+            .get_or_insert_with(BTreeMap::new)
+            .entry(name.to_owned())
+            .or_default()
+    }
+
+    pub fn remove_profile(&mut self, name: &str) -> bool {
+        match self.profiles.as_mut() {
+            Some(profiles) => profiles.remove(name).is_some(),
+            None => false,
+        }
+    }
+
+    /// Moves the single endpoint settings of older versions into the default profile.
+    fn migrate(&mut self) -> bool {
+        if self.api_key.is_none()
+            && self.port.is_none()
+            && self.model_name.is_none()
+            && self.provider.is_none()
+        {
+            return false;
+        }
+
+        let api_key = self.api_key.take();
+        let port = self.port.take();
+        let model_name = self.model_name.take();
+        let provider = self.provider.take();
+
+        let profile = self.profile_mut(DEFAULT_PROFILE);
+        profile.api_key = profile.api_key.take().or(api_key);
+        if let Some(port) = port {
+            profile.base_url = Some(format!("http://localhost:{}", port));
+        }
+        profile.model = profile.model.take().or(model_name);
+        profile.provider = profile.provider.take().or(provider);
+
+        true
+    }
+}
+
+impl Profile {
+    pub fn get_base_url(&self) -> String {
+        self.base_url
+            .to_owned()
+            .unwrap_or(DEFAULT_BASE_URL.to_owned())
+    }
+
+    pub fn get_model(&self) -> String {
+        self.model.to_owned().unwrap_or(MODEL_NAME.to_owned())
+    }
+
+    /// The configured context window, or the known one of the model. Local
+    /// servers rarely load a model with its whole window, so those get the
+    /// usual default of LM Studio and Ollama.
+    pub fn get_context_tokens(&self) -> usize {
+        self.context_tokens.unwrap_or_else(|| {
+            let window = budget::context_window(&self.get_model());
+            if self.is_local() {
+                window.min(budget::LOCAL_CONTEXT_TOKENS)
+            } else {
+                window
+            }
+        })
+    }
+
+    /// Whether the server runs on this machine.
+    fn is_local(&self) -> bool {
+        if self.get_provider() == "ollama" {
+            return true;
+        }
+        let Ok(url) = reqwest::Url::parse(&self.get_base_url()) else {
+            return false;
+        };
+        match url.host_str() {
+            Some("localhost") => true,
+            Some(host) => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<std::net::IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback() || ip.is_unspecified()),
+            None => false,
+        }
+    }
+
+    pub fn get_provider(&self) -> String {
+        self.provider.to_owned().unwrap_or("openai".to_owned())
+    }
+
+    /// The key from the profile, from the output of `api_key_command`, from
+    /// the environment variable named by `api_key_env` or from
+    /// `CHAT_GPT_TOKEN`, in that order.
+    pub fn get_api_key(&self) -> String {
+        if let Some(api_key) = &self.api_key {
+            return api_key.to_owned();
+        }
+        if let Some(command) = &self.api_key_command {
+            return match run_key_command(command) {
+                Ok(api_key) => api_key,
+                Err(err) => {
+                    warn_once(format!("api_key_command failed: {}", err));
+                    String::new()
+                }
+            };
+        }
+        if let Some(Ok(api_key)) = self.api_key_env.as_ref().map(std::env::var) {
+            return api_key;
+        }
+        std::env::var("CHAT_GPT_TOKEN").unwrap_or_else(|_| "".to_owned())
+    }
+
+    /// Whether requests need an api key that isn't set.
+    pub fn missing_api_key(&self) -> bool {
+        let auth = self
+            .auth
+            .to_owned()
+            .unwrap_or(providers::from_profile(self).default_auth().to_owned());
+        auth != "none" && self.get_api_key().is_empty()
+    }
+
+    pub fn get_response_format(&self) -> String {
+        self.response_format
+            .to_owned()
+            .unwrap_or("json_schema".to_owned())
+    }
+
+    pub fn get_headers(&self) -> BTreeMap<String, String> {
+        self.headers.to_owned().unwrap_or_default()
+    }
+
+    pub fn get_url(&self, default_path: &str) -> String {
+        let path = self.path.to_owned().unwrap_or(default_path.to_owned());
+        format!(
+            "{}/{}",
+            self.get_base_url().trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "base_url" => self.base_url.to_owned(),
+            "path" => self.path.to_owned(),
+            "model" => self.model.to_owned(),
+            "context_tokens" => self.context_tokens.map(|tokens| tokens.to_string()),
+            "provider" => self.provider.to_owned(),
+            "api_key" => self.api_key.to_owned(),
+            "api_key_command" => self.api_key_command.to_owned(),
+            "api_key_env" => self.api_key_env.to_owned(),
+            "auth" => self.auth.to_owned(),
+            "response_format" => self.response_format.to_owned(),
+            _ => key
+                .strip_prefix("header.")
+                .and_then(|name| self.get_headers().remove(name)),
+        }
+    }
+
+    /// Every key that is set, with its value.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let keys = PROFILE_KEYS[..PROFILE_KEYS.len() - 1]
+            .iter()
+            .map(|key| key.to_string())
+            .chain(
+                self.get_headers()
+                    .into_keys()
+                    .map(|name| format!("header.{}", name)),
+            );
+        keys.filter_map(|key| self.get(&key).map(|value| (key, value)))
+            .collect()
+    }
+
+    fn merge(&mut self, other: Profile) {
+        self.base_url = other.base_url.or(self.base_url.take());
+        self.path = other.path.or(self.path.take());
+        self.model = other.model.or(self.model.take());
+        self.context_tokens = other.context_tokens.or(self.context_tokens.take());
+        self.provider = other.provider.or(self.provider.take());
+        self.api_key = other.api_key.or(self.api_key.take());
+        self.api_key_command = other.api_key_command.or(self.api_key_command.take());
+        self.api_key_env = other.api_key_env.or(self.api_key_env.take());
+        self.auth = other.auth.or(self.auth.take());
+        self.response_format = other.response_format.or(self.response_format.take());
+        if let Some(headers) = other.headers {
+            self.headers
+                .get_or_insert_with(BTreeMap::new)
+                .extend(headers);
+        }
+    }
+
+    /// Drops the keys a repository can't set and returns them.
+    fn restrict(&mut self) -> Vec<String> {
+        let dropped = self
+            .entries()
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| is_user_only(key))
+            .collect::<Vec<String>>();
+        for key in &dropped {
+            self.set(key, None).ok();
+        }
+        dropped
+    }
+
+    pub fn set(&mut self, key: &str, value: Option<String>) -> Result<(), String> {
+        match key {
+            "base_url" => self.base_url = value,
+            "path" => self.path = value,
+            "model" => self.model = value,
+            "context_tokens" => {
+                self.context_tokens = match value {
+                    Some(value) => Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|_| "context_tokens has to be a number".to_owned())?,
+                    ),
+                    None => None,
+                }
+            }
+            "provider" => {
+                if let Some(provider) = &value {
+                    if !PROVIDERS.contains(&provider.as_str()) {
+                        return Err(format!(
+                            "Unknown provider, expected one of: {}",
+                            PROVIDERS.join(", ")
+                        ));
+                    }
+                }
+                self.provider = value
+            }
+            "api_key" => self.api_key = value,
+            "api_key_command" => self.api_key_command = value,
+            "api_key_env" => self.api_key_env = value,
+            "auth" => {
+                if let Some(auth) = &value {
+                    if !AUTH_STYLES.contains(&auth.as_str()) {
+                        return Err(format!(
+                            "Unknown auth style, expected one of: {}",
+                            AUTH_STYLES.join(", ")
+                        ));
+                    }
+                }
+                self.auth = value
+            }
+            "response_format" => {
+                if let Some(format) = &value {
+                    if !RESPONSE_FORMATS.contains(&format.as_str()) {
+                        return Err(format!(
+                            "Unknown response format, expected one of: {}",
+                            RESPONSE_FORMATS.join(", ")
+                        ));
+                    }
+                }
+                self.response_format = value
+            }
+            _ => match key.strip_prefix("header.") {
+                Some(name) if !name.is_empty() => {
+                    let headers = self.headers.get_or_insert_with(BTreeMap::new);
+                    match value {
+                        Some(value) => headers.insert(name.to_owned(), value),
+                        None => headers.remove(name),
+                    };
+                }
+                _ => {
+                    return Err(format!(
+                        "Unknown key, expected one of: {}",
+                        PROFILE_KEYS.join(", ")
+                    ))
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+pub fn is_user_only(key: &str) -> bool {
+    USER_ONLY_KEYS.contains(&key) || key.starts_with("header.")
+}
+
+/// Whether the value of a key must not be shown.
+pub fn is_secret(key: &str) -> bool {
+    key == "api_key"
+        || key.strip_prefix("header.").is_some_and(|name| {
+            let name = name.to_lowercase();
+            SECRET_HEADERS.iter().any(|part| name.contains(part))
+        })
+}
+
+/// Keeps just enough of a secret to tell it apart from others.
+pub fn mask_secret(secret: &str) -> String {
+    let chars = secret.chars().collect::<Vec<char>>();
+    if chars.len() < 12 {
+        return "*".repeat(chars.len().max(4));
+    }
+    format!(
+        "{}…{}",
+        chars[..3].iter().collect::<String>(),
+        chars[chars.len() - 4..].iter().collect::<String>()
+    )
+}
+
+/// Runs `api_key_command` through the shell. The key is kept for the rest of
+/// the process, so the command runs at most once.
+fn run_key_command(command: &str) -> Result<String, String> {
+    let mut keys = COMMAND_KEYS.lock().unwrap();
+    if let Some(api_key) = keys.get(command) {
+        return Ok(api_key.to_owned());
+    }
+
+    let mut process = if cfg!(windows) {
+        let mut process = Command::new("cmd");
+        process.arg("/C").arg(command);
+        process
+    } else {
+        let mut process = Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    };
+    let output = process
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|err| err.to_string())?;
+    if !output.status.success() {
+        return Err(format!("`{}` exited with {}", command, output.status));
+    }
+
+    // like git credential helpers, only the first line is the secret
+    let api_key = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_owned();
+    if api_key.is_empty() {
+        return Err(format!("`{}` printed no key", command));
+    }
+    keys.insert(command.to_owned(), api_key.to_owned());
+    Ok(api_key)
+}
+
+/// The config of the user, or of the repository in the current directory,
+/// with the path it is saved to.
+pub fn load_config(repo: bool) -> Result<(Config, PathBuf), String> {
+    let path = if repo {
+        repo_config_path().ok_or("Not a git repository".to_owned())?
+    } else {
+        migrate_location();
+        user_config_path()
+    };
+    Ok((read_config(&path)?, path))
+}
+
+/// The layers of the config, from the lowest to the highest priority.
+fn config_layers() -> Result<Vec<(Origin, Config)>, String> {
+    let (mut user, path) = load_config(false)?;
+    if user.migrate() {
+        user.save(&path).ok();
+    }
+    let mut layers = vec![(Origin::User(path), user)];
+
+    if let Some(path) = repo_config_path() {
+        let mut repo = read_config(&path)?;
+        let dropped = repo.restrict();
+        if !dropped.is_empty() {
+            warn_once(format!(
+                "{} can't set {}, they are ignored",
+                path.display(),
+                dropped.join(", ")
+            ));
+        }
+        layers.push((Origin::Repo(path), repo));
+    }
+
+    for (var, key) in [
+        (env_var("profile"), "default_profile"),
+        (env_var("convention"), "convention"),
+    ] {
+        if let Ok(value) = std::env::var(&var) {
+            let mut env = Config::default();
+            match key {
+                "default_profile" => env.default_profile = Some(value),
+                _ if Convention::from_name(&value).is_none() => {
+                    warn_once(format!("{}: Unknown convention \"{}\"", var, value));
+                    continue;
+                }
+                _ => env.convention = Some(value),
+            }
+            layers.push((Origin::Env(var), env));
+        }
+    }
+    for key in &PROFILE_KEYS[..PROFILE_KEYS.len() - 1] {
+        let var = env_var(key);
+        if let Ok(value) = std::env::var(&var) {
+            let mut env = Config::default();
+            if let Err(err) = env.overrides.set(key, Some(value)) {
+                warn_once(format!("{}: {}", var, err));
+                continue;
+            }
+            layers.push((Origin::Env(var), env));
+        }
+    }
+    for key in SETTING_KEYS {
+        let var = env_var(key);
+        if let Ok(value) = std::env::var(&var) {
+            let mut env = Config::default();
+            if let Err(err) = env.set_setting(key, Some(value)) {
+                warn_once(format!("{}: {}", var, err));
+                continue;
+            }
+            layers.push((Origin::Env(var), env));
+        }
+    }
+
+    Ok(layers)
+}
+
+pub(crate) fn warn_once(message: String) {
+    lazy_static! {
+        static ref WARNED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+    let mut warned = WARNED.lock().unwrap();
+    if !warned.contains(&message) {
+        eprintln!("{} {}", "Warning:".yellow(), message);
+        warned.push(message);
+    }
+}
+
+/// The user config with the repository config, the `GPT_COMMIT_*` variables
+/// and the command line put over it.
+pub fn get_config() -> Config {
+    try_get_config().unwrap_or_else(|err| config_failed(err))
+}
+
+/// Like `get_config`, but returns an unreadable config as an error instead of
+/// exiting, for the hook which must never block a commit.
+pub fn try_get_config() -> Result<Config, String> {
+    Ok(merge_layers(
+        config_layers()?.into_iter().map(|(_, layer)| layer),
+    ))
+}
+
+/// Puts every layer over the ones before it.
+fn merge_layers(layers: impl Iterator<Item = Config>) -> Config {
+    let mut config = Config::default();
+    for layer in layers {
+        let overrides = layer.overrides.to_owned();
+        config.merge(layer);
+        config.overrides.merge(overrides);
+    }
+    config
+}
+
+/// Every setting in effect with where it comes from. Values of the
+/// `GPT_COMMIT_*` variables are listed for the active profile.
+pub fn config_entries() -> Vec<(String, String, Origin)> {
+    let mut entries = BTreeMap::<String, (String, Origin)>::new();
+    let active = get_config().active_profile_name();
+    for (origin, layer) in config_layers().unwrap_or_else(|err| config_failed(err)) {
+        if let Some(profile) = &layer.default_profile {
+            entries.insert(
+                "default_profile".to_owned(),
+                (profile.to_owned(), origin.clone()),
+            );
+        }
+        if let Some(convention) = &layer.convention {
+            entries.insert(
+                "convention".to_owned(),
+                (convention.to_owned(), origin.clone()),
+            );
+        }
+        for key in SETTING_KEYS {
+            if let Some(value) = layer.get_setting(key) {
+                entries.insert(key.to_owned(), (value, origin.clone()));
+            }
+        }
+        for (name, profile) in layer.get_profiles() {
+            for (key, value) in profile.entries() {
+                entries.insert(
+                    format!("profiles.{}.{}", name, key),
+                    (value, origin.clone()),
+                );
+            }
+        }
+        for (key, value) in layer.overrides.entries() {
+            entries.insert(
+                format!("profiles.{}.{}", active, key),
+                (value, origin.clone()),
+            );
+        }
+    }
+    if let Some(convention) = *SELECTED_CONVENTION.lock().unwrap() {
+        entries.insert(
+            "convention".to_owned(),
+            (convention.name().to_owned(), Origin::CommandLine),
+        );
+    }
+
+    entries
+        .into_iter()
+        .map(|(key, (value, origin))| (key, value, origin))
+        .collect()
+}
+
+pub fn get_executable_name() -> String {
+    std::env::current_exe()
+        .unwrap()
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+pub struct Loader {
+    loading: Arc<Mutex<bool>>,
+}
+
+impl Loader {
+    pub fn new(message: &str) -> Self {
+        let loading: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
+        let loading_clone = Arc::clone(&loading);
+        let message = Arc::new(Mutex::new(message.to_owned()));
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(100));
+            let mut stdout = io::stdout();
+            loop {
+                let is_loading = {
+                    let guard = loading_clone.lock().unwrap();
+                    *guard
+                };
+
+                if is_loading {
+                    let total_message =
+                        loading_str(&format!("[ ] {}", message.lock().unwrap()), None);
+                    let message_len = total_message.len();
+                    let spaces = terminal_width() - message_len;
+
+                    print!("\r{}{}", total_message, " ".repeat(spaces + 2));
+                } else {
+                    break;
+                }
+
+                interval.tick().await;
+                stdout.flush().unwrap();
+            }
+        });
+
+        Self { loading }
+    }
+
+    pub fn stop(&self) {
+        let mut guard = self.loading.lock().unwrap();
+        if !*guard {
+            return;
+        }
+        *guard = false;
+        io::stdout().flush().unwrap();
+        print!("\r{}", " ".repeat(terminal_width()));
+        print!("\r");
+    }
+}
+
+/// Prints a streamed response while it is generated. The loader keeps spinning
+/// until the first token arrives, the first line is highlighted as the subject.
+pub struct StreamPrinter<'a> {
+    loader: &'a Loader,
+    started: bool,
+    in_subject: bool,
+    json: Option<JsonPreview>,
+}
+
+/// The reply streamed so far and the part of the message already printed.
+struct JsonPreview {
+    reply: String,
+    printed: String,
+}
+
+/// Reads the value of a string field from a JSON object that is still being
+/// streamed. Returns the value so far and whether the string is complete.
+pub(crate) fn partial_json_string(json: &str, field: &str) -> Option<(String, bool)> {
+    let start = json.find(&format!("\"{}\"", field))? + field.len() + 2;
+    let rest = json[start..].trim_start().strip_prefix(':')?;
+    let mut chars = rest.trim_start().strip_prefix('"')?.chars();
+
+    let mut value = String::new();
+    while let Some(char) = chars.next() {
+        match char {
+            '"' => return Some((value, true)),
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => {}
+                Some('u') => {
+                    let code = chars.by_ref().take(4).collect::<String>();
+                    match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                        Some(char) if code.len() == 4 => value.push(char),
+                        _ => break,
+                    }
+                }
+                Some(char) => value.push(char),
+                None => break,
+            },
+            char => value.push(char),
+        }
+    }
+    Some((value, false))
+}
+
+impl<'a> StreamPrinter<'a> {
+    pub fn new(loader: &'a Loader) -> Self {
+        Self {
+            loader,
+            started: false,
+            in_subject: true,
+            json: None,
+        }
+    }
+
+    /// A printer for replies in the JSON format of `gpt_api::CommitMessage`,
+    /// showing the subject and the body instead of the raw JSON.
+    pub fn json(loader: &'a Loader) -> Self {
+        Self {
+            json: Some(JsonPreview {
+                reply: String::new(),
+                printed: String::new(),
+            }),
+            ..Self::new(loader)
+        }
+    }
+
+    pub fn print(&mut self, token: &str) {
+        let text = match &mut self.json {
+            Some(preview) => {
+                preview.reply.push_str(token);
+                let start = preview.reply.trim_start();
+                if !start.is_empty() && !start.starts_with(['{', '`']) {
+                    // not JSON after all, show the reply as it is
+                    let reply = preview.reply.to_owned();
+                    self.json = None;
+                    return self.print(&reply);
+                }
+                let mut message = match partial_json_string(&preview.reply, "subject") {
+                    Some((subject, true)) => match partial_json_string(&preview.reply, "body") {
+                        Some((body, _)) if !body.is_empty() => format!("{}\n{}", subject, body),
+                        _ => subject,
+                    },
+                    Some((subject, false)) => subject,
+                    None => String::new(),
+                };
+                match message.strip_prefix(&preview.printed) {
+                    Some(new) if !new.is_empty() => {
+                        let new = new.to_owned();
+                        std::mem::swap(&mut preview.printed, &mut message);
+                        new
+                    }
+                    _ => return,
+                }
+            }
+            None => token.to_owned(),
+        };
+
+        if !self.started {
+            self.loader.stop();
+            self.started = true;
+        }
+
+        for (index, part) in text.split('\n').enumerate() {
+            if index > 0 {
+                self.in_subject = false;
+                println!();
+            }
+            if self.in_subject {
+                print!("{}", part.bright_green());
+            } else {
+                print!("{}", part);
+            }
+        }
+        io::stdout().flush().unwrap();
+    }
+
+    pub fn finish(&self) {
+        if self.started {
+            println!("\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::{AUTH_STYLES, PROVIDERS};
+
+    fn value(key: &str) -> String {
+        match key {
+            "provider" => PROVIDERS[0].to_owned(),
+            "auth" => AUTH_STYLES[0].to_owned(),
+            _ => format!("https://example.com/{}", key),
+        }
+    }
+
+    fn with_user_only_keys() -> Config {
+        let mut config = Config::default();
+        for key in USER_ONLY_KEYS {
+            let key = key.replace("<name>", "Authorization");
+            if SETTING_KEYS.contains(&key.as_str()) {
+                config.set_setting(&key, Some(value(&key))).unwrap();
+            } else {
+                config
+                    .profile_mut(DEFAULT_PROFILE)
+                    .set(&key, Some(value(&key)))
+                    .unwrap();
+            }
+        }
+        config
+    }
+
+    #[test]
+    fn repo_config_cannot_set_user_only_keys() {
+        let mut repo = with_user_only_keys();
+        repo.profile_mut(DEFAULT_PROFILE)
+            .set("model", Some("llama3".to_owned()))
+            .unwrap();
+        repo.set_setting("update.channel", Some("prerelease".to_owned()))
+            .unwrap();
+
+        let dropped = repo.restrict();
+        assert_eq!(dropped.len(), USER_ONLY_KEYS.len());
+
+        let profile = repo.get_profiles().remove(DEFAULT_PROFILE).unwrap();
+        for key in USER_ONLY_KEYS {
+            let key = key.replace("<name>", "Authorization");
+            assert_eq!(profile.get(&key), None, "{}", key);
+            assert_eq!(repo.get_setting(&key), None, "{}", key);
+        }
+        assert_eq!(profile.get("model").as_deref(), Some("llama3"));
+        assert_eq!(
+            repo.get_setting("update.channel").as_deref(),
+            Some("prerelease")
+        );
+    }
+
+    fn layer(model: Option<&str>, convention: Option<&str>, channel: Option<&str>) -> Config {
+        let mut config = Config::default();
+        if let Some(model) = model {
+            config
+                .profile_mut(DEFAULT_PROFILE)
+                .set("model", Some(model.to_owned()))
+                .unwrap();
+        }
+        config.convention = convention.map(|convention| convention.to_owned());
+        if let Some(channel) = channel {
+            config
+                .set_setting("update.channel", Some(channel.to_owned()))
+                .unwrap();
+        }
+        config
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let user = || {
+            let mut user = layer(Some("user-model"), Some("plain"), Some("stable"));
+            user.profile_mut(DEFAULT_PROFILE)
+                .set("base_url", Some("http://user".to_owned()))
+                .unwrap();
+            user.profile_mut("work")
+                .set("model", Some("work-model".to_owned()))
+                .unwrap();
+            user
+        };
+        let repo = || layer(Some("repo-model"), Some("conventional"), None);
+        let env = || {
+            let mut env = layer(None, None, Some("prerelease"));
+            env.overrides
+                .set("model", Some("env-model".to_owned()))
+                .unwrap();
+            env
+        };
+
+        let config = merge_layers([user()].into_iter());
+        assert_eq!(config.active_profile().model.as_deref(), Some("user-model"));
+        assert_eq!(config.get_convention(), Convention::Plain);
+
+        let config = merge_layers([user(), repo()].into_iter());
+        assert_eq!(config.active_profile().model.as_deref(), Some("repo-model"));
+        assert_eq!(config.get_convention(), Convention::Conventional);
+        assert_eq!(config.get_update_channel(), "stable");
+        // keys the repository doesn't set stay as the user set them
+        assert_eq!(
+            config.active_profile().base_url.as_deref(),
+            Some("http://user")
+        );
+
+        let config = merge_layers([user(), repo(), env()].into_iter());
+        assert_eq!(config.active_profile().model.as_deref(), Some("env-model"));
+        assert_eq!(config.get_update_channel(), "prerelease");
+
+        // the command line selects the profile and the convention last, the
+        // variables still apply to the selected profile
+        select_profile("work");
+        select_convention(Convention::Gitmoji);
+        let model = config.active_profile().model;
+        let convention = config.get_convention();
+        *SELECTED_PROFILE.lock().unwrap() = None;
+        *SELECTED_CONVENTION.lock().unwrap() = None;
+        assert_eq!(model.as_deref(), Some("env-model"));
+        assert_eq!(convention, Convention::Gitmoji);
+    }
+
+    #[test]
+    fn profiles_of_the_repo_config_are_restricted() {
+        let mut profile = Profile::default();
+        profile
+            .set("base_url", Some("http://evil".to_owned()))
+            .unwrap();
+        profile.set("header.X-Trace", Some("1".to_owned())).unwrap();
+        profile.set("model", Some("llama3".to_owned())).unwrap();
+        profile
+            .set("context_tokens", Some("4096".to_owned()))
+            .unwrap();
+
+        assert_eq!(profile.restrict(), vec!["base_url", "header.X-Trace"]);
+        assert_eq!(
+            profile.entries(),
+            vec![
+                ("model".to_owned(), "llama3".to_owned()),
+                ("context_tokens".to_owned(), "4096".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn profile_keys_are_validated() {
+        let mut profile = Profile::default();
+        profile.set("provider", Some("ollama".to_owned())).unwrap();
+        profile.set("auth", Some("x-api-key".to_owned())).unwrap();
+        profile.set("header.X-Trace", Some("1".to_owned())).unwrap();
+        assert_eq!(profile.get("provider").as_deref(), Some("ollama"));
+        assert_eq!(profile.get("auth").as_deref(), Some("x-api-key"));
+        assert_eq!(profile.get("header.X-Trace").as_deref(), Some("1"));
+
+        assert!(profile.set("provider", Some("gemini".to_owned())).is_err());
+        assert!(profile.set("auth", Some("basic".to_owned())).is_err());
+        assert!(profile
+            .set("context_tokens", Some("lots".to_owned()))
+            .is_err());
+        assert!(profile.set("header.", Some("1".to_owned())).is_err());
+        assert!(profile.set("colour", Some("red".to_owned())).is_err());
+        assert_eq!(profile.get("provider").as_deref(), Some("ollama"));
+
+        profile.set("header.X-Trace", None).unwrap();
+        assert_eq!(profile.get("header.X-Trace"), None);
+    }
+
+    #[test]
+    fn joins_the_base_url_and_the_path() {
+        let mut profile = Profile {
+            base_url: Some("https://llm.example.com/openai/".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            profile.get_url("/v1/chat/completions"),
+            "https://llm.example.com/openai/v1/chat/completions"
+        );
+        profile.path = Some("deployments/gpt/chat".to_owned());
+        assert_eq!(
+            profile.get_url("/v1/chat/completions"),
+            "https://llm.example.com/openai/deployments/gpt/chat"
+        );
+    }
+
+    #[test]
+    fn moves_old_settings_into_the_default_profile() {
+        let mut config = toml::from_str::<Config>(
+            "api_key = \"sk-old\"\nport = 8080\nmodel_name = \"llama3\"\n",
+        )
+        .unwrap();
+        assert!(config.migrate());
+
+        let profile = config.get_profiles().remove(DEFAULT_PROFILE).unwrap();
+        assert_eq!(profile.base_url.as_deref(), Some("http://localhost:8080"));
+        assert_eq!(profile.api_key.as_deref(), Some("sk-old"));
+        assert_eq!(profile.model.as_deref(), Some("llama3"));
+        assert!(config.api_key.is_none() && config.port.is_none());
+        assert!(!config.migrate());
+
+        let saved = toml::to_string(&config).unwrap();
+        assert!(saved.contains("[profiles.default]"));
+        assert!(!saved.contains("model_name"));
+    }
+
+    #[test]
+    fn local_servers_get_a_small_context_window() {
+        let profile = |base_url: &str, provider: Option<&str>| Profile {
+            base_url: Some(base_url.to_owned()),
+            model: Some("deepseek-coder-v2-lite-instruct".to_owned()),
+            provider: provider.map(str::to_owned),
+            ..Default::default()
+        };
+        for base_url in [
+            "http://localhost:1234",
+            "http://127.0.0.1:1234",
+            "http://[::1]:1234",
+        ] {
+            assert_eq!(profile(base_url, None).get_context_tokens(), 4096);
+        }
+        assert_eq!(
+            profile("http://gpu-box:11434", Some("ollama")).get_context_tokens(),
+            4096
+        );
+        assert_eq!(
+            profile("https://api.deepseek.com", None).get_context_tokens(),
+            128000
+        );
+
+        let configured = Profile {
+            context_tokens: Some(32768),
+            ..profile("http://localhost:1234", None)
+        };
+        assert_eq!(configured.get_context_tokens(), 32768);
+    }
+
+    #[test]
+    fn update_settings_are_validated() {
+        let mut config = Config::default();
+        assert!(config.get_update_check());
+        assert_eq!(
+            config.get_update_interval(),
+            Duration::from_secs(UPDATE_INTERVAL_HOURS * 60 * 60)
+        );
+
+        config
+            .set_setting("update.check", Some("false".to_owned()))
+            .unwrap();
+        config
+            .set_setting("update.interval_hours", Some("6".to_owned()))
+            .unwrap();
+        assert!(!config.get_update_check());
+        assert_eq!(
+            config.get_update_interval(),
+            Duration::from_secs(6 * 60 * 60)
+        );
+
+        assert!(config
+            .set_setting("update.check", Some("no".to_owned()))
+            .is_err());
+        assert!(config
+            .set_setting("update.interval_hours", Some("daily".to_owned()))
+            .is_err());
+
+        config.set_setting("update.check", None).unwrap();
+        config.set_setting("update.interval_hours", None).unwrap();
+        assert!(config.update.is_none());
+    }
+
+    #[test]
+    fn masks_secrets() {
+        assert_eq!(mask_secret("sk-proj-1234567890abcdef"), "sk-…cdef");
+        assert_eq!(mask_secret("123456789012"), "123…9012");
+        assert_eq!(mask_secret("12345678901"), "***********");
+        assert_eq!(mask_secret("ab"), "****");
+        assert_eq!(mask_secret(""), "****");
+    }
+
+    #[test]
+    fn finds_secret_keys() {
+        assert!(is_secret("api_key"));
+        assert!(is_secret("header.Authorization"));
+        assert!(is_secret("header.X-Api-Key"));
+        assert!(is_secret("header.x-auth-token"));
+        assert!(!is_secret("header.X-Trace"));
+        assert!(!is_secret("api_key_command"));
+        assert!(!is_secret("model"));
+    }
+
+    #[test]
+    fn dotenv_cannot_set_user_only_keys() {
+        for key in USER_ONLY_KEYS {
+            assert!(!dotenv_allowed(&env_var(key)), "{}", key);
+        }
+        assert!(!dotenv_allowed("GPT_COMMIT_HEADER_AUTHORIZATION"));
+        assert!(dotenv_allowed("GPT_COMMIT_MODEL"));
+        assert!(dotenv_allowed("GPT_COMMIT_UPDATE_CHANNEL"));
+        assert!(dotenv_allowed("CHAT_GPT_TOKEN"));
+    }
+}
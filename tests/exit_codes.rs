@@ -0,0 +1,190 @@
+//! Runs the program like a script would, against a stand-in for the model.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use git2::{IndexAddOption, Repository};
+
+const NOTHING_TO_COMMIT: i32 = 3;
+const MODEL_UNREACHABLE: i32 = 4;
+const UNUSABLE_OUTPUT: i32 = 5;
+const ABORTED: i32 = 7;
+
+/// A repository with a commit and an uncommitted change, next to the config
+/// and cache directories of the program.
+struct Sandbox {
+    dir: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Sandbox {
+        let dir =
+            std::env::temp_dir().join(format!("gpt-commit-exit-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let repo = Repository::init(dir.join("repo")).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+
+        fs::write(dir.join("repo/a.txt"), "a\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+
+        Sandbox { dir }
+    }
+
+    fn repo(&self) -> PathBuf {
+        self.dir.join("repo")
+    }
+
+    fn change(&self) {
+        fs::write(self.repo().join("a.txt"), "b\n").unwrap();
+    }
+
+    /// Runs the program without a terminal and returns the exit code and stdout.
+    fn run(&self, base_url: &str, args: &[&str]) -> (i32, String) {
+        let output = Command::new(env!("CARGO_BIN_EXE_gpt-commit-rust"))
+            .args(args)
+            .current_dir(self.repo())
+            .env("XDG_CONFIG_HOME", self.dir.join("config"))
+            .env("XDG_CACHE_HOME", self.dir.join("cache"))
+            .env("GPT_COMMIT_UPDATE_CHECK", "false")
+            .env("GPT_COMMIT_API_KEY", "test")
+            .env("GPT_COMMIT_BASE_URL", base_url)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        (
+            output.status.code().unwrap(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        )
+    }
+
+    fn head_message(&self) -> String {
+        let repo = Repository::open(self.repo()).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        head.message().unwrap().trim_end().to_owned()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Answers every chat request with `reply`, the way an OpenAI compatible
+/// server does without streaming.
+fn serve(reply: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            reader.read_exact(&mut vec![0; length]).unwrap();
+
+            let body = serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": reply } }],
+            })
+            .to_string();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    url
+}
+
+/// A port nothing listens on.
+fn closed_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+const REPLY: &str = r#"{"gitmoji":":bug:","type":"fix","scope":"","subject":"Fix a","body":"It was a.","breaking":false,"breaking_change":""}"#;
+
+fn assert_committed_nothing(sandbox: &Sandbox) {
+    assert_eq!(sandbox.head_message(), "init");
+    let repo = Repository::open(sandbox.repo()).unwrap();
+    assert!(repo
+        .index()
+        .unwrap()
+        .iter()
+        .all(|entry| { repo.find_blob(entry.id).unwrap().content() == b"a\n" }));
+}
+
+#[test]
+fn nothing_to_commit() {
+    let sandbox = Sandbox::new("nothing");
+    let (code, _) = sandbox.run(&serve(REPLY), &["--print"]);
+    assert_eq!(code, NOTHING_TO_COMMIT);
+}
+
+#[test]
+fn model_unreachable() {
+    let sandbox = Sandbox::new("unreachable");
+    sandbox.change();
+    let (code, _) = sandbox.run(&closed_port(), &["--print"]);
+    assert_eq!(code, MODEL_UNREACHABLE);
+}
+
+#[test]
+fn unusable_output() {
+    let sandbox = Sandbox::new("unusable");
+    sandbox.change();
+    let (code, _) = sandbox.run(&serve(r#"{"gitmoji":":bug:","#), &["--print"]);
+    assert_eq!(code, UNUSABLE_OUTPUT);
+}
+
+#[test]
+fn prints_without_committing() {
+    let sandbox = Sandbox::new("print");
+    sandbox.change();
+    let (code, stdout) = sandbox.run(&serve(REPLY), &["--print"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, ":bug: Fix a\n\nIt was a.\n");
+    assert_committed_nothing(&sandbox);
+}
+
+#[test]
+fn questions_without_a_terminal_abort() {
+    let sandbox = Sandbox::new("no-terminal");
+    sandbox.change();
+    for args in [&["--no-stream"][..], &["--no-stream", "--non-interactive"]] {
+        let (code, _) = sandbox.run(&serve(REPLY), args);
+        assert_eq!(code, ABORTED, "{:?}", args);
+        assert_committed_nothing(&sandbox);
+    }
+}
+
+#[test]
+fn yes_commits() {
+    let sandbox = Sandbox::new("yes");
+    sandbox.change();
+    let (code, _) = sandbox.run(&serve(REPLY), &["--no-stream", "--yes"]);
+    assert_eq!(code, 0);
+    assert_eq!(sandbox.head_message(), ":bug: Fix a\n\nIt was a.");
+}